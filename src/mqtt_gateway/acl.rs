@@ -0,0 +1,321 @@
+use super::CONFIG;
+use super::config::{Acl, ConfigError, or_exit};
+use clap::ValueEnum;
+use log::warn;
+use serde::Deserialize;
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::sync::Arc;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Access {
+    Publish,
+    Subscribe,
+    Both,
+}
+
+#[derive(Debug)]
+struct Rule {
+    allow: bool,
+    access: Access,
+    /// Topic filter, `%c` and `%u` stand for the client id and the username.
+    filter: String,
+}
+
+/// What happens to a client publishing on a topic it may not publish on.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, ValueEnum)]
+#[serde(rename_all = "kebab-case")]
+pub enum DeniedPublish {
+    /// The publish is acked and dropped, MQTT v5 clients get a `NotAuthorized` ack.
+    #[default]
+    Drop,
+    /// The client is disconnected.
+    Disconnect,
+}
+
+/// Topic access rules, checked in order with the first matching rule deciding. A topic nobody
+/// has a rule for is denied, unless no rules are configured at all.
+#[derive(Debug)]
+pub struct TopicAcl {
+    rules: Vec<Rule>,
+    default_allow: bool,
+    pub denied_publish: DeniedPublish,
+}
+
+impl TopicAcl {
+    pub fn allow_all() -> Self {
+        Self {
+            rules: Vec::new(),
+            default_allow: true,
+            denied_publish: DeniedPublish::Drop,
+        }
+    }
+
+    /// Loads rules from a file of `allow|deny pub|sub|pubsub <filter>` lines. Empty lines and
+    /// lines starting with `#` are skipped.
+    pub fn load(path: &Path, denied_publish: DeniedPublish) -> io::Result<Self> {
+        let mut rules = Vec::new();
+        for (number, line) in fs::read_to_string(path)?.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let invalid = |msg| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("line {}: {}", number + 1, msg),
+                )
+            };
+            let mut fields = line.split_whitespace();
+            let allow = match fields.next() {
+                Some("allow") => true,
+                Some("deny") => false,
+                _ => return Err(invalid("expected allow or deny")),
+            };
+            let access = match fields.next() {
+                Some("pub") => Access::Publish,
+                Some("sub") => Access::Subscribe,
+                Some("pubsub") => Access::Both,
+                _ => return Err(invalid("expected pub, sub or pubsub")),
+            };
+            let (Some(filter), None) = (fields.next(), fields.next()) else {
+                return Err(invalid("expected a single topic filter"));
+            };
+            rules.push(Rule {
+                allow,
+                access,
+                filter: filter.to_owned(),
+            });
+        }
+
+        Ok(Self {
+            rules,
+            default_allow: false,
+            denied_publish,
+        })
+    }
+
+    pub fn can_publish(&self, client_id: &str, username: Option<&str>, topic: &str) -> bool {
+        self.check(Access::Publish, client_id, username, |filter, _| {
+            topic_matches(filter, topic)
+        })
+    }
+
+    /// An allow rule has to cover every topic the subscription matches, while a deny rule
+    /// applies as soon as the two filters share a topic.
+    pub fn can_subscribe(&self, client_id: &str, username: Option<&str>, filter: &str) -> bool {
+        self.check(Access::Subscribe, client_id, username, |rule, allow| {
+            if allow {
+                filter_covers(rule, filter)
+            } else {
+                filters_overlap(rule, filter)
+            }
+        })
+    }
+
+    fn check(
+        &self,
+        access: Access,
+        client_id: &str,
+        username: Option<&str>,
+        applies: impl Fn(&str, bool) -> bool,
+    ) -> bool {
+        self.rules
+            .iter()
+            .filter(|rule| rule.access == access || rule.access == Access::Both)
+            .find(|rule| {
+                substitute(&rule.filter, client_id, username)
+                    .is_some_and(|filter| applies(&filter, rule.allow))
+            })
+            .map_or(self.default_allow, |rule| rule.allow)
+    }
+}
+
+/// Fills in `%c` and `%u`. A rule referring to a missing username, or to a value that would
+/// change the filter structure, does not apply.
+fn substitute(filter: &str, client_id: &str, username: Option<&str>) -> Option<String> {
+    let valid = |value: &str| !value.contains(['/', '+', '#']);
+
+    let mut filter = filter.to_owned();
+    if filter.contains("%c") {
+        if !valid(client_id) {
+            return None;
+        }
+        filter = filter.replace("%c", client_id);
+    }
+    if filter.contains("%u") {
+        let username = username.filter(|username| valid(username))?;
+        filter = filter.replace("%u", username);
+    }
+    Some(filter)
+}
+
+fn is_wildcard(level: &str) -> bool {
+    level == "+" || level == "#"
+}
+
+/// Wildcards at the first level do not match topics starting with `$` [MQTT-4.7.2-1].
+fn first_level_hidden(wildcard: &str, other: &str) -> bool {
+    wildcard.starts_with(['+', '#']) && other.starts_with('$')
+}
+
+fn topic_matches(filter: &str, topic: &str) -> bool {
+    if first_level_hidden(filter, topic) {
+        return false;
+    }
+
+    let (mut filter, mut topic) = (filter.split('/'), topic.split('/'));
+    loop {
+        match (filter.next(), topic.next()) {
+            // `#` also matches the parent level, `a/#` matches `a`.
+            (Some("#"), _) => return true,
+            (None, None) => return true,
+            (Some(f), Some(t)) if f == "+" || f == t => {}
+            _ => return false,
+        }
+    }
+}
+
+/// Whether every topic matching `filter` also matches `rule`.
+fn filter_covers(rule: &str, filter: &str) -> bool {
+    if first_level_hidden(rule, filter) {
+        return false;
+    }
+
+    let (mut rule, mut filter) = (rule.split('/'), filter.split('/'));
+    loop {
+        match (rule.next(), filter.next()) {
+            (Some("#"), _) => return true,
+            (None, None) => return true,
+            (Some("+"), Some(f)) if f != "#" => {}
+            (Some(r), Some(f)) if r == f && !is_wildcard(f) => {}
+            _ => return false,
+        }
+    }
+}
+
+/// Whether some topic matches both filters.
+fn filters_overlap(a: &str, b: &str) -> bool {
+    if first_level_hidden(a, b) || first_level_hidden(b, a) {
+        return false;
+    }
+
+    let (mut a, mut b) = (a.split('/'), b.split('/'));
+    loop {
+        match (a.next(), b.next()) {
+            (Some("#"), _) | (_, Some("#")) => return true,
+            (None, None) => return true,
+            (Some(x), Some(y)) if x == "+" || y == "+" || x == y => {}
+            _ => return false,
+        }
+    }
+}
+
+pub(crate) fn create_acl() -> Arc<TopicAcl> {
+    or_exit(load_acl(&CONFIG.acl))
+}
+
+fn load_acl(acl: &Acl) -> Result<Arc<TopicAcl>, ConfigError> {
+    match &acl.file {
+        Some(path) => TopicAcl::load(path, acl.denied_publish)
+            .map(Arc::new)
+            .map_err(|e| ConfigError::Invalid("acl.file", format!("{}: {}", path.display(), e))),
+        None => {
+            warn!("No topic ACL configured, every client may use every topic");
+            Ok(Arc::new(TopicAcl::allow_all()))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn topic_matches_wildcards() {
+        assert!(topic_matches("a/b", "a/b"));
+        assert!(topic_matches("a/+", "a/b"));
+        assert!(topic_matches("a/+", "a/"));
+        assert!(!topic_matches("a/+", "a/b/c"));
+        assert!(topic_matches("a/#", "a/b/c"));
+        assert!(topic_matches("a/#", "a"));
+        assert!(topic_matches("#", "a/b"));
+        assert!(!topic_matches("a/b", "a/b/c"));
+        assert!(!topic_matches("a/b/c", "a/b"));
+    }
+
+    #[test]
+    fn wildcards_skip_dollar_topics() {
+        assert!(!topic_matches("#", "$SYS/info"));
+        assert!(!topic_matches("+/info", "$SYS/info"));
+        assert!(topic_matches("$SYS/#", "$SYS/info"));
+        assert!(!filter_covers("#", "$SYS/#"));
+        assert!(!filters_overlap("+/info", "$SYS/+"));
+        assert!(!filters_overlap("$SYS/+", "#"));
+    }
+
+    #[test]
+    fn filter_covers_narrower_filters() {
+        assert!(filter_covers("a/#", "a/+/c"));
+        assert!(filter_covers("a/#", "a/#"));
+        assert!(filter_covers("a/+", "a/b"));
+        assert!(filter_covers("+/+", "a/+"));
+        assert!(!filter_covers("a/+", "a/#"));
+        assert!(!filter_covers("a/b", "a/+"));
+        assert!(!filter_covers("a/+", "a/b/c"));
+    }
+
+    #[test]
+    fn filters_overlap_when_sharing_a_topic() {
+        assert!(filters_overlap("a/+", "+/b"));
+        assert!(filters_overlap("a/#", "a"));
+        assert!(filters_overlap("#", "a/b/c"));
+        assert!(!filters_overlap("a/+", "b/+"));
+        assert!(!filters_overlap("a/+", "a/b/c"));
+    }
+
+    #[test]
+    fn substitute_fills_in_identity() {
+        assert_eq!(
+            substitute("devices/%c/%u", "c1", Some("alice")).as_deref(),
+            Some("devices/c1/alice")
+        );
+        assert_eq!(substitute("devices/%u", "c1", None), None);
+        assert_eq!(substitute("devices/%c", "a/b", None), None);
+        assert_eq!(substitute("devices/%c", "#", None), None);
+        assert_eq!(substitute("devices/%u", "c1", Some("+")), None);
+        assert_eq!(
+            substitute("public/#", "a/b", None).as_deref(),
+            Some("public/#")
+        );
+    }
+
+    #[test]
+    fn first_matching_rule_decides() {
+        let rule = |allow, access, filter: &str| Rule {
+            allow,
+            access,
+            filter: filter.to_owned(),
+        };
+        let acl = TopicAcl {
+            rules: vec![
+                rule(false, Access::Subscribe, "devices/+/secret"),
+                rule(true, Access::Both, "devices/%c/#"),
+                rule(true, Access::Subscribe, "public/#"),
+            ],
+            default_allow: false,
+            denied_publish: DeniedPublish::Drop,
+        };
+        assert!(acl.can_publish("c1", None, "devices/c1/temp"));
+        assert!(!acl.can_publish("c1", None, "devices/c2/temp"));
+        assert!(!acl.can_publish("c1", None, "public/news"));
+        assert!(acl.can_subscribe("c1", None, "public/+"));
+        assert!(acl.can_subscribe("c1", None, "devices/c1/temp"));
+        // Both may match `devices/c1/secret`, which the deny rule covers.
+        assert!(!acl.can_subscribe("c1", None, "devices/c1/+"));
+        assert!(!acl.can_subscribe("c1", None, "devices/c1/#"));
+        assert!(!acl.can_subscribe("c1", None, "devices/+/temp"));
+    }
+}
@@ -0,0 +1,539 @@
+use super::cert::ClientCertificate;
+use super::config::{Auth, ConfigError, or_exit};
+use super::tenant::Tenant;
+use super::{AUTHENTICATOR, CERT_MAPPING, CONFIG};
+use argon2::password_hash::SaltString;
+use argon2::{Argon2, Params, PasswordHash, PasswordHasher, PasswordVerifier, Version};
+use clap::ValueEnum;
+use jsonwebtoken::{Algorithm, DecodingKey, Validation};
+use log::{debug, error, warn};
+use ntex::util::{ByteString, Bytes};
+use ntex_mqtt::{v3, v5};
+use rand::rngs::OsRng;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::sync::Arc;
+
+/// What a client presents when it connects.
+pub struct Credentials {
+    pub client_id: String,
+    pub username: Option<String>,
+    pub password: Option<Bytes>,
+    /// Client certificate, already verified by the TLS listener.
+    pub certificate: Option<ClientCertificate>,
+}
+
+/// Identity of an authenticated client.
+#[derive(Debug, Clone, Default)]
+pub struct Identity {
+    pub username: Option<String>,
+    pub certificate: Option<ClientCertificate>,
+    /// Tenant the client connected to, `None` for the default backend pool.
+    pub tenant: Option<Arc<Tenant>>,
+}
+
+impl Identity {
+    pub fn tenant_name(&self) -> Option<&str> {
+        self.tenant.as_ref().map(|tenant| tenant.name.as_str())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AuthError {
+    /// The credentials are missing, malformed or wrong.
+    BadCredentials,
+    /// The credentials are valid, but do not allow this connection.
+    NotAuthorized,
+    /// The client id is not the one the client certificate is issued for.
+    IdentifierRejected,
+}
+
+impl From<AuthError> for v3::codec::ConnectAckReason {
+    fn from(err: AuthError) -> Self {
+        match err {
+            AuthError::BadCredentials => v3::codec::ConnectAckReason::BadUserNameOrPassword,
+            AuthError::NotAuthorized => v3::codec::ConnectAckReason::NotAuthorized,
+            AuthError::IdentifierRejected => v3::codec::ConnectAckReason::IdentifierRejected,
+        }
+    }
+}
+
+impl From<AuthError> for v5::codec::ConnectAckReason {
+    fn from(err: AuthError) -> Self {
+        match err {
+            AuthError::BadCredentials => v5::codec::ConnectAckReason::BadUserNameOrPassword,
+            AuthError::NotAuthorized => v5::codec::ConnectAckReason::NotAuthorized,
+            AuthError::IdentifierRejected => v5::codec::ConnectAckReason::ClientIdentifierNotValid,
+        }
+    }
+}
+
+/// Decides whether a client may connect, before any backend connection is opened. Runs on the
+/// blocking pool, so it may take its time.
+pub trait Authenticator: Send + Sync {
+    fn authenticate(&self, credentials: &Credentials) -> Result<Identity, AuthError>;
+}
+
+/// Accepts every client, the username is taken as is.
+pub struct AllowAll;
+
+impl Authenticator for AllowAll {
+    fn authenticate(&self, credentials: &Credentials) -> Result<Identity, AuthError> {
+        Ok(Identity {
+            username: credentials.username.clone(),
+            ..Default::default()
+        })
+    }
+}
+
+/// Checks passwords against a file of `username:hash` lines, where the hash is an argon2 PHC
+/// string. Empty lines and lines starting with `#` are skipped.
+pub struct PasswordFileAuthenticator {
+    users: HashMap<String, String>,
+    /// Verified in place of the hash of an unknown username, so unknown usernames take as long
+    /// to reject as wrong passwords.
+    dummy_hash: String,
+}
+
+impl PasswordFileAuthenticator {
+    pub fn load(path: &Path) -> io::Result<Self> {
+        let mut users = HashMap::new();
+        for (number, line) in fs::read_to_string(path)?.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let invalid = |msg| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("line {}: {}", number + 1, msg),
+                )
+            };
+            let (username, hash) = line
+                .split_once(':')
+                .ok_or_else(|| invalid("expected username:hash"))?;
+            let parsed = PasswordHash::new(hash).map_err(|_| invalid("invalid password hash"))?;
+            argon2::Algorithm::try_from(parsed.algorithm)
+                .map_err(|_| invalid("not an argon2 password hash"))?;
+            users.insert(username.to_owned(), hash.to_owned());
+        }
+        let dummy_hash = dummy_hash(&users).map_err(|e| io::Error::other(e.to_string()))?;
+        Ok(Self { users, dummy_hash })
+    }
+}
+
+/// Hash of a random password with the parameters of the hashes in the file.
+fn dummy_hash(users: &HashMap<String, String>) -> argon2::password_hash::Result<String> {
+    let params = users
+        .values()
+        .find_map(|hash| Params::try_from(&PasswordHash::new(hash).ok()?).ok())
+        .unwrap_or_default();
+    let salt = SaltString::generate(&mut OsRng);
+    let hash = Argon2::new(argon2::Algorithm::Argon2id, Version::V0x13, params)
+        .hash_password(salt.as_str().as_bytes(), &salt)?;
+    Ok(hash.to_string())
+}
+
+impl Authenticator for PasswordFileAuthenticator {
+    fn authenticate(&self, credentials: &Credentials) -> Result<Identity, AuthError> {
+        let (Some(username), Some(password)) = (&credentials.username, &credentials.password)
+        else {
+            return Err(AuthError::BadCredentials);
+        };
+        let known = self.users.get(username);
+        let hash = PasswordHash::new(known.unwrap_or(&self.dummy_hash))
+            .map_err(|_| AuthError::BadCredentials)?;
+
+        let verified = Argon2::default().verify_password(password, &hash).is_ok();
+        if known.is_none() || !verified {
+            return Err(AuthError::BadCredentials);
+        }
+        Ok(Identity {
+            username: Some(username.to_owned()),
+            ..Default::default()
+        })
+    }
+}
+
+#[derive(Deserialize)]
+struct Claims {
+    sub: String,
+    /// Restricts the token to a single client id.
+    #[serde(default)]
+    client_id: Option<String>,
+}
+
+/// Accepts an HMAC signed JWT as the password. The `sub` claim is the username and `exp` is
+/// required.
+pub struct JwtAuthenticator {
+    key: DecodingKey,
+    validation: Validation,
+}
+
+impl JwtAuthenticator {
+    pub fn new(secret: &[u8]) -> Self {
+        let mut validation = Validation::new(Algorithm::HS256);
+        validation.algorithms = vec![Algorithm::HS256, Algorithm::HS384, Algorithm::HS512];
+        Self {
+            key: DecodingKey::from_secret(secret),
+            validation,
+        }
+    }
+}
+
+impl Authenticator for JwtAuthenticator {
+    fn authenticate(&self, credentials: &Credentials) -> Result<Identity, AuthError> {
+        let token = credentials
+            .password
+            .as_deref()
+            .and_then(|password| std::str::from_utf8(password).ok())
+            .ok_or(AuthError::BadCredentials)?;
+        let claims = jsonwebtoken::decode::<Claims>(token, &self.key, &self.validation)
+            .map_err(|e| {
+                debug!("Rejected JWT of client {}: {}", credentials.client_id, e);
+                AuthError::BadCredentials
+            })?
+            .claims;
+
+        if credentials
+            .username
+            .as_ref()
+            .is_some_and(|username| *username != claims.sub)
+        {
+            return Err(AuthError::BadCredentials);
+        }
+        if claims
+            .client_id
+            .is_some_and(|client_id| client_id != credentials.client_id)
+        {
+            return Err(AuthError::NotAuthorized);
+        }
+        Ok(Identity {
+            username: Some(claims.sub),
+            ..Default::default()
+        })
+    }
+}
+
+/// Accepts clients that presented a certificate, which the TLS listener has verified already.
+/// The common name of the certificate subject is the username.
+pub struct MtlsAuthenticator;
+
+impl Authenticator for MtlsAuthenticator {
+    fn authenticate(&self, credentials: &Credentials) -> Result<Identity, AuthError> {
+        let cert = credentials
+            .certificate
+            .as_ref()
+            .ok_or(AuthError::NotAuthorized)?;
+        Ok(Identity {
+            username: cert.common_name.clone(),
+            ..Default::default()
+        })
+    }
+}
+
+/// Where the credentials of clients are checked.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, ValueEnum)]
+#[serde(rename_all = "kebab-case")]
+pub enum AuthBackend {
+    /// A file of usernames and Argon2 password hashes.
+    File,
+    /// The password is a JWT signed with a shared secret.
+    Jwt,
+    /// The client certificate, clients without one are rejected.
+    Mtls,
+}
+
+pub(crate) fn create_authenticator() -> Arc<dyn Authenticator> {
+    or_exit(load_authenticator(&CONFIG.auth))
+}
+
+/// The files each backend needs are checked when the configuration is loaded, reading them
+/// can still fail.
+fn load_authenticator(auth: &Auth) -> Result<Arc<dyn Authenticator>, ConfigError> {
+    let unreadable = |key, path: &Path, e: io::Error| {
+        ConfigError::Invalid(key, format!("{}: {}", path.display(), e))
+    };
+
+    match auth.backend {
+        Some(AuthBackend::File) => {
+            let path = auth.password_file.as_deref().unwrap();
+            PasswordFileAuthenticator::load(path)
+                .map(|auth| Arc::new(auth) as Arc<dyn Authenticator>)
+                .map_err(|e| unreadable("auth.password_file", path, e))
+        }
+        Some(AuthBackend::Jwt) => {
+            let path = auth.jwt_key_file.as_deref().unwrap();
+            let secret = fs::read(path).map_err(|e| unreadable("auth.jwt_key_file", path, e))?;
+            Ok(Arc::new(JwtAuthenticator::new(&secret)))
+        }
+        Some(AuthBackend::Mtls) => Ok(Arc::new(MtlsAuthenticator)),
+        None => {
+            warn!("No authentication backend configured, every client is accepted");
+            Ok(Arc::new(AllowAll))
+        }
+    }
+}
+
+/// Runs the authenticator on the blocking pool, verifying an Argon2 hash would hold up every
+/// other connection of the worker.
+async fn authenticate(credentials: Credentials) -> Result<Identity, AuthError> {
+    ntex::rt::spawn_blocking(move || {
+        let result = AUTHENTICATOR.authenticate(&credentials);
+        match &result {
+            Ok(identity) => debug!(
+                "Client authenticated: client_id={}, username={:?}",
+                credentials.client_id, identity.username
+            ),
+            Err(e) => warn!(
+                "Client authentication failed: client_id={}, username={:?}, reason={:?}",
+                credentials.client_id, credentials.username, e
+            ),
+        }
+        result
+    })
+    .await
+    .unwrap_or_else(|e| {
+        error!("Authentication did not complete: {}", e);
+        Err(AuthError::NotAuthorized)
+    })
+}
+
+/// Authenticates a connecting client. The certificate mapping applies first, so a client id
+/// taken from the certificate replaces the one in the CONNECT packet.
+async fn identify(
+    client_id: &mut ByteString,
+    username: Option<&ByteString>,
+    password: Option<&Bytes>,
+    certificate: Option<ClientCertificate>,
+    tenant: Option<Arc<Tenant>>,
+) -> Result<Identity, AuthError> {
+    match CERT_MAPPING.client_id(certificate.as_ref(), client_id) {
+        Ok(Some(mapped)) => *client_id = mapped.into(),
+        Ok(None) => {}
+        Err(e) => {
+            warn!(
+                "Client id does not match the client certificate: client_id={}, certificate={:?}",
+                client_id,
+                certificate.as_ref().map(|cert| &cert.subject)
+            );
+            return Err(e);
+        }
+    }
+
+    let mut identity = authenticate(Credentials {
+        client_id: client_id.to_string(),
+        username: username.map(ByteString::to_string),
+        password: password.cloned(),
+        certificate: certificate.clone(),
+    })
+    .await?;
+    if let Some(username) = CERT_MAPPING.username(certificate.as_ref()) {
+        identity.username = Some(username);
+    }
+    identity.certificate = certificate;
+    identity.tenant = tenant;
+    Ok(identity)
+}
+
+pub(crate) async fn authenticate_v3(handshake: &mut v3::Handshake) -> Result<Identity, AuthError> {
+    let certificate = ClientCertificate::from_io(handshake.io());
+    let tenant = Tenant::from_io(handshake.io());
+    let packet = handshake.packet_mut();
+    identify(
+        &mut packet.client_id,
+        packet.username.as_ref(),
+        packet.password.as_ref(),
+        certificate,
+        tenant,
+    )
+    .await
+}
+
+pub(crate) async fn authenticate_v5(handshake: &mut v5::Handshake) -> Result<Identity, AuthError> {
+    let certificate = ClientCertificate::from_io(handshake.io());
+    let tenant = Tenant::from_io(handshake.io());
+    let packet = handshake.packet_mut();
+    identify(
+        &mut packet.client_id,
+        packet.username.as_ref(),
+        packet.password.as_ref(),
+        certificate,
+        tenant,
+    )
+    .await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use jsonwebtoken::{EncodingKey, Header};
+    use serde_json::json;
+    use std::time::{SystemTime, UNIX_EPOCH};
+
+    /// Writes a password file, named after the test, to the temp directory.
+    fn password_file(name: &str, contents: &str) -> std::path::PathBuf {
+        let path =
+            std::env::temp_dir().join(format!("mqtt_gateway-{}-{}", std::process::id(), name));
+        fs::write(&path, contents).unwrap();
+        path
+    }
+
+    /// An argon2id hash with the smallest parameters, so the tests stay fast.
+    fn hash(password: &str) -> String {
+        let params = Params::new(Params::MIN_M_COST, 1, 1, None).unwrap();
+        let salt = SaltString::generate(&mut OsRng);
+        Argon2::new(argon2::Algorithm::Argon2id, Version::V0x13, params)
+            .hash_password(password.as_bytes(), &salt)
+            .unwrap()
+            .to_string()
+    }
+
+    fn credentials(username: Option<&str>, password: &str) -> Credentials {
+        Credentials {
+            client_id: "c1".into(),
+            username: username.map(str::to_owned),
+            password: Some(Bytes::copy_from_slice(password.as_bytes())),
+            certificate: None,
+        }
+    }
+
+    fn load_error(name: &str, contents: &str) -> String {
+        let path = password_file(name, contents);
+        let result = PasswordFileAuthenticator::load(&path);
+        fs::remove_file(path).unwrap();
+        match result {
+            Err(e) => e.to_string(),
+            Ok(_) => panic!("expected {} to be rejected", contents),
+        }
+    }
+
+    #[test]
+    fn password_file_checks_passwords() {
+        let contents = format!("# users\n\nalice:{}\n", hash("secret"));
+        let path = password_file("passwords", &contents);
+        let auth = PasswordFileAuthenticator::load(&path).unwrap();
+        fs::remove_file(path).unwrap();
+
+        let identity = auth
+            .authenticate(&credentials(Some("alice"), "secret"))
+            .unwrap();
+        assert_eq!(identity.username.as_deref(), Some("alice"));
+        let wrong = auth.authenticate(&credentials(Some("alice"), "guess"));
+        assert_eq!(wrong.unwrap_err(), AuthError::BadCredentials);
+        // Checked against the dummy hash, which no password matches.
+        let unknown = auth.authenticate(&credentials(Some("bob"), "secret"));
+        assert_eq!(unknown.unwrap_err(), AuthError::BadCredentials);
+        let anonymous = auth.authenticate(&credentials(None, "secret"));
+        assert_eq!(anonymous.unwrap_err(), AuthError::BadCredentials);
+    }
+
+    #[test]
+    fn password_file_dummy_hash_uses_the_file_parameters() {
+        let users = HashMap::from([("alice".to_owned(), hash("secret"))]);
+        let dummy = dummy_hash(&users).unwrap();
+        let params = |hash: &str| Params::try_from(&PasswordHash::new(hash).unwrap()).unwrap();
+        assert_eq!(params(&dummy).m_cost(), Params::MIN_M_COST);
+        assert_eq!(params(&dummy).t_cost(), params(&users["alice"]).t_cost());
+    }
+
+    #[test]
+    fn password_file_rejects_invalid_lines() {
+        assert!(load_error("separator", "alice").starts_with("line 1: expected username:hash"));
+        let invalid = load_error("invalid", "\nalice:secret");
+        assert!(invalid.starts_with("line 2: invalid password hash"));
+        // A PHC string, but a PBKDF2 one.
+        let pbkdf2 = "alice:$pbkdf2-sha256$i=1000$c2FsdHNhbHQ$ZmFrZWhhc2hmYWtlaGFzaA";
+        let other = load_error("pbkdf2", pbkdf2);
+        assert!(other.starts_with("line 1: not an argon2 password hash"));
+    }
+
+    fn token(claims: serde_json::Value) -> String {
+        jsonwebtoken::encode(
+            &Header::default(),
+            &claims,
+            &EncodingKey::from_secret(b"key"),
+        )
+        .unwrap()
+    }
+
+    fn expires_in(seconds: i64) -> i64 {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs() as i64;
+        now + seconds
+    }
+
+    #[test]
+    fn jwt_takes_the_username_from_the_subject() {
+        let auth = JwtAuthenticator::new(b"key");
+        let password = token(json!({"sub": "alice", "exp": expires_in(60)}));
+
+        let identity = auth.authenticate(&credentials(None, &password)).unwrap();
+        assert_eq!(identity.username.as_deref(), Some("alice"));
+        assert!(
+            auth.authenticate(&credentials(Some("alice"), &password))
+                .is_ok()
+        );
+        let other = auth.authenticate(&credentials(Some("bob"), &password));
+        assert_eq!(other.unwrap_err(), AuthError::BadCredentials);
+        let forged = auth.authenticate(&credentials(None, &format!("{}x", password)));
+        assert_eq!(forged.unwrap_err(), AuthError::BadCredentials);
+    }
+
+    #[test]
+    fn jwt_requires_an_unexpired_token() {
+        let auth = JwtAuthenticator::new(b"key");
+        // Past the default leeway of a minute.
+        let expired = token(json!({"sub": "alice", "exp": expires_in(-120)}));
+        let result = auth.authenticate(&credentials(None, &expired));
+        assert_eq!(result.unwrap_err(), AuthError::BadCredentials);
+        let unbounded = token(json!({"sub": "alice"}));
+        let result = auth.authenticate(&credentials(None, &unbounded));
+        assert_eq!(result.unwrap_err(), AuthError::BadCredentials);
+    }
+
+    #[test]
+    fn jwt_client_id_claim_restricts_the_client() {
+        let auth = JwtAuthenticator::new(b"key");
+        let password = token(json!({"sub": "alice", "client_id": "c1", "exp": expires_in(60)}));
+        assert!(auth.authenticate(&credentials(None, &password)).is_ok());
+
+        let other_client = Credentials {
+            client_id: "c2".into(),
+            ..credentials(None, &password)
+        };
+        let result = auth.authenticate(&other_client);
+        assert_eq!(result.unwrap_err(), AuthError::NotAuthorized);
+    }
+
+    #[test]
+    fn auth_errors_map_to_connack_reasons() {
+        use v3::codec::ConnectAckReason as V3;
+        use v5::codec::ConnectAckReason as V5;
+
+        assert_eq!(
+            V3::from(AuthError::BadCredentials),
+            V3::BadUserNameOrPassword
+        );
+        assert_eq!(V3::from(AuthError::NotAuthorized), V3::NotAuthorized);
+        assert_eq!(
+            V3::from(AuthError::IdentifierRejected),
+            V3::IdentifierRejected
+        );
+        assert_eq!(
+            V5::from(AuthError::BadCredentials),
+            V5::BadUserNameOrPassword
+        );
+        assert_eq!(V5::from(AuthError::NotAuthorized), V5::NotAuthorized);
+        assert_eq!(
+            V5::from(AuthError::IdentifierRejected),
+            V5::ClientIdentifierNotValid
+        );
+    }
+}
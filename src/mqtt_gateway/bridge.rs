@@ -0,0 +1,222 @@
+use clap::ValueEnum;
+use ntex::util::{ByteString, Bytes};
+use ntex_mqtt::error::SendPacketError;
+use ntex_mqtt::{QoS, v3, v5};
+use serde::Deserialize;
+use std::fmt;
+
+use super::CONFIG;
+use super::error::ServerError;
+use super::session::SessionState;
+
+/// MQTT protocol version spoken on one side of the gateway.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, ValueEnum)]
+#[serde(rename_all = "kebab-case")]
+pub enum ProtocolVersion {
+    #[serde(alias = "3", alias = "3.1.1")]
+    #[value(alias = "3", alias = "3.1.1")]
+    V3,
+    #[serde(alias = "5")]
+    #[value(alias = "5")]
+    V5,
+}
+
+/// Ties a sink to the sink type of the other protocol version, so a session can be backed by an
+/// upstream connection that speaks a different version than the client.
+pub trait Bridge {
+    type Peer: Clone + fmt::Debug;
+
+    /// Sends a QoS 1 publish on this connection and waits for its ack.
+    fn send_at_least_once(
+        &self,
+        topic: ByteString,
+        payload: Bytes,
+        packet_id: u16,
+        dup: bool,
+    ) -> impl Future<Output = Result<(), SendPacketError>>;
+
+    /// Subscribes the upstream of a session to all the session subscriptions again.
+    fn resubscribe(session: &SessionState<Self>) -> impl Future<Output = Result<(), ServerError>>
+    where
+        Self: Sized;
+}
+
+impl Bridge for v3::MqttSink {
+    type Peer = v5::MqttSink;
+
+    fn send_at_least_once(
+        &self,
+        topic: ByteString,
+        payload: Bytes,
+        packet_id: u16,
+        dup: bool,
+    ) -> impl Future<Output = Result<(), SendPacketError>> {
+        self.publish(topic, payload)
+            .packet_id(packet_id)
+            .dup(dup)
+            .send_at_least_once()
+    }
+
+    async fn resubscribe(session: &SessionState<Self>) -> Result<(), ServerError> {
+        session.sink().resubscribe(session).await
+    }
+}
+
+impl Bridge for v5::MqttSink {
+    type Peer = v3::MqttSink;
+
+    async fn send_at_least_once(
+        &self,
+        topic: ByteString,
+        payload: Bytes,
+        packet_id: u16,
+        dup: bool,
+    ) -> Result<(), SendPacketError> {
+        self.publish(topic, payload)
+            .packet_id(packet_id)
+            .dup(dup)
+            .send_at_least_once()
+            .await
+            .map(drop)
+    }
+
+    async fn resubscribe(session: &SessionState<Self>) -> Result<(), ServerError> {
+        session.sink().resubscribe(session).await
+    }
+}
+
+/// Returns the protocol version to use for backend connections, `None` means the same version as
+/// the client.
+pub(crate) fn upstream_protocol() -> Option<ProtocolVersion> {
+    CONFIG.upstream.protocol
+}
+
+pub(crate) fn is_success(code: v5::codec::PublishAckReason) -> bool {
+    u8::from(code) < 0x80
+}
+
+pub(crate) fn subscribe_code_to_v3(
+    code: v5::codec::SubscribeAckReason,
+) -> v3::codec::SubscribeReturnCode {
+    match code {
+        v5::codec::SubscribeAckReason::GrantedQos0 => {
+            v3::codec::SubscribeReturnCode::Success(QoS::AtMostOnce)
+        }
+        v5::codec::SubscribeAckReason::GrantedQos1 => {
+            v3::codec::SubscribeReturnCode::Success(QoS::AtLeastOnce)
+        }
+        v5::codec::SubscribeAckReason::GrantedQos2 => {
+            v3::codec::SubscribeReturnCode::Success(QoS::ExactlyOnce)
+        }
+        _ => v3::codec::SubscribeReturnCode::Failure,
+    }
+}
+
+pub(crate) fn subscribe_code_to_v5(
+    code: v3::codec::SubscribeReturnCode,
+) -> v5::codec::SubscribeAckReason {
+    match code {
+        v3::codec::SubscribeReturnCode::Success(QoS::AtMostOnce) => {
+            v5::codec::SubscribeAckReason::GrantedQos0
+        }
+        v3::codec::SubscribeReturnCode::Success(QoS::AtLeastOnce) => {
+            v5::codec::SubscribeAckReason::GrantedQos1
+        }
+        v3::codec::SubscribeReturnCode::Success(QoS::ExactlyOnce) => {
+            v5::codec::SubscribeAckReason::GrantedQos2
+        }
+        v3::codec::SubscribeReturnCode::Failure => v5::codec::SubscribeAckReason::UnspecifiedError,
+    }
+}
+
+pub(crate) fn connect_code_to_v3(code: v5::codec::ConnectAckReason) -> v3::codec::ConnectAckReason {
+    match code {
+        v5::codec::ConnectAckReason::Success => v3::codec::ConnectAckReason::ConnectionAccepted,
+        v5::codec::ConnectAckReason::UnsupportedProtocolVersion => {
+            v3::codec::ConnectAckReason::UnacceptableProtocolVersion
+        }
+        v5::codec::ConnectAckReason::ClientIdentifierNotValid => {
+            v3::codec::ConnectAckReason::IdentifierRejected
+        }
+        v5::codec::ConnectAckReason::BadUserNameOrPassword => {
+            v3::codec::ConnectAckReason::BadUserNameOrPassword
+        }
+        v5::codec::ConnectAckReason::NotAuthorized | v5::codec::ConnectAckReason::Banned => {
+            v3::codec::ConnectAckReason::NotAuthorized
+        }
+        _ => v3::codec::ConnectAckReason::ServiceUnavailable,
+    }
+}
+
+pub(crate) fn connect_code_to_v5(code: v3::codec::ConnectAckReason) -> v5::codec::ConnectAckReason {
+    match code {
+        v3::codec::ConnectAckReason::ConnectionAccepted => v5::codec::ConnectAckReason::Success,
+        v3::codec::ConnectAckReason::UnacceptableProtocolVersion => {
+            v5::codec::ConnectAckReason::UnsupportedProtocolVersion
+        }
+        v3::codec::ConnectAckReason::IdentifierRejected => {
+            v5::codec::ConnectAckReason::ClientIdentifierNotValid
+        }
+        v3::codec::ConnectAckReason::BadUserNameOrPassword => {
+            v5::codec::ConnectAckReason::BadUserNameOrPassword
+        }
+        v3::codec::ConnectAckReason::NotAuthorized => v5::codec::ConnectAckReason::NotAuthorized,
+        _ => v5::codec::ConnectAckReason::ServerUnavailable,
+    }
+}
+
+/// MQTT 3.1.1 has no way to tell the client why the connection is refused other than the CONNACK
+/// return codes, so anything else is reported as "server unavailable".
+pub(crate) fn reject_v3<St>(
+    handshake: v3::Handshake,
+    code: v3::codec::ConnectAckReason,
+) -> v3::HandshakeAck<St> {
+    match code {
+        v3::codec::ConnectAckReason::IdentifierRejected => handshake.identifier_rejected(),
+        v3::codec::ConnectAckReason::BadUserNameOrPassword => handshake.bad_username_or_pwd(),
+        v3::codec::ConnectAckReason::NotAuthorized => handshake.not_authorized(),
+        _ => handshake.service_unavailable(),
+    }
+}
+
+/// Translates a MQTT 3.1.1 CONNECT into its MQTT v5 equivalent, v5-only properties are left unset.
+pub(crate) fn connect_to_v5(connect: &v3::codec::Connect) -> v5::codec::Connect {
+    v5::codec::Connect {
+        clean_start: connect.clean_session,
+        keep_alive: connect.keep_alive,
+        last_will: connect.last_will.as_ref().map(|will| v5::codec::LastWill {
+            qos: will.qos,
+            retain: will.retain,
+            topic: will.topic.clone(),
+            message: will.message.clone(),
+            will_delay_interval_sec: None,
+            correlation_data: None,
+            message_expiry_interval: None,
+            content_type: None,
+            user_properties: Vec::new(),
+            is_utf8_payload: None,
+            response_topic: None,
+        }),
+        client_id: connect.client_id.clone(),
+        username: connect.username.clone(),
+        password: connect.password.clone(),
+        ..Default::default()
+    }
+}
+
+/// Translates a MQTT v5 CONNECT into its MQTT 3.1.1 equivalent, v5 properties are dropped.
+pub(crate) fn connect_to_v3(connect: &v5::codec::Connect) -> v3::codec::Connect {
+    v3::codec::Connect {
+        clean_session: connect.clean_start,
+        keep_alive: connect.keep_alive,
+        last_will: connect.last_will.as_ref().map(|will| v3::codec::LastWill {
+            qos: will.qos,
+            retain: will.retain,
+            topic: will.topic.clone(),
+            message: will.message.clone(),
+        }),
+        client_id: connect.client_id.clone(),
+        username: connect.username.clone(),
+        password: connect.password.clone(),
+    }
+}
@@ -0,0 +1,162 @@
+use super::CONFIG;
+use super::auth::AuthError;
+use ntex::tls::rustls::PeerCert;
+use ntex_io::IoRef;
+use sha2::{Digest, Sha256};
+use x509_parser::certificate::X509Certificate;
+use x509_parser::extensions::GeneralName;
+use x509_parser::prelude::FromDer;
+
+/// Client certificate of a TLS connection, parsed once when the client connects.
+#[derive(Debug, Clone, Default)]
+pub struct ClientCertificate {
+    pub subject: String,
+    pub issuer: String,
+    /// Serial number as colon separated hex, the way CRL entries are matched.
+    pub serial: String,
+    pub common_name: Option<String>,
+    pub organizational_units: Vec<String>,
+    pub san_dns: Vec<String>,
+    pub san_email: Vec<String>,
+    pub san_uri: Vec<String>,
+    /// SHA-256 of the DER encoding, in lowercase hex.
+    pub fingerprint: String,
+}
+
+impl ClientCertificate {
+    pub fn parse(der: &[u8]) -> Option<Self> {
+        let (_, cert) = X509Certificate::from_der(der).ok()?;
+        let subject = cert.subject();
+        let mut parsed = Self {
+            subject: subject.to_string(),
+            issuer: cert.issuer().to_string(),
+            serial: cert.raw_serial_as_string(),
+            common_name: subject
+                .iter_common_name()
+                .next()
+                .and_then(|cn| cn.as_str().ok())
+                .map(str::to_owned),
+            organizational_units: subject
+                .iter_organizational_unit()
+                .filter_map(|ou| ou.as_str().ok())
+                .map(str::to_owned)
+                .collect(),
+            fingerprint: Sha256::digest(der)
+                .iter()
+                .map(|b| format!("{:02x}", b))
+                .collect(),
+            ..Default::default()
+        };
+
+        if let Ok(Some(san)) = cert.subject_alternative_name() {
+            for name in &san.value.general_names {
+                match name {
+                    GeneralName::DNSName(dns) => parsed.san_dns.push(dns.to_string()),
+                    GeneralName::RFC822Name(email) => parsed.san_email.push(email.to_string()),
+                    GeneralName::URI(uri) => parsed.san_uri.push(uri.to_string()),
+                    _ => {}
+                }
+            }
+        }
+        Some(parsed)
+    }
+
+    /// Returns the certificate of a TLS connection, `None` for plain TCP.
+    pub fn from_io(io: &IoRef) -> Option<Self> {
+        io.query::<PeerCert>()
+            .as_ref()
+            .and_then(|cert| Self::parse(&cert.0))
+    }
+
+    /// Value of a field, the first one for fields that may occur several times.
+    pub fn field(&self, field: CertField) -> Option<&str> {
+        match field {
+            CertField::Subject => Some(&self.subject),
+            CertField::CommonName => self.common_name.as_deref(),
+            CertField::OrganizationalUnit => self.organizational_units.first().map(String::as_str),
+            CertField::SanDns => self.san_dns.first().map(String::as_str),
+            CertField::SanEmail => self.san_email.first().map(String::as_str),
+            CertField::SanUri => self.san_uri.first().map(String::as_str),
+            CertField::Fingerprint => Some(&self.fingerprint),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CertField {
+    Subject,
+    CommonName,
+    OrganizationalUnit,
+    SanDns,
+    SanEmail,
+    SanUri,
+    Fingerprint,
+}
+
+impl CertField {
+    pub fn parse(name: &str) -> Option<Self> {
+        match name {
+            "subject" => Some(CertField::Subject),
+            "cn" => Some(CertField::CommonName),
+            "ou" => Some(CertField::OrganizationalUnit),
+            "san_dns" => Some(CertField::SanDns),
+            "san_email" => Some(CertField::SanEmail),
+            "san_uri" => Some(CertField::SanUri),
+            "fingerprint" => Some(CertField::Fingerprint),
+            _ => None,
+        }
+    }
+}
+
+/// Which certificate fields identify a client. Mapped fields replace what the client claims in
+/// its CONNECT, or with `require_client_id` the claimed client id has to match the certificate.
+/// Clients without a certificate are left alone, requiring one is up to the authenticator.
+#[derive(Debug, Default)]
+pub struct CertMapping {
+    pub client_id: Option<CertField>,
+    pub username: Option<CertField>,
+    pub require_client_id: bool,
+}
+
+impl CertMapping {
+    /// Returns the client id the session uses.
+    pub fn client_id(
+        &self,
+        cert: Option<&ClientCertificate>,
+        claimed: &str,
+    ) -> Result<Option<String>, AuthError> {
+        let Some(cert) = cert else {
+            return Ok(None);
+        };
+
+        let field = match (self.client_id, self.require_client_id) {
+            (Some(field), _) => field,
+            (None, true) => CertField::CommonName,
+            (None, false) => return Ok(None),
+        };
+        let value = cert.field(field);
+        if self.require_client_id {
+            return match value {
+                Some(value) if value == claimed => Ok(None),
+                _ => Err(AuthError::IdentifierRejected),
+            };
+        }
+        Ok(value.map(str::to_owned))
+    }
+
+    pub fn username(&self, cert: Option<&ClientCertificate>) -> Option<String> {
+        cert?.field(self.username?).map(str::to_owned)
+    }
+}
+
+/// Certificate fields are validated when the configuration is loaded.
+pub(crate) fn create_cert_mapping() -> CertMapping {
+    let fields = &CONFIG.cert_mapping;
+    let field = |name: &Option<String>| name.as_deref().map(|name| CertField::parse(name).unwrap());
+
+    CertMapping {
+        client_id: field(&fields.client_id_field),
+        username: field(&fields.username_field),
+        require_client_id: fields.require_client_id,
+    }
+}
@@ -0,0 +1,323 @@
+use super::dedup::DualSide;
+use hdrhistogram::Histogram;
+use log::{info, warn};
+use ntex::time::{Seconds, sleep};
+use std::fmt::{self, Write};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{LazyLock, Mutex};
+use std::time::{Duration, Instant};
+
+/// Log target of the comparison between the backends of dual sessions, so it can be filtered
+/// on its own.
+const TARGET: &str = "dual_compare";
+
+struct Backend {
+    acked: u64,
+    failed: u64,
+    /// Publish ack latencies in microseconds.
+    latency: Histogram<u64>,
+    /// Messages only this backend delivered.
+    unmatched: u64,
+}
+
+impl Default for Backend {
+    fn default() -> Self {
+        Self {
+            acked: 0,
+            failed: 0,
+            latency: Histogram::new(3).expect("three significant figures are supported"),
+            unmatched: 0,
+        }
+    }
+}
+
+impl fmt::Display for Backend {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "acked={}, failed={}, p50_us={}, p99_us={}, max_us={}, unmatched={}",
+            self.acked,
+            self.failed,
+            self.latency.value_at_quantile(0.5),
+            self.latency.value_at_quantile(0.99),
+            self.latency.max(),
+            self.unmatched
+        )
+    }
+}
+
+/// What the backends of dual sessions returned since the last summary.
+struct Report {
+    since: Instant,
+    /// Subscribe and unsubscribe requests answered by both backends.
+    requests: u64,
+    /// Requests or publishes the backends answered differently.
+    divergences: u64,
+    /// Messages both backends delivered.
+    matched: u64,
+    primary: Backend,
+    secondary: Backend,
+}
+
+impl Default for Report {
+    fn default() -> Self {
+        Self {
+            since: Instant::now(),
+            requests: 0,
+            divergences: 0,
+            matched: 0,
+            primary: Backend::default(),
+            secondary: Backend::default(),
+        }
+    }
+}
+
+impl Report {
+    fn backend(&mut self, side: DualSide) -> &mut Backend {
+        match side {
+            DualSide::Primary => &mut self.primary,
+            DualSide::Secondary => &mut self.secondary,
+        }
+    }
+}
+
+static REPORT: LazyLock<Mutex<Report>> = LazyLock::new(Mutex::default);
+
+#[derive(Default)]
+struct BackendTotals {
+    acked: AtomicU64,
+    failed: AtomicU64,
+    /// Sum of the publish ack latencies in microseconds.
+    latency_us: AtomicU64,
+    unmatched: AtomicU64,
+}
+
+/// What the backends of dual sessions returned since the gateway started, for the metrics
+/// endpoint. Unlike the report, it is never reset.
+#[derive(Default)]
+struct Totals {
+    requests: AtomicU64,
+    divergences: AtomicU64,
+    matched: AtomicU64,
+    primary: BackendTotals,
+    secondary: BackendTotals,
+}
+
+impl Totals {
+    fn backend(&self, side: DualSide) -> &BackendTotals {
+        match side {
+            DualSide::Primary => &self.primary,
+            DualSide::Secondary => &self.secondary,
+        }
+    }
+}
+
+static TOTALS: LazyLock<Totals> = LazyLock::new(Totals::default);
+
+/// Compares the per filter results of a subscribe or unsubscribe sent to both backends.
+pub(crate) fn compare_acks<F, T, E>(
+    request: &str,
+    client_id: &str,
+    filters: impl IntoIterator<Item = F>,
+    primary: &Result<Vec<T>, E>,
+    secondary: &Result<Vec<T>, E>,
+) where
+    F: fmt::Display,
+    T: fmt::Debug + PartialEq,
+    E: fmt::Debug,
+{
+    let mut diverged = 0;
+    match (primary, secondary) {
+        (Ok(primary), Ok(secondary)) => {
+            for ((filter, primary), secondary) in filters.into_iter().zip(primary).zip(secondary) {
+                if primary != secondary {
+                    warn!(
+                        target: TARGET,
+                        "Backends diverge: request={}, client_id={}, filter={}, primary={:?}, \
+                         secondary={:?}",
+                        request, client_id, filter, primary, secondary
+                    );
+                    diverged += 1;
+                }
+            }
+        }
+        (Ok(_), Err(_)) | (Err(_), Ok(_)) => {
+            warn!(
+                target: TARGET,
+                "Backends diverge: request={}, client_id={}, primary={:?}, secondary={:?}",
+                request,
+                client_id,
+                primary.as_ref().map(|_| "ok"),
+                secondary.as_ref().map(|_| "ok")
+            );
+            diverged += 1;
+        }
+        (Err(_), Err(_)) => {}
+    }
+
+    let mut report = REPORT.lock().unwrap();
+    report.requests += 1;
+    report.divergences += diverged;
+    TOTALS.requests.fetch_add(1, Ordering::Relaxed);
+    TOTALS.divergences.fetch_add(diverged, Ordering::Relaxed);
+}
+
+/// Records the outcome of a QoS 1 publish forwarded to a backend.
+pub(crate) fn record_publish(side: DualSide, acked: bool, latency: Duration) {
+    let latency: u64 = latency.as_micros().try_into().unwrap_or(u64::MAX);
+    let mut report = REPORT.lock().unwrap();
+    let backend = report.backend(side);
+    let totals = TOTALS.backend(side);
+    if acked {
+        backend.acked += 1;
+        backend.latency.saturating_record(latency);
+        totals.acked.fetch_add(1, Ordering::Relaxed);
+        totals.latency_us.fetch_add(latency, Ordering::Relaxed);
+    } else {
+        backend.failed += 1;
+        totals.failed.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/// Compares the outcome of a publish forwarded to both backends.
+pub(crate) fn compare_publish(client_id: &str, topic: &str, primary: bool, secondary: bool) {
+    if primary == secondary {
+        return;
+    }
+    warn!(
+        target: TARGET,
+        "Backends diverge: request=publish, client_id={}, topic={}, primary_acked={}, \
+         secondary_acked={}",
+        client_id, topic, primary, secondary
+    );
+    REPORT.lock().unwrap().divergences += 1;
+    TOTALS.divergences.fetch_add(1, Ordering::Relaxed);
+}
+
+/// A message delivered by both backends.
+pub(crate) fn message_matched() {
+    REPORT.lock().unwrap().matched += 1;
+    TOTALS.matched.fetch_add(1, Ordering::Relaxed);
+}
+
+/// A message only one backend delivered within the deduplication window.
+pub(crate) fn message_unmatched(client_id: &str, side: DualSide, topic: &str, copies: u32) {
+    warn!(
+        target: TARGET,
+        "Message seen on one backend only: client_id={}, side={:?}, topic={}, copies={}",
+        client_id, side, topic, copies
+    );
+    REPORT.lock().unwrap().backend(side).unmatched += u64::from(copies);
+    TOTALS
+        .backend(side)
+        .unmatched
+        .fetch_add(u64::from(copies), Ordering::Relaxed);
+}
+
+/// Logs a summary of what the backends returned every `interval` and starts over.
+pub(crate) fn report_every(interval: Seconds) {
+    ntex::rt::spawn(async move {
+        loop {
+            sleep(interval).await;
+            let report = std::mem::take(&mut *REPORT.lock().unwrap());
+            info!(
+                target: TARGET,
+                "Dual backend comparison: seconds={}, requests={}, divergences={}, matched={}, \
+                 primary=[{}], secondary=[{}]",
+                report.since.elapsed().as_secs(),
+                report.requests,
+                report.divergences,
+                report.matched,
+                report.primary,
+                report.secondary
+            );
+        }
+    });
+}
+
+/// Writes the totals in the Prometheus text format.
+pub(crate) fn write_metrics(out: &mut String) -> fmt::Result {
+    let counter = |out: &mut String, name: &str, help: &str| {
+        writeln!(out, "# HELP {} {}", name, help)?;
+        writeln!(out, "# TYPE {} counter", name)
+    };
+    let sides = [
+        ("primary", &TOTALS.primary),
+        ("secondary", &TOTALS.secondary),
+    ];
+
+    counter(
+        out,
+        "gateway_dual_requests_total",
+        "Subscribe and unsubscribe requests answered by both backends.",
+    )?;
+    writeln!(
+        out,
+        "gateway_dual_requests_total {}",
+        TOTALS.requests.load(Ordering::Relaxed)
+    )?;
+    counter(
+        out,
+        "gateway_dual_divergences_total",
+        "Requests or publishes the backends answered differently.",
+    )?;
+    writeln!(
+        out,
+        "gateway_dual_divergences_total {}",
+        TOTALS.divergences.load(Ordering::Relaxed)
+    )?;
+    counter(
+        out,
+        "gateway_dual_messages_matched_total",
+        "Messages both backends delivered.",
+    )?;
+    writeln!(
+        out,
+        "gateway_dual_messages_matched_total {}",
+        TOTALS.matched.load(Ordering::Relaxed)
+    )?;
+
+    counter(
+        out,
+        "gateway_dual_messages_unmatched_total",
+        "Messages only one backend delivered within the deduplication window.",
+    )?;
+    for (side, totals) in sides {
+        writeln!(
+            out,
+            "gateway_dual_messages_unmatched_total{{backend=\"{}\"}} {}",
+            side,
+            totals.unmatched.load(Ordering::Relaxed)
+        )?;
+    }
+    counter(
+        out,
+        "gateway_dual_publishes_total",
+        "QoS 1 publishes forwarded to a backend, by outcome.",
+    )?;
+    for (side, totals) in sides {
+        for (result, count) in [("acked", &totals.acked), ("failed", &totals.failed)] {
+            writeln!(
+                out,
+                "gateway_dual_publishes_total{{backend=\"{}\",result=\"{}\"}} {}",
+                side,
+                result,
+                count.load(Ordering::Relaxed)
+            )?;
+        }
+    }
+    counter(
+        out,
+        "gateway_dual_publish_ack_seconds_total",
+        "Time acked publishes waited for their ack.",
+    )?;
+    for (side, totals) in sides {
+        writeln!(
+            out,
+            "gateway_dual_publish_ack_seconds_total{{backend=\"{}\"}} {}",
+            side,
+            totals.latency_us.load(Ordering::Relaxed) as f64 / 1e6
+        )?;
+    }
+    Ok(())
+}
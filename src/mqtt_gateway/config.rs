@@ -0,0 +1,1064 @@
+use super::acl::DeniedPublish;
+use super::auth::AuthBackend;
+use super::bridge::ProtocolVersion;
+use super::cert::CertField;
+use super::dedup::DedupPreference;
+use super::dual::{DualMode, SubscribePolicy};
+use super::tls::{ClientAuth, TlsVersion};
+use clap::Parser;
+use clap::builder::FalseyValueParser;
+use log::error;
+use ntex::time::Seconds;
+use pingora_load_balancing::Backend;
+use serde::Deserialize;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+
+/// Command line flags. Each one can also be set through its environment variable, and both
+/// take precedence over the configuration file.
+#[derive(Parser, Debug)]
+#[command(version, about = "MQTT gateway", long_about = None)]
+struct Args {
+    /// TOML configuration file.
+    #[arg(short, long, env = "GATEWAY_CONFIG")]
+    config: Option<PathBuf>,
+
+    /// Address of the plain TCP listener.
+    #[arg(long, env = "GATEWAY_TCP_ADDR")]
+    tcp_addr: Option<SocketAddr>,
+
+    /// Address of the TLS listener.
+    #[arg(long, env = "GATEWAY_TLS_ADDR")]
+    tls_addr: Option<SocketAddr>,
+
+    /// Worker threads of each listener.
+    #[arg(short, long, env = "GATEWAY_WORKERS")]
+    workers: Option<usize>,
+
+    /// Server certificate chain of the TLS listener.
+    #[arg(long, env = "TLS_CERT_FILE")]
+    tls_cert_file: Option<PathBuf>,
+
+    /// Private key of the TLS listener.
+    #[arg(long, env = "TLS_KEY_FILE")]
+    tls_key_file: Option<PathBuf>,
+
+    /// CA bundle client certificates are verified against.
+    #[arg(long, env = "TLS_CLIENT_CA_FILE")]
+    tls_client_ca_file: Option<PathBuf>,
+
+    /// Whether TLS clients are asked for a certificate, required when a client CA is set.
+    #[arg(long, env = "TLS_CLIENT_AUTH", value_enum)]
+    tls_client_auth: Option<ClientAuth>,
+
+    /// Comma separated CRL files client certificates are checked against.
+    #[arg(long, env = "TLS_CRL_FILES", value_delimiter = ',')]
+    tls_crl_files: Option<Vec<PathBuf>>,
+
+    /// Closes the sessions of client certificates a reloaded CRL revokes.
+    #[arg(
+        long,
+        env = "TLS_CRL_KICK",
+        num_args = 0..=1,
+        default_missing_value = "true",
+        value_parser = FalseyValueParser::new()
+    )]
+    tls_crl_kick: Option<bool>,
+
+    /// Comma separated ALPN protocols of the TLS listener.
+    #[arg(long, env = "TLS_ALPN", value_delimiter = ',')]
+    tls_alpn: Option<Vec<String>>,
+
+    /// Oldest TLS version the listener accepts.
+    #[arg(long, env = "TLS_MIN_VERSION", value_enum)]
+    tls_min_version: Option<TlsVersion>,
+
+    /// Seconds between checks of the TLS files for changes, none when zero.
+    #[arg(long, env = "TLS_RELOAD_INTERVAL")]
+    tls_reload_interval: Option<u16>,
+
+    /// Where client credentials are checked, every client is accepted when unset.
+    #[arg(long, env = "AUTH_BACKEND", value_enum)]
+    auth_backend: Option<AuthBackend>,
+
+    /// Password file of the `file` authentication backend.
+    #[arg(long, env = "AUTH_PASSWORD_FILE")]
+    auth_password_file: Option<PathBuf>,
+
+    /// File of the secret JWTs are signed with, for the `jwt` authentication backend.
+    #[arg(long, env = "AUTH_JWT_KEY_FILE")]
+    auth_jwt_key_file: Option<PathBuf>,
+
+    /// Topic ACL file, every client may use every topic when unset.
+    #[arg(long, env = "ACL_FILE")]
+    acl_file: Option<PathBuf>,
+
+    /// What happens to a client publishing on a topic the ACL denies.
+    #[arg(long, env = "ACL_DENIED_PUBLISH", value_enum)]
+    acl_denied_publish: Option<DeniedPublish>,
+
+    /// Client certificate field used as the client id.
+    #[arg(long, env = "CERT_CLIENT_ID_FIELD")]
+    cert_client_id_field: Option<String>,
+
+    /// Client certificate field used as the username.
+    #[arg(long, env = "CERT_USERNAME_FIELD")]
+    cert_username_field: Option<String>,
+
+    /// Rejects clients whose client id differs from the certificate field.
+    #[arg(
+        long,
+        env = "CERT_REQUIRE_CLIENT_ID",
+        num_args = 0..=1,
+        default_missing_value = "true",
+        value_parser = FalseyValueParser::new()
+    )]
+    cert_require_client_id: Option<bool>,
+
+    /// Comma separated backend addresses.
+    #[arg(long, env = "BACKEND", value_delimiter = ',')]
+    backend: Option<Vec<String>>,
+
+    /// Keep-alive of backend connections in seconds, the client's own when unset.
+    #[arg(long, env = "UPSTREAM_KEEP_ALIVE")]
+    keep_alive: Option<u16>,
+
+    /// MQTT version of backend connections, the client's own when unset.
+    #[arg(long, env = "UPSTREAM_PROTOCOL", value_enum)]
+    upstream_protocol: Option<ProtocolVersion>,
+
+    /// Seconds between backend health checks.
+    #[arg(long, env = "HEALTH_CHECK_INTERVAL")]
+    health_check_interval: Option<u64>,
+
+    /// Seconds between backend discovery updates.
+    #[arg(long, env = "DISCOVERY_INTERVAL")]
+    discovery_interval: Option<u64>,
+
+    /// QoS 1 publishes each session forwards without an ack yet, per direction.
+    #[arg(long, env = "INFLIGHT_MAX")]
+    inflight_max: Option<usize>,
+
+    /// Seconds to wait for an ack before a publish is sent again.
+    #[arg(long, env = "INFLIGHT_RETRY_SECS")]
+    inflight_retry_secs: Option<u16>,
+
+    /// Times a publish is sent again before the session gives up on it.
+    #[arg(long, env = "INFLIGHT_MAX_RETRIES")]
+    inflight_max_retries: Option<u16>,
+
+    /// Address of the metrics endpoint, none when unset.
+    #[arg(long, env = "METRICS_ADDR")]
+    metrics_addr: Option<SocketAddr>,
+
+    /// Directory sessions are kept in, in memory when unset.
+    #[arg(long, env = "SESSION_STORE_DIR")]
+    session_store_dir: Option<PathBuf>,
+
+    /// Runs every client in dual mode, not just the cohorts of the configuration file.
+    #[arg(
+        long,
+        env = "RUN_DUAL",
+        num_args = 0..=1,
+        default_missing_value = "true",
+        value_parser = FalseyValueParser::new()
+    )]
+    dual: Option<bool>,
+
+    /// How dual sessions write client publishes to the two backends.
+    #[arg(long, env = "DUAL_MODE", value_enum)]
+    dual_mode: Option<DualMode>,
+
+    /// How dual sessions combine the subscribe results of the two backends.
+    #[arg(long, env = "DUAL_SUBSCRIBE_POLICY", value_enum)]
+    dual_subscribe_policy: Option<SubscribePolicy>,
+
+    /// Comma separated addresses of the dual primary backends.
+    #[arg(long, env = "DUAL_PRIMARY", value_delimiter = ',')]
+    dual_primary: Option<Vec<String>>,
+
+    /// Comma separated addresses of the dual secondary backends.
+    #[arg(long, env = "DUAL_SECONDARY", value_delimiter = ',')]
+    dual_secondary: Option<Vec<String>>,
+
+    /// Comma separated tenant names, replacing the tenants of the configuration file. A tenant
+    /// `acme` is configured by `TENANT_ACME_*` environment variables, see `TenantSettings`.
+    #[arg(long, env = "TENANTS", value_delimiter = ',')]
+    tenants: Option<Vec<String>>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    /// Worker threads of each listener, one per CPU by default.
+    pub workers: usize,
+    pub listeners: Listeners,
+    pub tls: Tls,
+    pub metrics: Metrics,
+    pub auth: Auth,
+    pub acl: Acl,
+    pub cert_mapping: CertFields,
+    pub upstream: Upstream,
+    pub inflight: Inflight,
+    pub session_store: SessionStorage,
+    pub dual: Dual,
+    pub tenants: Vec<TenantSettings>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Listeners {
+    pub tcp: SocketAddr,
+    pub tls: SocketAddr,
+}
+
+/// Certificates of the TLS listener, and of its clients when they are asked for one.
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Tls {
+    pub cert_file: PathBuf,
+    pub key_file: PathBuf,
+    /// CA bundle client certificates are verified against.
+    pub client_ca_file: Option<PathBuf>,
+    /// Required when unset and `client_ca_file` is set, otherwise none.
+    pub client_auth: Option<ClientAuth>,
+    /// CRLs client certificates are checked against.
+    pub crl_files: Vec<PathBuf>,
+    /// Closes the sessions of client certificates a reloaded CRL revokes.
+    pub crl_kick: bool,
+    pub alpn: Vec<String>,
+    pub min_version: TlsVersion,
+    /// Seconds between checks of the files for changes, none when zero.
+    pub reload_interval: u16,
+}
+
+/// HTTP endpoint serving the gateway metrics in the Prometheus text format.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Metrics {
+    /// Address of the endpoint, the metrics are not served when unset.
+    pub addr: Option<SocketAddr>,
+}
+
+/// How clients are authenticated, every client is accepted without a backend.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Auth {
+    pub backend: Option<AuthBackend>,
+    /// `username:hash` lines with Argon2 hashes, for the `file` backend.
+    pub password_file: Option<PathBuf>,
+    /// Secret JWTs are signed with, for the `jwt` backend.
+    pub jwt_key_file: Option<PathBuf>,
+}
+
+/// Topic access rules, every client may use every topic without a file.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Acl {
+    /// `allow|deny pub|sub|pubsub <filter>` lines, checked in order.
+    pub file: Option<PathBuf>,
+    pub denied_publish: DeniedPublish,
+}
+
+/// Client certificate fields identifying a client: `subject`, `cn`, `ou`, `san_dns`,
+/// `san_email`, `san_uri` or `fingerprint`.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CertFields {
+    pub client_id_field: Option<String>,
+    pub username_field: Option<String>,
+    /// Rejects clients whose client id differs from the certificate field, instead of
+    /// replacing it.
+    pub require_client_id: bool,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Upstream {
+    pub backends: Vec<String>,
+    /// Keep-alive of backend connections in seconds, the client's own when unset.
+    pub keep_alive: Option<u16>,
+    /// MQTT version of backend connections, the client's own when unset.
+    pub protocol: Option<ProtocolVersion>,
+    pub health_check_interval: u64,
+    pub discovery_interval: u64,
+}
+
+/// Forwarded QoS 1 publishes waiting for their ack.
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Inflight {
+    pub max: usize,
+    pub retry_secs: u16,
+    pub max_retries: u16,
+}
+
+/// Where the sessions of clients that connect with clean-session unset are kept.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SessionStorage {
+    /// Directory of the session files, sessions are kept in memory when unset.
+    pub dir: Option<PathBuf>,
+}
+
+/// Dual mode connects a client to a backend of the primary and one of the secondary pool.
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Dual {
+    /// Runs every client in dual mode, otherwise only the clients of a cohort.
+    pub enabled: bool,
+    pub primary: Vec<String>,
+    pub secondary: Vec<String>,
+    pub mode: DualMode,
+    /// File naming the mode to switch to, checked while the gateway runs.
+    pub mode_file: Option<PathBuf>,
+    pub subscribe_policy: SubscribePolicy,
+    pub dedup: Dedup,
+    /// Seconds between summaries of how the two backends compare, none when zero.
+    pub report_interval: u16,
+    pub cohorts: Vec<Cohort>,
+}
+
+/// Window of the publishes forwarded from one backend, to drop the other backend's copy.
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Dedup {
+    /// Messages remembered per session.
+    pub size: usize,
+    /// Seconds a message is remembered.
+    pub ttl_secs: u64,
+    pub prefer: DedupPreference,
+    /// How long a copy of the backend that is not preferred waits for the preferred one.
+    pub hold_millis: u32,
+}
+
+/// Clients running in dual mode. Every condition that is set has to match.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Cohort {
+    /// Client id pattern, `*` matches any number of characters.
+    pub client_id: Option<String>,
+    pub tenant: Option<String>,
+    /// Certificate field, named as for `cert_mapping`, to match `certificate_value`.
+    pub certificate_field: Option<String>,
+    /// Pattern for the certificate field, `*` matches any number of characters.
+    pub certificate_value: Option<String>,
+}
+
+/// A customer hosted behind the TLS listener, picked by the server name its clients connect to.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TenantSettings {
+    pub name: String,
+    /// Server names routed to the tenant, `*.example.com` matches a single label.
+    pub server_names: Vec<String>,
+    pub backends: Vec<String>,
+    /// Server certificate and key replacing the listener's.
+    pub cert_file: Option<PathBuf>,
+    pub key_file: Option<PathBuf>,
+    /// CA bundle replacing the listener's, client certificates are required with one.
+    pub client_ca_file: Option<PathBuf>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            workers: std::thread::available_parallelism().map_or(1, usize::from),
+            listeners: Listeners::default(),
+            tls: Tls::default(),
+            metrics: Metrics::default(),
+            auth: Auth::default(),
+            acl: Acl::default(),
+            cert_mapping: CertFields::default(),
+            upstream: Upstream::default(),
+            inflight: Inflight::default(),
+            session_store: SessionStorage::default(),
+            dual: Dual::default(),
+            tenants: Vec::new(),
+        }
+    }
+}
+
+impl Default for Listeners {
+    fn default() -> Self {
+        Self {
+            tcp: ([0, 0, 0, 0], 1884).into(),
+            tls: ([0, 0, 0, 0], 1885).into(),
+        }
+    }
+}
+
+impl Default for Tls {
+    fn default() -> Self {
+        Self {
+            cert_file: "resources/server.chain.crt".into(),
+            key_file: "resources/server.pkcs8.key".into(),
+            client_ca_file: None,
+            client_auth: None,
+            crl_files: Vec::new(),
+            crl_kick: false,
+            alpn: Vec::new(),
+            min_version: TlsVersion::default(),
+            reload_interval: 30,
+        }
+    }
+}
+
+impl Tls {
+    pub fn client_auth(&self) -> ClientAuth {
+        match (self.client_auth, &self.client_ca_file) {
+            (Some(client_auth), _) => client_auth,
+            (None, Some(_)) => ClientAuth::Required,
+            (None, None) => ClientAuth::None,
+        }
+    }
+}
+
+impl Default for Upstream {
+    fn default() -> Self {
+        Self {
+            backends: vec!["127.0.0.1:1883".into()],
+            keep_alive: None,
+            protocol: None,
+            health_check_interval: 60,
+            discovery_interval: 60,
+        }
+    }
+}
+
+impl Default for Inflight {
+    fn default() -> Self {
+        Self {
+            max: 64,
+            retry_secs: 10,
+            max_retries: 3,
+        }
+    }
+}
+
+impl Inflight {
+    pub fn retry_interval(&self) -> Seconds {
+        Seconds(self.retry_secs)
+    }
+}
+
+impl Default for Dual {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            primary: vec!["127.0.0.1:1883".into()],
+            secondary: vec!["127.0.0.1:2883".into()],
+            mode: DualMode::default(),
+            mode_file: None,
+            subscribe_policy: SubscribePolicy::default(),
+            dedup: Dedup::default(),
+            report_interval: 60,
+            cohorts: Vec::new(),
+        }
+    }
+}
+
+impl Dual {
+    pub fn report_interval(&self) -> Option<Seconds> {
+        (self.report_interval > 0).then_some(Seconds(self.report_interval))
+    }
+}
+
+impl Default for Dedup {
+    fn default() -> Self {
+        Self {
+            size: 1024,
+            ttl_secs: 30,
+            prefer: DedupPreference::default(),
+            hold_millis: 200,
+        }
+    }
+}
+
+impl TenantSettings {
+    /// Reads a tenant named in `TENANTS` from its `TENANT_<NAME>_*` environment variables, the
+    /// name upper cased with `-` as `_`. `SERVER_NAMES` and `BACKEND` are comma separated lists,
+    /// `CERT_FILE`, `KEY_FILE` and `CLIENT_CA_FILE` replace the listener's TLS files.
+    fn from_env(name: &str) -> Self {
+        let prefix = format!("TENANT_{}_", name.to_ascii_uppercase().replace('-', "_"));
+        let var = |key: &str| std::env::var(format!("{}{}", prefix, key)).ok();
+        let list = |key: &str| -> Vec<String> {
+            var(key)
+                .map(|items| {
+                    items
+                        .split(',')
+                        .map(|item| item.trim().to_owned())
+                        .filter(|item| !item.is_empty())
+                        .collect()
+                })
+                .unwrap_or_default()
+        };
+
+        Self {
+            name: name.to_owned(),
+            server_names: list("SERVER_NAMES"),
+            backends: list("BACKEND"),
+            cert_file: var("CERT_FILE").map(PathBuf::from),
+            key_file: var("KEY_FILE").map(PathBuf::from),
+            client_ca_file: var("CLIENT_CA_FILE").map(PathBuf::from),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum ConfigError {
+    Read(PathBuf, io::Error),
+    Parse(PathBuf, toml::de::Error),
+    /// A setting has a value the gateway cannot run with.
+    Invalid(&'static str, String),
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigError::Read(path, e) => write!(f, "cannot read {}: {}", path.display(), e),
+            ConfigError::Parse(path, e) => write!(f, "cannot parse {}: {}", path.display(), e),
+            ConfigError::Invalid(key, msg) => write!(f, "{}: {}", key, msg),
+        }
+    }
+}
+
+impl Config {
+    /// Reads the configuration file named on the command line, if any, and applies the flags
+    /// and environment variables on top of it.
+    pub fn load() -> Result<Self, ConfigError> {
+        let args = Args::parse();
+        let mut config = match &args.config {
+            Some(path) => Self::from_file(path)?,
+            None => Self::default(),
+        };
+        config.apply(args);
+        config.validate()?;
+        Ok(config)
+    }
+
+    fn from_file(path: &PathBuf) -> Result<Self, ConfigError> {
+        let data = fs::read_to_string(path).map_err(|e| ConfigError::Read(path.clone(), e))?;
+        toml::from_str(&data).map_err(|e| ConfigError::Parse(path.clone(), e))
+    }
+
+    fn apply(&mut self, args: Args) {
+        let Args {
+            config: _,
+            tcp_addr,
+            tls_addr,
+            metrics_addr,
+            workers,
+            tls_cert_file,
+            tls_key_file,
+            tls_client_ca_file,
+            tls_client_auth,
+            tls_crl_files,
+            tls_crl_kick,
+            tls_alpn,
+            tls_min_version,
+            tls_reload_interval,
+            auth_backend,
+            auth_password_file,
+            auth_jwt_key_file,
+            acl_file,
+            acl_denied_publish,
+            cert_client_id_field,
+            cert_username_field,
+            cert_require_client_id,
+            backend,
+            keep_alive,
+            upstream_protocol,
+            health_check_interval,
+            discovery_interval,
+            inflight_max,
+            inflight_retry_secs,
+            inflight_max_retries,
+            session_store_dir,
+            dual,
+            dual_mode,
+            dual_subscribe_policy,
+            dual_primary,
+            dual_secondary,
+            tenants,
+        } = args;
+
+        self.workers = workers.unwrap_or(self.workers);
+        self.listeners.tcp = tcp_addr.unwrap_or(self.listeners.tcp);
+        self.listeners.tls = tls_addr.unwrap_or(self.listeners.tls);
+        if let Some(cert_file) = tls_cert_file {
+            self.tls.cert_file = cert_file;
+        }
+        if let Some(key_file) = tls_key_file {
+            self.tls.key_file = key_file;
+        }
+        self.tls.client_ca_file = tls_client_ca_file.or(self.tls.client_ca_file.take());
+        self.tls.client_auth = tls_client_auth.or(self.tls.client_auth);
+        if let Some(crl_files) = tls_crl_files {
+            self.tls.crl_files = crl_files;
+        }
+        self.tls.crl_kick = tls_crl_kick.unwrap_or(self.tls.crl_kick);
+        if let Some(alpn) = tls_alpn {
+            self.tls.alpn = alpn;
+        }
+        self.tls.min_version = tls_min_version.unwrap_or(self.tls.min_version);
+        self.tls.reload_interval = tls_reload_interval.unwrap_or(self.tls.reload_interval);
+        self.metrics.addr = metrics_addr.or(self.metrics.addr);
+        self.auth.backend = auth_backend.or(self.auth.backend);
+        self.auth.password_file = auth_password_file.or(self.auth.password_file.take());
+        self.auth.jwt_key_file = auth_jwt_key_file.or(self.auth.jwt_key_file.take());
+        self.acl.file = acl_file.or(self.acl.file.take());
+        self.acl.denied_publish = acl_denied_publish.unwrap_or(self.acl.denied_publish);
+        self.cert_mapping.client_id_field =
+            cert_client_id_field.or(self.cert_mapping.client_id_field.take());
+        self.cert_mapping.username_field =
+            cert_username_field.or(self.cert_mapping.username_field.take());
+        self.cert_mapping.require_client_id =
+            cert_require_client_id.unwrap_or(self.cert_mapping.require_client_id);
+        if let Some(backends) = backend {
+            self.upstream.backends = backends;
+        }
+        self.upstream.keep_alive = keep_alive.or(self.upstream.keep_alive);
+        self.upstream.protocol = upstream_protocol.or(self.upstream.protocol);
+        self.upstream.health_check_interval =
+            health_check_interval.unwrap_or(self.upstream.health_check_interval);
+        self.upstream.discovery_interval =
+            discovery_interval.unwrap_or(self.upstream.discovery_interval);
+        self.inflight.max = inflight_max.unwrap_or(self.inflight.max);
+        self.inflight.retry_secs = inflight_retry_secs.unwrap_or(self.inflight.retry_secs);
+        self.inflight.max_retries = inflight_max_retries.unwrap_or(self.inflight.max_retries);
+        self.session_store.dir = session_store_dir.or(self.session_store.dir.take());
+        self.dual.enabled = dual.unwrap_or(self.dual.enabled);
+        self.dual.mode = dual_mode.unwrap_or(self.dual.mode);
+        self.dual.subscribe_policy = dual_subscribe_policy.unwrap_or(self.dual.subscribe_policy);
+        if let Some(primary) = dual_primary {
+            self.dual.primary = primary;
+        }
+        if let Some(secondary) = dual_secondary {
+            self.dual.secondary = secondary;
+        }
+        if let Some(names) = tenants {
+            self.tenants = names
+                .iter()
+                .map(|name| name.trim())
+                .filter(|name| !name.is_empty())
+                .map(TenantSettings::from_env)
+                .collect();
+        }
+    }
+
+    fn validate(&self) -> Result<(), ConfigError> {
+        let invalid = |key, msg: &str| Err(ConfigError::Invalid(key, msg.to_owned()));
+
+        if self.workers == 0 {
+            return invalid("workers", "at least one worker is needed");
+        }
+        if self.listeners.tcp == self.listeners.tls {
+            return invalid(
+                "listeners",
+                "the TCP and TLS listeners need different addresses",
+            );
+        }
+
+        let client_auth = self.tls.client_auth();
+        if client_auth != ClientAuth::None && self.tls.client_ca_file.is_none() {
+            return invalid(
+                "tls.client_auth",
+                "client certificates need tls.client_ca_file",
+            );
+        }
+        if !self.tls.crl_files.is_empty() && client_auth == ClientAuth::None {
+            return invalid(
+                "tls.crl_files",
+                "CRLs need client certificates to be enabled",
+            );
+        }
+        if self.tls.alpn.iter().any(|protocol| protocol.is_empty()) {
+            return invalid("tls.alpn", "protocol names cannot be empty");
+        }
+
+        if let Some(addr) = self.metrics.addr
+            && (addr == self.listeners.tcp || addr == self.listeners.tls)
+        {
+            return invalid(
+                "metrics.addr",
+                "the metrics endpoint needs an address of its own",
+            );
+        }
+
+        match self.auth.backend {
+            Some(AuthBackend::File) if self.auth.password_file.is_none() => {
+                return invalid(
+                    "auth.password_file",
+                    "the file backend needs a password file",
+                );
+            }
+            Some(AuthBackend::Jwt) if self.auth.jwt_key_file.is_none() => {
+                return invalid("auth.jwt_key_file", "the jwt backend needs a key file");
+            }
+            Some(AuthBackend::Mtls)
+                if client_auth == ClientAuth::None
+                    && self
+                        .tenants
+                        .iter()
+                        .all(|tenant| tenant.client_ca_file.is_none()) =>
+            {
+                return invalid(
+                    "auth.backend",
+                    "the mtls backend needs client certificates to be enabled",
+                );
+            }
+            _ => {}
+        }
+
+        for field in [
+            &self.cert_mapping.client_id_field,
+            &self.cert_mapping.username_field,
+        ]
+        .into_iter()
+        .flatten()
+        {
+            if CertField::parse(field).is_none() {
+                return Err(ConfigError::Invalid(
+                    "cert_mapping",
+                    format!("unknown certificate field {}", field),
+                ));
+            }
+        }
+        if self.cert_mapping.require_client_id && self.cert_mapping.client_id_field.is_none() {
+            return invalid(
+                "cert_mapping.require_client_id",
+                "needs client_id_field to compare the client id with",
+            );
+        }
+
+        validate_backends("upstream.backends", &self.upstream.backends)?;
+        if self.upstream.keep_alive == Some(0) {
+            return invalid("upstream.keep_alive", "must be at least one second");
+        }
+        if self.upstream.health_check_interval == 0 {
+            return invalid(
+                "upstream.health_check_interval",
+                "must be at least one second",
+            );
+        }
+        if self.upstream.discovery_interval == 0 {
+            return invalid("upstream.discovery_interval", "must be at least one second");
+        }
+
+        if self.inflight.max == 0 {
+            return invalid("inflight.max", "at least one publish has to be in flight");
+        }
+        if self.inflight.retry_secs == 0 {
+            return invalid("inflight.retry_secs", "must be at least one second");
+        }
+
+        if let Some(dir) = &self.session_store.dir
+            && dir.exists()
+            && !dir.is_dir()
+        {
+            return Err(ConfigError::Invalid(
+                "session_store.dir",
+                format!("{} is not a directory", dir.display()),
+            ));
+        }
+
+        validate_backends("dual.primary", &self.dual.primary)?;
+        validate_backends("dual.secondary", &self.dual.secondary)?;
+        if self.dual.dedup.size == 0 {
+            return invalid(
+                "dual.dedup.size",
+                "at least one message has to be remembered",
+            );
+        }
+        if self.dual.dedup.ttl_secs == 0 {
+            return invalid("dual.dedup.ttl_secs", "must be at least one second");
+        }
+        for cohort in &self.dual.cohorts {
+            if cohort.client_id.is_none()
+                && cohort.tenant.is_none()
+                && cohort.certificate_field.is_none()
+            {
+                return invalid("dual.cohorts", "a cohort needs at least one condition");
+            }
+            match (&cohort.certificate_field, &cohort.certificate_value) {
+                (Some(field), Some(_)) if CertField::parse(field).is_none() => {
+                    return Err(ConfigError::Invalid(
+                        "dual.cohorts.certificate_field",
+                        format!("unknown certificate field {}", field),
+                    ));
+                }
+                (Some(_), None) | (None, Some(_)) => {
+                    return invalid(
+                        "dual.cohorts",
+                        "certificate_field and certificate_value go together",
+                    );
+                }
+                _ => {}
+            }
+        }
+
+        for (i, tenant) in self.tenants.iter().enumerate() {
+            let invalid = |key, msg: &str| {
+                Err(ConfigError::Invalid(
+                    key,
+                    format!("tenant {}: {}", tenant.name, msg),
+                ))
+            };
+            if tenant.name.is_empty() {
+                return invalid("tenants.name", "the name cannot be empty");
+            }
+            if self.tenants[..i]
+                .iter()
+                .any(|other| other.name == tenant.name)
+            {
+                return invalid("tenants.name", "configured more than once");
+            }
+            if tenant.server_names.is_empty() {
+                return invalid("tenants.server_names", "at least one server name is needed");
+            }
+            if tenant.backends.is_empty() {
+                return invalid("tenants.backends", "at least one backend is needed");
+            }
+            validate_backends("tenants.backends", &tenant.backends)?;
+            if tenant.cert_file.is_some() != tenant.key_file.is_some() {
+                return invalid("tenants.key_file", "cert_file and key_file go together");
+            }
+        }
+        Ok(())
+    }
+}
+
+fn validate_backends(key: &'static str, backends: &[String]) -> Result<(), ConfigError> {
+    if backends.is_empty() {
+        return Err(ConfigError::Invalid(
+            key,
+            "at least one backend is needed".into(),
+        ));
+    }
+    for backend in backends {
+        if Backend::new(backend).is_err() {
+            return Err(ConfigError::Invalid(
+                key,
+                format!("{} is not an IP address and port", backend),
+            ));
+        }
+    }
+    Ok(())
+}
+
+pub(crate) fn load_config() -> Config {
+    or_exit(Config::load())
+}
+
+/// Unwraps what is set up from the configuration, exiting on an invalid setting.
+pub(crate) fn or_exit<T>(result: Result<T, ConfigError>) -> T {
+    result.unwrap_or_else(|e| {
+        error!("Invalid configuration: {}", e);
+        std::process::exit(1)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Key of the setting `validate` rejects.
+    fn rejected(config: &Config) -> &'static str {
+        match config.validate() {
+            Err(ConfigError::Invalid(key, _)) => key,
+            other => panic!("expected an invalid setting, got {:?}", other),
+        }
+    }
+
+    fn parse(toml: &str) -> Config {
+        toml::from_str(toml).unwrap()
+    }
+
+    fn tenant(name: &str) -> TenantSettings {
+        TenantSettings {
+            name: name.into(),
+            server_names: vec![format!("{}.example.com", name)],
+            backends: vec!["127.0.0.1:1883".into()],
+            cert_file: None,
+            key_file: None,
+            client_ca_file: None,
+        }
+    }
+
+    #[test]
+    fn defaults_are_valid() {
+        Config::default().validate().unwrap();
+    }
+
+    #[test]
+    fn parses_settings() {
+        let config = parse(
+            r#"
+            [tls]
+            client_ca_file = "ca.crt"
+            min_version = "1.3"
+
+            [auth]
+            backend = "jwt"
+            jwt_key_file = "jwt.key"
+
+            [acl]
+            denied_publish = "disconnect"
+
+            [upstream]
+            protocol = "3.1.1"
+
+            [[tenants]]
+            name = "acme"
+            server_names = ["*.acme.example"]
+            backends = ["10.0.0.1:1883"]
+            "#,
+        );
+        config.validate().unwrap();
+        assert_eq!(config.tls.client_auth(), ClientAuth::Required);
+        assert_eq!(config.tls.min_version, TlsVersion::V1_3);
+        assert_eq!(config.auth.backend, Some(AuthBackend::Jwt));
+        assert_eq!(config.acl.denied_publish, DeniedPublish::Disconnect);
+        assert_eq!(config.upstream.protocol, Some(ProtocolVersion::V3));
+        assert_eq!(config.tenants[0].name, "acme");
+    }
+
+    #[test]
+    fn rejects_unknown_settings() {
+        assert!(toml::from_str::<Config>("[tls]\ncert = \"server.crt\"").is_err());
+        assert!(toml::from_str::<Config>("[auth]\nbackend = \"ldap\"").is_err());
+    }
+
+    #[test]
+    fn rejects_listener_settings() {
+        let config = Config {
+            workers: 0,
+            ..Config::default()
+        };
+        assert_eq!(rejected(&config), "workers");
+
+        let mut config = Config::default();
+        config.listeners.tls = config.listeners.tcp;
+        assert_eq!(rejected(&config), "listeners");
+
+        let mut config = Config::default();
+        config.metrics.addr = Some(config.listeners.tcp);
+        assert_eq!(rejected(&config), "metrics.addr");
+    }
+
+    #[test]
+    fn rejects_tls_settings() {
+        let mut config = Config::default();
+        config.tls.client_auth = Some(ClientAuth::Optional);
+        assert_eq!(rejected(&config), "tls.client_auth");
+
+        let mut config = Config::default();
+        config.tls.crl_files = vec!["revoked.crl".into()];
+        assert_eq!(rejected(&config), "tls.crl_files");
+
+        config.tls.client_ca_file = Some("ca.crt".into());
+        config.validate().unwrap();
+        config.tls.client_auth = Some(ClientAuth::None);
+        assert_eq!(rejected(&config), "tls.crl_files");
+    }
+
+    #[test]
+    fn rejects_auth_settings() {
+        let mut config = Config::default();
+        config.auth.backend = Some(AuthBackend::File);
+        assert_eq!(rejected(&config), "auth.password_file");
+
+        config.auth.backend = Some(AuthBackend::Jwt);
+        assert_eq!(rejected(&config), "auth.jwt_key_file");
+
+        config.auth.backend = Some(AuthBackend::Mtls);
+        assert_eq!(rejected(&config), "auth.backend");
+        config.tenants.push(TenantSettings {
+            client_ca_file: Some("ca.crt".into()),
+            ..tenant("acme")
+        });
+        config.validate().unwrap();
+    }
+
+    #[test]
+    fn rejects_cert_mapping_settings() {
+        let mut config = Config::default();
+        config.cert_mapping.username_field = Some("email".into());
+        assert_eq!(rejected(&config), "cert_mapping");
+
+        let mut config = Config::default();
+        config.cert_mapping.require_client_id = true;
+        assert_eq!(rejected(&config), "cert_mapping.require_client_id");
+        config.cert_mapping.client_id_field = Some("cn".into());
+        config.validate().unwrap();
+    }
+
+    #[test]
+    fn rejects_backend_settings() {
+        let mut config = Config::default();
+        config.upstream.backends = vec!["broker:1883".into()];
+        assert_eq!(rejected(&config), "upstream.backends");
+
+        let mut config = Config::default();
+        config.upstream.backends.clear();
+        assert_eq!(rejected(&config), "upstream.backends");
+
+        let mut config = Config::default();
+        config.inflight.max = 0;
+        assert_eq!(rejected(&config), "inflight.max");
+    }
+
+    #[test]
+    fn rejects_dual_settings() {
+        let mut config = Config::default();
+        config.dual.dedup.ttl_secs = 0;
+        assert_eq!(rejected(&config), "dual.dedup.ttl_secs");
+
+        let cohort = |certificate_field: Option<&str>, certificate_value: Option<&str>| Cohort {
+            client_id: None,
+            tenant: None,
+            certificate_field: certificate_field.map(str::to_owned),
+            certificate_value: certificate_value.map(str::to_owned),
+        };
+        let mut config = Config::default();
+        config.dual.cohorts = vec![cohort(None, None)];
+        assert_eq!(rejected(&config), "dual.cohorts");
+        config.dual.cohorts = vec![cohort(Some("ou"), None)];
+        assert_eq!(rejected(&config), "dual.cohorts");
+        config.dual.cohorts = vec![cohort(Some("org"), Some("fleet"))];
+        assert_eq!(rejected(&config), "dual.cohorts.certificate_field");
+        config.dual.cohorts = vec![cohort(Some("ou"), Some("fleet-*"))];
+        config.validate().unwrap();
+    }
+
+    #[test]
+    fn rejects_tenant_settings() {
+        let mut config = Config {
+            tenants: vec![tenant("acme"), tenant("acme")],
+            ..Config::default()
+        };
+        assert_eq!(rejected(&config), "tenants.name");
+
+        config.tenants = vec![TenantSettings {
+            server_names: Vec::new(),
+            ..tenant("acme")
+        }];
+        assert_eq!(rejected(&config), "tenants.server_names");
+
+        config.tenants = vec![TenantSettings {
+            backends: vec!["localhost".into()],
+            ..tenant("acme")
+        }];
+        assert_eq!(rejected(&config), "tenants.backends");
+
+        config.tenants = vec![TenantSettings {
+            cert_file: Some("acme.crt".into()),
+            ..tenant("acme")
+        }];
+        assert_eq!(rejected(&config), "tenants.key_file");
+    }
+}
@@ -0,0 +1,166 @@
+use super::cert::ClientCertificate;
+use super::lifecycle::ConnectionPair;
+use log::{info, warn};
+use ntex::time::{Seconds, sleep};
+use rustls::pki_types::CertificateRevocationListDer;
+use std::cell::Cell;
+use std::collections::{HashMap, HashSet};
+use std::io;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, LazyLock, RwLock};
+use x509_parser::prelude::FromDer;
+use x509_parser::revocation_list::CertificateRevocationList;
+
+/// Certificates revoked by a set of CRLs, by issuer and serial number.
+#[derive(Debug, Default)]
+pub struct RevokedCertificates {
+    /// Revoked serial numbers by issuer.
+    entries: HashMap<String, HashSet<String>>,
+}
+
+impl RevokedCertificates {
+    pub fn parse(crls: &[CertificateRevocationListDer<'_>]) -> io::Result<Self> {
+        let mut entries = HashMap::<_, HashSet<_>>::new();
+        for der in crls {
+            let (_, crl) = CertificateRevocationList::from_der(der)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+            entries.entry(crl.issuer().to_string()).or_default().extend(
+                crl.iter_revoked_certificates()
+                    .map(|revoked| revoked.raw_serial_as_string()),
+            );
+        }
+        Ok(Self { entries })
+    }
+
+    pub fn contains(&self, cert: &ClientCertificate) -> bool {
+        self.entries
+            .get(&cert.issuer)
+            .is_some_and(|serials| serials.contains(&cert.serial))
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.values().map(HashSet::len).sum()
+    }
+}
+
+/// Revocation lists by the tenant whose TLS configuration loaded them, `None` for the listener's
+/// own. A session is only checked against the list of the tenant it connected to.
+static REVOKED: LazyLock<RwLock<HashMap<Option<String>, Arc<RevokedCertificates>>>> =
+    LazyLock::new(RwLock::default);
+static GENERATION: AtomicU64 = AtomicU64::new(0);
+
+thread_local! {
+    static WATCHING: Cell<bool> = const { Cell::new(false) };
+}
+
+/// Publishes a new revocation list of a tenant, every worker then closes the sessions of the
+/// tenant's clients whose certificate is on it.
+pub(crate) fn kick_revoked(tenant: Option<&str>, revoked: RevokedCertificates) {
+    info!(
+        "Closing sessions of revoked client certificates: tenant={:?}, revoked={}",
+        tenant,
+        revoked.len()
+    );
+    REVOKED
+        .write()
+        .unwrap()
+        .insert(tenant.map(str::to_owned), Arc::new(revoked));
+    GENERATION.fetch_add(1, Ordering::Release);
+}
+
+/// Starts checking the sessions of the current worker against published revocation lists,
+/// once per worker thread.
+pub(crate) fn watch_sessions() {
+    if WATCHING.replace(true) {
+        return;
+    }
+
+    let mut seen = GENERATION.load(Ordering::Acquire);
+    ntex::rt::spawn(async move {
+        loop {
+            sleep(Seconds(1)).await;
+            let generation = GENERATION.load(Ordering::Acquire);
+            if generation == seen {
+                continue;
+            }
+            seen = generation;
+
+            let closed = close_revoked(&REVOKED.read().unwrap().clone());
+            if closed > 0 {
+                warn!(
+                    "Closed sessions using revoked client certificates: closed={}",
+                    closed
+                );
+            }
+        }
+    });
+}
+
+/// Closes the sessions of the current worker whose certificate is on the revocation list of
+/// their tenant.
+fn close_revoked(revoked: &HashMap<Option<String>, Arc<RevokedCertificates>>) -> usize {
+    ConnectionPair::close_where(|tenant, cert| {
+        revoked
+            .get(&tenant.map(str::to_owned))
+            .is_some_and(|revoked| revoked.contains(cert))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::lifecycle::tests::Closed;
+    use super::*;
+    use std::rc::Rc;
+
+    fn certificate(issuer: &str, serial: &str) -> ClientCertificate {
+        ClientCertificate {
+            issuer: issuer.into(),
+            serial: serial.into(),
+            ..Default::default()
+        }
+    }
+
+    fn revoked(issuer: &str, serials: &[&str]) -> Arc<RevokedCertificates> {
+        let serials = serials.iter().map(|serial| serial.to_string()).collect();
+        Arc::new(RevokedCertificates {
+            entries: HashMap::from([(issuer.to_owned(), serials)]),
+        })
+    }
+
+    /// Registers a pair on this worker for a client with a certificate.
+    fn connect(tenant: Option<&str>, client_id: &str, cert: ClientCertificate) -> Closed {
+        let closed = Closed::default();
+        ConnectionPair::default().register(tenant, client_id, Some(Rc::new(cert)), closed.clone());
+        closed
+    }
+
+    #[test]
+    fn revoked_certificate_matches_issuer_and_serial() {
+        let revoked = revoked("CN=CA", &["01:02"]);
+        assert_eq!(revoked.len(), 1);
+        assert!(revoked.contains(&certificate("CN=CA", "01:02")));
+        assert!(!revoked.contains(&certificate("CN=CA", "01:03")));
+        assert!(!revoked.contains(&certificate("CN=Other CA", "01:02")));
+    }
+
+    #[ntex::test]
+    async fn only_sessions_with_a_revoked_certificate_are_closed() {
+        let revoked_pair = connect(Some("crl-a"), "c1", certificate("CN=CA", "01:02"));
+        let valid_pair = connect(Some("crl-a"), "c2", certificate("CN=CA", "01:03"));
+        // Same certificate, but the tenant's list does not revoke it.
+        let other_tenant = connect(Some("crl-b"), "c1", certificate("CN=CA", "01:02"));
+        let no_list = connect(None, "c1", certificate("CN=CA", "01:02"));
+
+        let lists = HashMap::from([
+            (Some("crl-a".to_owned()), revoked("CN=CA", &["01:02"])),
+            (Some("crl-b".to_owned()), revoked("CN=CA", &["01:04"])),
+        ]);
+        assert_eq!(close_revoked(&lists), 1);
+        assert!(revoked_pair.downstream.get() && revoked_pair.upstream.get());
+        for pair in [&valid_pair, &other_tenant, &no_list] {
+            assert!(!pair.downstream.get() && !pair.upstream.get());
+        }
+        // Already closed, nothing is left to close.
+        assert_eq!(close_revoked(&lists), 0);
+    }
+}
@@ -0,0 +1,469 @@
+use super::compare;
+use super::config::Dedup;
+use ntex::channel::condition::Condition;
+use ntex::channel::oneshot;
+use ntex::time::{Millis, sleep, timeout};
+use ntex::util::ByteString;
+use serde::Deserialize;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::hash::{DefaultHasher, Hash, Hasher};
+use std::num::NonZeroU16;
+use std::rc::Rc;
+use std::time::{Duration, Instant};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Delivery {
+    Forwarding,
+    Delivered,
+}
+
+/// Packet ids of the QoS 1 publishes received on one backend connection, used to ack a DUP
+/// redelivery without forwarding it twice. Clones share the same state.
+#[derive(Debug, Clone, Default)]
+pub struct PublishDedup {
+    packets: Rc<RefCell<HashMap<NonZeroU16, Delivery>>>,
+    completed: Condition,
+}
+
+impl PublishDedup {
+    /// Registers a publish before it is forwarded. Returns `false` when it is a redelivery of a
+    /// publish that has already been delivered. A redelivery of a publish that is still being
+    /// forwarded waits for the original, and is forwarded itself if the original failed.
+    pub(crate) async fn receive(&self, packet_id: Option<NonZeroU16>, dup: bool) -> bool {
+        let Some(packet_id) = packet_id else {
+            return true;
+        };
+
+        loop {
+            let waiter = {
+                let mut packets = self.packets.borrow_mut();
+                match packets.get(&packet_id) {
+                    Some(Delivery::Delivered) if dup => return false,
+                    Some(Delivery::Forwarding) if dup => self.completed.wait(),
+                    // A publish without DUP reuses a released packet id for a new message.
+                    _ => {
+                        packets.insert(packet_id, Delivery::Forwarding);
+                        return true;
+                    }
+                }
+            };
+            waiter.await;
+        }
+    }
+
+    /// Records the outcome of forwarding a publish registered with `receive`.
+    pub(crate) fn complete(&self, packet_id: Option<NonZeroU16>, delivered: bool) {
+        let Some(packet_id) = packet_id else {
+            return;
+        };
+
+        let mut packets = self.packets.borrow_mut();
+        if delivered {
+            packets.insert(packet_id, Delivery::Delivered);
+        } else {
+            // Let the backend's redelivery through.
+            packets.remove(&packet_id);
+        }
+        self.completed.notify();
+    }
+}
+
+/// Backend of a dual session a publish arrived from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DualSide {
+    Primary,
+    Secondary,
+}
+
+/// Which copy of a publish delivered by both backends of a dual session reaches the client.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum DedupPreference {
+    /// Whichever copy arrives first.
+    #[default]
+    First,
+    /// The primary's copy, the secondary's waits for it up to the hold time.
+    Primary,
+    /// The secondary's copy, the primary's waits for it up to the hold time.
+    Secondary,
+}
+
+impl DedupPreference {
+    fn side(self) -> Option<DualSide> {
+        match self {
+            DedupPreference::First => None,
+            DedupPreference::Primary => Some(DualSide::Primary),
+            DedupPreference::Secondary => Some(DualSide::Secondary),
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Forwarded {
+    side: DualSide,
+    topic: ByteString,
+    /// Copies forwarded from `side` that the other backend has not delivered yet.
+    copies: u32,
+    expires: Instant,
+}
+
+#[derive(Debug, Default)]
+struct Window {
+    forwarded: HashMap<u64, Forwarded>,
+    /// Copies from the backend that is not preferred, waiting for the preferred one.
+    held: HashMap<u64, oneshot::Sender<()>>,
+}
+
+/// Recently forwarded publishes of a dual session, so a message that exists on both clusters
+/// reaches the client once. Clones share the same window.
+#[derive(Debug, Clone)]
+pub struct DualDedup {
+    client_id: String,
+    window: Rc<RefCell<Window>>,
+    size: usize,
+    ttl: Duration,
+    prefer: DedupPreference,
+    hold: Millis,
+}
+
+impl DualDedup {
+    pub fn new(client_id: String, config: &Dedup) -> Self {
+        Self {
+            client_id,
+            window: Rc::default(),
+            size: config.size,
+            ttl: Duration::from_secs(config.ttl_secs),
+            prefer: config.prefer,
+            hold: Millis(config.hold_millis),
+        }
+    }
+
+    /// Identifies a message by its topic, its payload and, for MQTT v5, its correlation data.
+    ///
+    /// Neither protocol gives a message an id that survives the hop through a broker, so two
+    /// different messages with the same content are the same message here. When one backend
+    /// delivers one of them and the other backend the other within the TTL, the client only
+    /// gets one. Each copy is counted, so a message repeated on both backends still reaches
+    /// the client as often as either backend delivered it. Publishers that need repeated
+    /// messages told apart have to make them differ, e.g. with a sequence number in the
+    /// payload or the correlation data.
+    pub fn key(topic: &str, payload: &[u8], correlation: Option<&[u8]>) -> u64 {
+        let mut hasher = DefaultHasher::new();
+        topic.hash(&mut hasher);
+        payload.hash(&mut hasher);
+        correlation.hash(&mut hasher);
+        hasher.finish()
+    }
+
+    /// Returns whether a publish from `side` is to be forwarded to the client, `false` when the
+    /// other backend's copy was or will be forwarded instead.
+    pub async fn admit(&self, side: DualSide, key: u64, topic: &ByteString) -> bool {
+        let held = {
+            let mut window = self.window.borrow_mut();
+            for forwarded in window.expire() {
+                self.unmatched(forwarded);
+            }
+
+            if let Some(forwarded) = window.forwarded.get_mut(&key)
+                && forwarded.side != side
+            {
+                compare::message_matched();
+                forwarded.copies -= 1;
+                if forwarded.copies == 0 {
+                    window.forwarded.remove(&key);
+                }
+                return false;
+            }
+            match self.prefer.side() {
+                Some(preferred) if preferred == side => {
+                    if let Some(held) = window.held.remove(&key) {
+                        // Both copies are accounted for, the held one is dropped.
+                        let _ = held.send(());
+                        compare::message_matched();
+                        return true;
+                    }
+                    None
+                }
+                Some(_) if !window.held.contains_key(&key) => {
+                    let (tx, rx) = oneshot::channel();
+                    window.held.insert(key, tx);
+                    Some(rx)
+                }
+                _ => None,
+            }
+        };
+
+        if let Some(rx) = held {
+            if let Ok(Ok(())) = timeout(self.hold, rx).await {
+                return false;
+            }
+            // The preferred copy did not come in time, a late one is dropped as a duplicate.
+            self.window.borrow_mut().held.remove(&key);
+        }
+        self.forward(side, key, topic);
+        true
+    }
+
+    fn forward(&self, side: DualSide, key: u64, topic: &ByteString) {
+        let mut window = self.window.borrow_mut();
+        let expires = Instant::now() + self.ttl;
+        if let Some(forwarded) = window.forwarded.get_mut(&key) {
+            forwarded.copies += 1;
+            forwarded.expires = expires;
+            return;
+        }
+        if window.forwarded.len() >= self.size {
+            // Full of messages only one backend delivered, the oldest is forgotten.
+            let oldest = window
+                .forwarded
+                .iter()
+                .min_by_key(|(_, forwarded)| forwarded.expires)
+                .map(|(key, _)| *key);
+            if let Some(forwarded) = oldest.and_then(|oldest| window.forwarded.remove(&oldest)) {
+                self.unmatched(forwarded);
+            }
+        }
+        window.forwarded.insert(
+            key,
+            Forwarded {
+                side,
+                topic: topic.clone(),
+                copies: 1,
+                expires,
+            },
+        );
+    }
+
+    /// Reports the messages the other backend never delivered every TTL, also while no
+    /// publishes come in. Stops once the session is gone.
+    pub fn expire_every_ttl(&self) {
+        let window = Rc::downgrade(&self.window);
+        let client_id = self.client_id.clone();
+        let ttl = self.ttl;
+        ntex::rt::spawn(async move {
+            loop {
+                sleep(ttl).await;
+                let Some(window) = window.upgrade() else {
+                    break;
+                };
+                let expired = window.borrow_mut().expire();
+                for forwarded in expired {
+                    report_unmatched(&client_id, forwarded);
+                }
+            }
+        });
+    }
+
+    /// Reports every message the other backend has not delivered yet, when the session ends.
+    pub fn flush(&self) {
+        let forwarded: Vec<_> = self.window.borrow_mut().forwarded.drain().collect();
+        for (_, forwarded) in forwarded {
+            self.unmatched(forwarded);
+        }
+    }
+
+    fn unmatched(&self, forwarded: Forwarded) {
+        report_unmatched(&self.client_id, forwarded);
+    }
+}
+
+impl Window {
+    /// Removes the messages whose TTL is over.
+    fn expire(&mut self) -> Vec<Forwarded> {
+        let now = Instant::now();
+        self.forwarded
+            .extract_if(|_, forwarded| forwarded.expires <= now)
+            .map(|(_, forwarded)| forwarded)
+            .collect()
+    }
+}
+
+fn report_unmatched(client_id: &str, forwarded: Forwarded) {
+    compare::message_unmatched(
+        client_id,
+        forwarded.side,
+        &forwarded.topic,
+        forwarded.copies,
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::join;
+    use std::cell::Cell;
+
+    fn dedup(size: usize, prefer: DedupPreference) -> DualDedup {
+        DualDedup::new(
+            "client".into(),
+            &Dedup {
+                size,
+                ttl_secs: 30,
+                prefer,
+                hold_millis: 50,
+            },
+        )
+    }
+
+    #[ntex::test]
+    async fn redelivery_of_a_delivered_publish_is_not_forwarded() {
+        let dedup = PublishDedup::default();
+        let id = NonZeroU16::new(1);
+        assert!(dedup.receive(id, false).await);
+        dedup.complete(id, true);
+        assert!(!dedup.receive(id, true).await);
+        // Without DUP the packet id was released and reused for a new message.
+        assert!(dedup.receive(id, false).await);
+    }
+
+    #[ntex::test]
+    async fn concurrent_redelivery_waits_for_the_original() {
+        let dedup = PublishDedup::default();
+        let id = NonZeroU16::new(1);
+        assert!(dedup.receive(id, false).await);
+
+        let completed = Cell::new(false);
+        let (forwarded, ()) = join!(
+            async {
+                let forwarded = dedup.receive(id, true).await;
+                assert!(completed.get(), "returned before the original completed");
+                forwarded
+            },
+            async {
+                sleep(Millis(10)).await;
+                completed.set(true);
+                dedup.complete(id, true);
+            }
+        );
+        assert!(!forwarded);
+        assert_eq!(dedup.packets.borrow().len(), 1);
+    }
+
+    #[ntex::test]
+    async fn concurrent_redelivery_is_forwarded_when_the_original_failed() {
+        let dedup = PublishDedup::default();
+        let id = NonZeroU16::new(1);
+        assert!(dedup.receive(id, false).await);
+
+        let (forwarded, ()) = join!(dedup.receive(id, true), async {
+            sleep(Millis(10)).await;
+            dedup.complete(id, false);
+        });
+        assert!(forwarded);
+        assert_eq!(dedup.packets.borrow()[&id.unwrap()], Delivery::Forwarding);
+    }
+
+    #[test]
+    fn key_covers_topic_payload_and_correlation() {
+        let key = DualDedup::key("a/b", b"x", None);
+        assert_eq!(key, DualDedup::key("a/b", b"x", None));
+        assert_ne!(key, DualDedup::key("a/c", b"x", None));
+        assert_ne!(key, DualDedup::key("a/b", b"y", None));
+        assert_ne!(key, DualDedup::key("a/b", b"x", Some(b"")));
+        assert_ne!(
+            DualDedup::key("a/b", b"x", Some(b"1")),
+            DualDedup::key("a/b", b"x", Some(b"2"))
+        );
+    }
+
+    #[ntex::test]
+    async fn other_backends_copy_is_dropped() {
+        let dedup = dedup(16, DedupPreference::First);
+        let topic = ByteString::from("a/b");
+        let key = DualDedup::key(&topic, b"x", None);
+        assert!(dedup.admit(DualSide::Primary, key, &topic).await);
+        assert!(!dedup.admit(DualSide::Secondary, key, &topic).await);
+        // Matched, so a third copy is a new message.
+        assert!(dedup.admit(DualSide::Secondary, key, &topic).await);
+    }
+
+    #[ntex::test]
+    async fn repeated_message_is_counted_per_copy() {
+        let dedup = dedup(16, DedupPreference::First);
+        let topic = ByteString::from("a/b");
+        let key = DualDedup::key(&topic, b"x", None);
+        assert!(dedup.admit(DualSide::Primary, key, &topic).await);
+        assert!(dedup.admit(DualSide::Primary, key, &topic).await);
+        assert!(!dedup.admit(DualSide::Secondary, key, &topic).await);
+        assert!(!dedup.admit(DualSide::Secondary, key, &topic).await);
+        assert!(dedup.window.borrow().forwarded.is_empty());
+    }
+
+    #[ntex::test]
+    async fn expired_message_is_forwarded_again() {
+        let dedup = dedup(16, DedupPreference::First);
+        let topic = ByteString::from("a/b");
+        let key = DualDedup::key(&topic, b"x", None);
+        assert!(dedup.admit(DualSide::Primary, key, &topic).await);
+        dedup
+            .window
+            .borrow_mut()
+            .forwarded
+            .get_mut(&key)
+            .unwrap()
+            .expires = Instant::now();
+        assert!(dedup.admit(DualSide::Secondary, key, &topic).await);
+        assert_eq!(
+            dedup.window.borrow().forwarded[&key].side,
+            DualSide::Secondary
+        );
+    }
+
+    #[ntex::test]
+    async fn full_window_forgets_the_oldest_message() {
+        let dedup = dedup(2, DedupPreference::First);
+        let topic = ByteString::from("a/b");
+        let keys: Vec<u64> = (0..3)
+            .map(|i: u8| DualDedup::key(&topic, &[i], None))
+            .collect();
+        for key in &keys[..2] {
+            assert!(dedup.admit(DualSide::Primary, *key, &topic).await);
+        }
+        // Expires before the other one, whatever the clock resolution.
+        dedup
+            .window
+            .borrow_mut()
+            .forwarded
+            .get_mut(&keys[0])
+            .unwrap()
+            .expires -= Duration::from_secs(1);
+        assert!(dedup.admit(DualSide::Primary, keys[2], &topic).await);
+        assert_eq!(dedup.window.borrow().forwarded.len(), 2);
+        assert!(dedup.admit(DualSide::Secondary, keys[0], &topic).await);
+        assert!(!dedup.admit(DualSide::Secondary, keys[2], &topic).await);
+    }
+
+    #[ntex::test]
+    async fn preferred_copy_wins_within_the_hold_time() {
+        let dedup = dedup(16, DedupPreference::Primary);
+        let topic = ByteString::from("a/b");
+        let key = DualDedup::key(&topic, b"x", None);
+        let (secondary, primary) = join!(dedup.admit(DualSide::Secondary, key, &topic), async {
+            sleep(Millis(10)).await;
+            dedup.admit(DualSide::Primary, key, &topic).await
+        });
+        assert!(!secondary);
+        assert!(primary);
+        assert!(dedup.window.borrow().forwarded.is_empty());
+        assert!(dedup.window.borrow().held.is_empty());
+    }
+
+    #[ntex::test]
+    async fn held_copy_is_forwarded_after_the_hold_time() {
+        let dedup = dedup(16, DedupPreference::Primary);
+        let topic = ByteString::from("a/b");
+        let key = DualDedup::key(&topic, b"x", None);
+        assert!(dedup.admit(DualSide::Secondary, key, &topic).await);
+        assert!(!dedup.admit(DualSide::Primary, key, &topic).await);
+    }
+
+    #[ntex::test]
+    async fn flush_empties_the_window() {
+        let dedup = dedup(16, DedupPreference::First);
+        let topic = ByteString::from("a/b");
+        assert!(dedup.admit(DualSide::Primary, 1, &topic).await);
+        dedup.flush();
+        assert!(dedup.window.borrow().forwarded.is_empty());
+    }
+}
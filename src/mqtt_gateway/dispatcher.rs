@@ -1,19 +1,27 @@
-use super::session::AnySink;
-
+use super::auth::{authenticate_v3, authenticate_v5};
+use super::bridge;
 use super::error::ServerError;
+use super::handler::{
+    handle_connect, handle_connect_v5, handle_downstream_control, handle_downstream_control_v5,
+    handle_downstream_pub, handle_downstream_pub_v5,
+};
+use super::lifecycle::take_over;
 use super::session::SessionState;
-use super::handler::{handle_connect, handle_downstream_control, handle_downstream_pub};
 use ntex::service::fn_factory_with_config;
 use ntex::util::Ready;
-use ntex::{fn_service, ServiceFactory};
+use ntex::{ServiceFactory, fn_service};
 use ntex_mqtt::{v3, v5};
-use std::cell::RefCell;
-use log::{info, debug};
 
 pub(crate) async fn connect_v3(
-    handshake: v3::Handshake,
+    mut handshake: v3::Handshake,
 ) -> Result<v3::HandshakeAck<SessionState<v3::MqttSink>>, ServerError> {
-    handle_connect(handshake).await
+    match authenticate_v3(&mut handshake).await {
+        Ok(identity) => {
+            take_over(identity.tenant_name(), &handshake.packet().client_id).await;
+            handle_connect(handshake, identity).await
+        }
+        Err(e) => Ok(bridge::reject_v3(handshake, e.into())),
+    }
 }
 
 pub(crate) fn control_factory_v3() -> impl ServiceFactory<
@@ -24,7 +32,9 @@ pub(crate) fn control_factory_v3() -> impl ServiceFactory<
     InitError = ServerError,
 > {
     fn_factory_with_config(|session: v3::Session<SessionState<v3::MqttSink>>| {
-        Ready::Ok(fn_service(move |control: v3::Control<ServerError>| handle_downstream_control(control, session.state().clone())))
+        Ready::Ok(fn_service(move |control: v3::Control<ServerError>| {
+            handle_downstream_control(control, session.state().clone())
+        }))
     })
 }
 
@@ -36,22 +46,22 @@ pub fn publish_factory_v3() -> impl ServiceFactory<
     InitError = ServerError,
 > {
     fn_factory_with_config(|session: v3::Session<SessionState<v3::MqttSink>>| {
-        Ready::Ok(fn_service(move |publish: v3::Publish| handle_downstream_pub(publish, session.state().clone())))
+        Ready::Ok(fn_service(move |publish: v3::Publish| {
+            handle_downstream_pub(publish, session.state().clone())
+        }))
     })
 }
 
 pub(crate) async fn connect_v5(
-    handshake: v5::Handshake,
+    mut handshake: v5::Handshake,
 ) -> Result<v5::HandshakeAck<SessionState<v5::MqttSink>>, ServerError> {
-    info!("New MQTT v5 TCP connection established: client_id={}", handshake.packet().client_id);
-    debug!("Connection details: {:?}", handshake);
-    let session = SessionState {
-        client_id: handshake.packet().client_id.to_string(),
-        subscriptions: RefCell::new(vec![]),
-        source: handshake.sink(),
-        sink: AnySink::MqttSink(handshake.sink()),
-    };
-    Ok(handshake.ack(session))
+    match authenticate_v5(&mut handshake).await {
+        Ok(identity) => {
+            take_over(identity.tenant_name(), &handshake.packet().client_id).await;
+            handle_connect_v5(handshake, identity).await
+        }
+        Err(e) => Ok(handshake.failed(e.into())),
+    }
 }
 
 pub(crate) fn control_factory_v5() -> impl ServiceFactory<
@@ -62,28 +72,8 @@ pub(crate) fn control_factory_v5() -> impl ServiceFactory<
     InitError = ServerError,
 > {
     fn_factory_with_config(|session: v5::Session<SessionState<v5::MqttSink>>| {
-        Ready::Ok(fn_service(move |control| match control {
-            v5::Control::Auth(a) => Ready::Ok(a.ack(v5::codec::Auth::default())),
-            v5::Control::Error(e) => {
-                Ready::Ok(e.ack(v5::codec::DisconnectReasonCode::UnspecifiedError))
-            }
-            v5::Control::ProtocolError(e) => Ready::Ok(e.ack()),
-            v5::Control::Ping(p) => Ready::Ok(p.ack()),
-            v5::Control::Disconnect(d) => Ready::Ok(d.ack()),
-            v5::Control::Subscribe(mut s) => {
-                // store subscribed topics in session, publish service uses this list for echos
-                s.iter_mut().for_each(|mut s| {
-                    session.subscriptions.borrow_mut().push(s.topic().clone());
-                    s.confirm(s.options().qos);
-                });
-
-                Ready::Ok(s.ack())
-            }
-            v5::Control::Unsubscribe(s) => Ready::Ok(s.ack()),
-            v5::Control::Closed(c) => Ready::Ok(c.ack()),
-            v5::Control::PeerGone(c) => Ready::Ok(c.ack()),
-            // TODO: Back pressure
-            v5::Control::WrBackpressure(w) => Ready::Ok(w.ack())
+        Ready::Ok(fn_service(move |control: v5::Control<ServerError>| {
+            handle_downstream_control_v5(control, session.state().clone())
         }))
     })
 }
@@ -97,13 +87,7 @@ pub(crate) fn publish_factory_v5() -> impl ServiceFactory<
 > {
     fn_factory_with_config(|session: v5::Session<SessionState<v5::MqttSink>>| {
         Ready::Ok(fn_service(move |publish: v5::Publish| {
-            debug!(
-                "Incoming MQTT v5 publish over TCP from client {}: packet_id={:?}, topic={:?}",
-                session.client_id,
-                publish.id(),
-                publish.topic()
-            );
-            Ready::Ok(publish.ack())
+            handle_downstream_pub_v5(publish, session.state().clone())
         }))
     })
 }
@@ -1,23 +1,712 @@
-use ntex_mqtt::v3;
+use super::auth::Identity;
+use super::bridge;
+use super::cert::CertField;
+use super::compare;
+use super::config::Dual;
+use super::dedup::{DualDedup, DualSide};
+use super::error::ServerError;
+use super::inflight::InflightWindow;
+use super::upstream::{backend_set, create_pool};
+use super::{CONFIG, DUAL};
+use clap::ValueEnum;
+use futures::future::join;
+use log::{error, info, warn};
+use ntex::time::{Seconds, sleep};
+use ntex::util::{ByteString, Bytes};
+use ntex_mqtt::{QoS, v3, v5};
+use pingora_load_balancing::LoadBalancer;
+use pingora_load_balancing::selection::Consistent;
+use serde::Deserialize;
+use std::cell::Cell;
+use std::fmt;
+use std::fs;
+use std::path::PathBuf;
+use std::rc::Rc;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU8, Ordering};
+use std::time::{Duration, Instant, SystemTime};
 
-#[derive(Clone,Debug)]
+#[derive(Clone, Debug)]
 pub struct DualSink<S> {
     pub client_id: String,
     pub primary_sink: S,
     pub secondary_sink: S,
+    /// Publishes delivered by one backend, to drop the other's copy.
+    pub dedup: DualDedup,
+    /// Shadow publishes forwarded to the secondary and waiting for its ack.
+    pub shadow_inflight: InflightWindow,
+    /// Shadow publishes still being sent, at most `inflight.max`.
+    shadowing: Rc<Cell<usize>>,
 }
 
-
-impl DualSink<v3::MqttSink> {
-    pub fn new(
-        client_id: String,
-        primary_sink: v3::MqttSink,
-        secondary_sink: v3::MqttSink,
-    ) -> Self {
+impl<S> DualSink<S> {
+    pub fn new(client_id: String, primary_sink: S, secondary_sink: S) -> Self {
+        let dedup = DualDedup::new(client_id.clone(), &CONFIG.dual.dedup);
+        dedup.expire_every_ttl();
         Self {
             client_id,
             primary_sink,
             secondary_sink,
+            dedup,
+            shadow_inflight: InflightWindow::default(),
+            shadowing: Rc::default(),
+        }
+    }
+
+    /// Counts a QoS 1 shadow publish, unless as many as the shadow window holds are still
+    /// being sent and it is dropped.
+    fn start_shadow(&self, topic: &ByteString) -> Option<Shadowed> {
+        if self.shadowing.get() >= CONFIG.inflight.max {
+            warn!(
+                "Shadow publish to secondary dropped, too many in flight: client_id={}, topic={}",
+                self.client_id, topic
+            );
+            compare::record_publish(DualSide::Secondary, false, Duration::ZERO);
+            return None;
+        }
+        Some(Shadowed::new(&self.shadowing))
+    }
+}
+
+impl DualSink<v3::MqttSink> {
+    /// Forwards a client publish to the backends of the current mode, and returns once the
+    /// client may be acked.
+    pub async fn publish(
+        &self,
+        topic: ByteString,
+        payload: Bytes,
+        qos: QoS,
+        inflight: &InflightWindow,
+    ) -> Result<(), ServerError> {
+        let send = |sink, side| send_v3(sink, side, &topic, &payload, qos, inflight);
+        let primary = || send(&self.primary_sink, DualSide::Primary);
+        let secondary = || send(&self.secondary_sink, DualSide::Secondary);
+
+        match DUAL.mode() {
+            DualMode::PrimaryOnly => primary().await,
+            DualMode::Mirror => {
+                let (primary, secondary) = join(primary(), secondary()).await;
+                if qos != QoS::AtMostOnce {
+                    compare::compare_publish(
+                        &self.client_id,
+                        &topic,
+                        primary.is_ok(),
+                        secondary.is_ok(),
+                    );
+                }
+                // The secondary only gets compared, the client has the primary's result.
+                primary
+            }
+            DualMode::Shadow => {
+                self.shadow_v3(topic.clone(), payload.clone(), qos);
+                primary().await
+            }
+            DualMode::Cutover => secondary().await,
+        }
+    }
+
+    /// Sends a publish to the secondary without holding up the client. QoS 1 publishes go
+    /// through a window of their own, and are dropped while as many as the window holds are
+    /// still being sent.
+    fn shadow_v3(&self, topic: ByteString, payload: Bytes, qos: QoS) {
+        if qos == QoS::AtMostOnce {
+            let sent = self
+                .secondary_sink
+                .publish(topic.clone(), payload)
+                .send_at_most_once();
+            if let Err(e) = sent {
+                warn!(
+                    "Shadow publish to secondary failed: client_id={}, topic={}, error={:?}",
+                    self.client_id, topic, e
+                );
+            }
+            return;
+        }
+
+        let Some(shadowed) = self.start_shadow(&topic) else {
+            return;
+        };
+        let sink = self.clone();
+        ntex::rt::spawn(async move {
+            let _shadowed = shadowed;
+            let result = send_v3(
+                &sink.secondary_sink,
+                DualSide::Secondary,
+                &topic,
+                &payload,
+                qos,
+                &sink.shadow_inflight,
+            )
+            .await;
+            if let Err(e) = result {
+                warn!(
+                    "Shadow publish to secondary failed: client_id={}, topic={}, error={:?}",
+                    sink.client_id, topic, e
+                );
+            }
+        });
+    }
+}
+
+impl DualSink<v5::MqttSink> {
+    /// Forwards a client publish to the backends of the current mode, and returns the reason
+    /// code the client is acked with once it may be acked.
+    pub async fn publish(
+        &self,
+        packet: v5::codec::Publish,
+        inflight: &InflightWindow,
+    ) -> Result<v5::codec::PublishAckReason, ServerError> {
+        let send = |sink, side| send_v5(sink, side, &packet, inflight);
+        let primary = || send(&self.primary_sink, DualSide::Primary);
+        let secondary = || send(&self.secondary_sink, DualSide::Secondary);
+
+        match DUAL.mode() {
+            DualMode::PrimaryOnly => primary().await,
+            DualMode::Mirror => {
+                let (primary, secondary) = join(primary(), secondary()).await;
+                if packet.qos != QoS::AtMostOnce {
+                    compare::compare_publish(
+                        &self.client_id,
+                        &packet.topic,
+                        acked_v5(&primary),
+                        acked_v5(&secondary),
+                    );
+                }
+                // The secondary only gets compared, the client has the primary's result.
+                primary
+            }
+            DualMode::Shadow => {
+                self.shadow_v5(packet.clone());
+                primary().await
+            }
+            DualMode::Cutover => secondary().await,
+        }
+    }
+
+    /// Sends a publish to the secondary without holding up the client, as `shadow_v3` does.
+    fn shadow_v5(&self, packet: v5::codec::Publish) {
+        if packet.qos == QoS::AtMostOnce {
+            let topic = packet.topic.clone();
+            let sent = self.secondary_sink.publish_pkt(packet).send_at_most_once();
+            if let Err(e) = sent {
+                warn!(
+                    "Shadow publish to secondary failed: client_id={}, topic={}, error={:?}",
+                    self.client_id, topic, e
+                );
+            }
+            return;
+        }
+
+        let Some(shadowed) = self.start_shadow(&packet.topic) else {
+            return;
+        };
+        let sink = self.clone();
+        ntex::rt::spawn(async move {
+            let _shadowed = shadowed;
+            let result = send_v5(
+                &sink.secondary_sink,
+                DualSide::Secondary,
+                &packet,
+                &sink.shadow_inflight,
+            )
+            .await;
+            if !acked_v5(&result) {
+                warn!(
+                    "Shadow publish to secondary failed: client_id={}, topic={}, result={:?}",
+                    sink.client_id, packet.topic, result
+                );
+            }
+        });
+    }
+}
+
+/// Counts a shadow publish for as long as it is being sent.
+struct Shadowed(Rc<Cell<usize>>);
+
+impl Shadowed {
+    fn new(shadowing: &Rc<Cell<usize>>) -> Self {
+        shadowing.set(shadowing.get() + 1);
+        Self(shadowing.clone())
+    }
+}
+
+impl Drop for Shadowed {
+    fn drop(&mut self) {
+        self.0.set(self.0.get() - 1);
+    }
+}
+
+async fn send_v3(
+    sink: &v3::MqttSink,
+    side: DualSide,
+    topic: &ByteString,
+    payload: &Bytes,
+    qos: QoS,
+    inflight: &InflightWindow,
+) -> Result<(), ServerError> {
+    if qos == QoS::AtMostOnce {
+        return sink
+            .publish(topic.clone(), payload.clone())
+            .send_at_most_once()
+            .map_err(|_| ServerError::Internal);
+    }
+    let started = Instant::now();
+    let result = inflight
+        .deliver(topic, payload, |packet_id, dup| {
+            sink.publish(topic.clone(), payload.clone())
+                .packet_id(packet_id)
+                .dup(dup)
+                .send_at_least_once()
+        })
+        .await
+        .map(|_| ());
+    compare::record_publish(side, result.is_ok(), started.elapsed());
+    result
+}
+
+async fn send_v5(
+    sink: &v5::MqttSink,
+    side: DualSide,
+    packet: &v5::codec::Publish,
+    inflight: &InflightWindow,
+) -> Result<v5::codec::PublishAckReason, ServerError> {
+    if packet.qos == QoS::AtMostOnce {
+        return sink
+            .publish_pkt(packet.clone())
+            .send_at_most_once()
+            .map(|_| v5::codec::PublishAckReason::Success)
+            .map_err(|_| ServerError::Internal);
+    }
+    let started = Instant::now();
+    let result = inflight
+        .deliver(&packet.topic, &packet.payload, |packet_id, dup| {
+            sink.publish_pkt(packet.clone())
+                .packet_id(packet_id)
+                .dup(dup)
+                .send_at_least_once()
+        })
+        .await
+        .map(|ack| ack.reason_code);
+    compare::record_publish(side, acked_v5(&result), started.elapsed());
+    result
+}
+
+/// A MQTT v5 backend acked a publish when it answered with a success reason code.
+fn acked_v5(result: &Result<v5::codec::PublishAckReason, ServerError>) -> bool {
+    result
+        .as_ref()
+        .is_ok_and(|reason| bridge::is_success(*reason))
+}
+
+/// How dual sessions write client publishes, switched over the course of a broker migration.
+/// Subscriptions always go to both backends.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize, ValueEnum)]
+#[serde(rename_all = "kebab-case")]
+pub enum DualMode {
+    /// Publishes only go to the primary.
+    PrimaryOnly,
+    /// Publishes go to both backends and the client gets the primary's ack once both
+    /// answered, the secondary's outcome is only compared.
+    #[default]
+    Mirror,
+    /// Publishes go to both backends, the client is acked after the primary without waiting
+    /// for the secondary, whose failures are only logged.
+    Shadow,
+    /// Publishes only go to the secondary, which is authoritative.
+    Cutover,
+}
+
+impl DualMode {
+    fn from_u8(mode: u8) -> Self {
+        match mode {
+            0 => DualMode::PrimaryOnly,
+            1 => DualMode::Mirror,
+            2 => DualMode::Shadow,
+            _ => DualMode::Cutover,
+        }
+    }
+}
+
+/// How the results of a subscribe or unsubscribe sent to both backends of a dual session are
+/// combined into the one the client gets.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize, ValueEnum)]
+#[serde(rename_all = "kebab-case")]
+pub enum SubscribePolicy {
+    /// A subscription only succeeds if both backends accepted it, at the lower granted QoS.
+    #[default]
+    Both,
+    /// The primary's result is the client's, secondary failures are only logged.
+    PrimaryWins,
+}
+
+impl SubscribePolicy {
+    /// Combines the results of a request sent to both backends, `both` merges them when both
+    /// backends answered.
+    pub fn merge<T, E: fmt::Debug>(
+        self,
+        client_id: &str,
+        primary: Result<T, E>,
+        secondary: Result<T, E>,
+        both: impl FnOnce(T, T) -> T,
+    ) -> Result<T, ServerError> {
+        match (self, primary, secondary) {
+            (SubscribePolicy::Both, Ok(primary), Ok(secondary)) => Ok(both(primary, secondary)),
+            (SubscribePolicy::PrimaryWins, Ok(primary), Err(e)) => {
+                warn!(
+                    "Secondary failed a subscription request: client_id={}, error={:?}",
+                    client_id, e
+                );
+                Ok(primary)
+            }
+            (SubscribePolicy::PrimaryWins, Ok(primary), Ok(_)) => Ok(primary),
+            (_, Err(e), _) | (_, _, Err(e)) => {
+                error!(
+                    "Dual subscription request failed: client_id={}, error={:?}",
+                    client_id, e
+                );
+                Err(ServerError::Internal)
+            }
+        }
+    }
+
+    pub fn subscribe_code_v3(
+        self,
+        primary: v3::codec::SubscribeReturnCode,
+        secondary: v3::codec::SubscribeReturnCode,
+    ) -> v3::codec::SubscribeReturnCode {
+        use v3::codec::SubscribeReturnCode::{Failure, Success};
+        match (self, primary, secondary) {
+            (SubscribePolicy::PrimaryWins, primary, _) => primary,
+            (SubscribePolicy::Both, Success(primary), Success(secondary)) => {
+                Success(primary.min(secondary))
+            }
+            (SubscribePolicy::Both, _, _) => Failure,
+        }
+    }
+
+    pub fn subscribe_reason_v5(
+        self,
+        primary: v5::codec::SubscribeAckReason,
+        secondary: v5::codec::SubscribeAckReason,
+    ) -> v5::codec::SubscribeAckReason {
+        match self {
+            SubscribePolicy::PrimaryWins => primary,
+            // The failure, or else the lower granted QoS.
+            SubscribePolicy::Both => match (granted_qos_v5(primary), granted_qos_v5(secondary)) {
+                (Some(_), None) => secondary,
+                (Some(granted), Some(other)) if other < granted => secondary,
+                _ => primary,
+            },
+        }
+    }
+
+    pub fn unsubscribe_reason_v5(
+        self,
+        primary: v5::codec::UnsubscribeAckReason,
+        secondary: v5::codec::UnsubscribeAckReason,
+    ) -> v5::codec::UnsubscribeAckReason {
+        match (self, primary) {
+            (SubscribePolicy::Both, v5::codec::UnsubscribeAckReason::Success) => secondary,
+            _ => primary,
+        }
+    }
+}
+
+fn granted_qos_v5(reason: v5::codec::SubscribeAckReason) -> Option<QoS> {
+    match reason {
+        v5::codec::SubscribeAckReason::GrantedQos0 => Some(QoS::AtMostOnce),
+        v5::codec::SubscribeAckReason::GrantedQos1 => Some(QoS::AtLeastOnce),
+        v5::codec::SubscribeAckReason::GrantedQos2 => Some(QoS::ExactlyOnce),
+        _ => None,
+    }
+}
+
+struct Cohort {
+    client_id: Option<String>,
+    tenant: Option<String>,
+    certificate: Option<(CertField, String)>,
+}
+
+/// Decides which clients run in dual mode, so device cohorts can migrate one at a time.
+pub struct DualPolicy {
+    all: bool,
+    cohorts: Vec<Cohort>,
+    mode: AtomicU8,
+    mode_file: Option<PathBuf>,
+    subscribe_policy: SubscribePolicy,
+}
+
+impl DualPolicy {
+    pub fn new(dual: &Dual) -> Self {
+        let cohorts = dual
+            .cohorts
+            .iter()
+            .map(|cohort| Cohort {
+                client_id: cohort.client_id.clone(),
+                tenant: cohort.tenant.clone(),
+                // Validated when the configuration is loaded.
+                certificate: cohort
+                    .certificate_field
+                    .as_deref()
+                    .and_then(CertField::parse)
+                    .zip(cohort.certificate_value.clone()),
+            })
+            .collect();
+        Self {
+            all: dual.enabled,
+            cohorts,
+            mode: AtomicU8::new(dual.mode as u8),
+            mode_file: dual.mode_file.clone(),
+            subscribe_policy: dual.subscribe_policy,
+        }
+    }
+
+    pub fn subscribe_policy(&self) -> SubscribePolicy {
+        self.subscribe_policy
+    }
+
+    pub fn mode(&self) -> DualMode {
+        DualMode::from_u8(self.mode.load(Ordering::Relaxed))
+    }
+
+    /// Switches the mode of every dual session, from their next publish on.
+    pub fn set_mode(&self, mode: DualMode) {
+        let previous = DualMode::from_u8(self.mode.swap(mode as u8, Ordering::Relaxed));
+        if previous != mode {
+            info!("Dual mode switched: {:?} -> {:?}", previous, mode);
         }
     }
-}
\ No newline at end of file
+
+    /// Polls the mode file until the process exits. A file naming no mode is logged and the
+    /// current mode stays in use.
+    pub(crate) fn watch_mode_file(&'static self) {
+        let Some(path) = &self.mode_file else {
+            return;
+        };
+        let modified = move || fs::metadata(path).and_then(|meta| meta.modified()).ok();
+        let apply = move || match fs::read_to_string(path) {
+            Ok(name) => match DualMode::from_str(name.trim(), true) {
+                Ok(mode) => self.set_mode(mode),
+                Err(_) => error!("Unknown dual mode in {}: {}", path.display(), name.trim()),
+            },
+            Err(e) => error!("Failed to read dual mode file {}: {}", path.display(), e),
+        };
+
+        let mut seen: Option<SystemTime> = modified();
+        if seen.is_some() {
+            apply();
+        }
+        ntex::rt::spawn(async move {
+            loop {
+                sleep(Seconds(1)).await;
+                let now = modified();
+                if now == seen {
+                    continue;
+                }
+                seen = now;
+                if seen.is_some() {
+                    apply();
+                }
+            }
+        });
+    }
+
+    /// Whether any client may run in dual mode.
+    pub fn is_active(&self) -> bool {
+        self.all || !self.cohorts.is_empty()
+    }
+
+    pub fn applies(&self, client_id: &str, identity: &Identity) -> bool {
+        self.all
+            || self.cohorts.iter().any(|cohort| {
+                cohort
+                    .client_id
+                    .as_deref()
+                    .is_none_or(|pattern| wildcard_matches(pattern, client_id))
+                    && cohort
+                        .tenant
+                        .as_deref()
+                        .is_none_or(|tenant| identity.tenant_name() == Some(tenant))
+                    && cohort.certificate.as_ref().is_none_or(|(field, pattern)| {
+                        identity
+                            .certificate
+                            .as_ref()
+                            .and_then(|cert| cert.field(*field))
+                            .is_some_and(|value| wildcard_matches(pattern, value))
+                    })
+            })
+    }
+}
+
+/// Matches `value` against a pattern where `*` stands for any number of characters.
+fn wildcard_matches(pattern: &str, value: &str) -> bool {
+    let mut parts = pattern.split('*');
+    // A pattern without `*` has a single part, which has to be the whole value.
+    let first = parts.next().unwrap_or_default();
+    let Some(mut rest) = value.strip_prefix(first) else {
+        return false;
+    };
+    let mut parts = parts.peekable();
+    while let Some(part) = parts.next() {
+        if parts.peek().is_none() {
+            return rest.ends_with(part);
+        }
+        match rest.find(part) {
+            Some(at) => rest = &rest[at + part.len()..],
+            None => return false,
+        }
+    }
+    rest.is_empty()
+}
+
+pub(crate) fn create_dual_policy() -> DualPolicy {
+    DualPolicy::new(&CONFIG.dual)
+}
+
+pub(crate) fn create_dual_primary() -> Arc<LoadBalancer<Consistent>> {
+    create_pool(backend_set(&CONFIG.dual.primary))
+}
+
+pub(crate) fn create_dual_secondary() -> Arc<LoadBalancer<Consistent>> {
+    create_pool(backend_set(&CONFIG.dual.secondary))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::cert::ClientCertificate;
+    use super::super::config;
+    use super::*;
+
+    #[test]
+    fn wildcard_matches_patterns() {
+        assert!(wildcard_matches("sensor-1", "sensor-1"));
+        assert!(!wildcard_matches("sensor-1", "sensor-10"));
+        assert!(wildcard_matches("sensor-*", "sensor-10"));
+        assert!(wildcard_matches("sensor-*", "sensor-"));
+        assert!(!wildcard_matches("sensor-*", "actuator-1"));
+        assert!(wildcard_matches("*-eu", "sensor-eu"));
+        assert!(!wildcard_matches("*-eu", "sensor-eu-2"));
+        assert!(wildcard_matches("a*b*c", "a-b-b-c"));
+        assert!(!wildcard_matches("a*b*c", "a-c-b"));
+        assert!(!wildcard_matches("ab*ba", "aba"));
+        assert!(wildcard_matches("*", ""));
+    }
+
+    fn policy(cohorts: Vec<config::Cohort>) -> DualPolicy {
+        DualPolicy::new(&Dual {
+            cohorts,
+            ..Dual::default()
+        })
+    }
+
+    fn cohort(client_id: Option<&str>, common_name: Option<&str>) -> config::Cohort {
+        config::Cohort {
+            client_id: client_id.map(str::to_owned),
+            tenant: None,
+            certificate_field: common_name.map(|_| "cn".to_owned()),
+            certificate_value: common_name.map(str::to_owned),
+        }
+    }
+
+    fn identity(common_name: Option<&str>) -> Identity {
+        Identity {
+            certificate: Some(ClientCertificate {
+                common_name: common_name.map(str::to_owned),
+                ..Default::default()
+            }),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn cohorts_match_every_condition() {
+        let policy = policy(vec![cohort(Some("sensor-*"), Some("fleet-*"))]);
+        assert!(policy.is_active());
+        assert!(policy.applies("sensor-1", &identity(Some("fleet-a"))));
+        assert!(!policy.applies("sensor-1", &identity(Some("office"))));
+        assert!(!policy.applies("sensor-1", &identity(None)));
+        assert!(!policy.applies("sensor-1", &Identity::default()));
+        assert!(!policy.applies("actuator-1", &identity(Some("fleet-a"))));
+    }
+
+    #[test]
+    fn any_cohort_applies() {
+        let policy = policy(vec![
+            cohort(Some("sensor-*"), None),
+            cohort(None, Some("fleet-*")),
+        ]);
+        assert!(policy.applies("sensor-1", &Identity::default()));
+        assert!(policy.applies("actuator-1", &identity(Some("fleet-a"))));
+        assert!(!policy.applies("actuator-1", &identity(Some("office"))));
+    }
+
+    #[test]
+    fn enabled_policy_applies_to_everyone() {
+        let policy = policy(Vec::new());
+        assert!(!policy.is_active());
+        assert!(!policy.applies("sensor-1", &Identity::default()));
+
+        let policy = DualPolicy::new(&Dual {
+            enabled: true,
+            ..Dual::default()
+        });
+        assert!(policy.applies("sensor-1", &Identity::default()));
+    }
+
+    #[test]
+    fn merge_follows_the_policy() {
+        let both = |primary: u8, secondary: u8| primary.min(secondary);
+
+        let merged = SubscribePolicy::Both.merge("c", Ok::<_, ()>(2), Ok(1), both);
+        assert!(matches!(merged, Ok(1)));
+        let merged = SubscribePolicy::Both.merge("c", Ok(2), Err(()), both);
+        assert!(matches!(merged, Err(ServerError::Internal)));
+
+        let merged = SubscribePolicy::PrimaryWins.merge("c", Ok::<_, ()>(2), Ok(1), both);
+        assert!(matches!(merged, Ok(2)));
+        let merged = SubscribePolicy::PrimaryWins.merge("c", Ok(2), Err(()), both);
+        assert!(matches!(merged, Ok(2)));
+        let merged = SubscribePolicy::PrimaryWins.merge("c", Err(()), Ok(1), both);
+        assert!(matches!(merged, Err(ServerError::Internal)));
+    }
+
+    #[test]
+    fn subscribe_results_combine_to_the_lower_grant() {
+        use v3::codec::SubscribeReturnCode::{Failure, Success};
+        use v5::codec::SubscribeAckReason::{GrantedQos0, GrantedQos1, NotAuthorized};
+
+        let both = SubscribePolicy::Both;
+        let at_least_once = Success(QoS::AtLeastOnce);
+        let at_most_once = Success(QoS::AtMostOnce);
+        assert_eq!(
+            both.subscribe_code_v3(at_least_once, at_most_once),
+            at_most_once
+        );
+        assert_eq!(both.subscribe_code_v3(at_least_once, Failure), Failure);
+        assert_eq!(
+            SubscribePolicy::PrimaryWins.subscribe_code_v3(at_least_once, Failure),
+            at_least_once
+        );
+
+        assert_eq!(
+            both.subscribe_reason_v5(GrantedQos1, GrantedQos0),
+            GrantedQos0
+        );
+        assert_eq!(
+            both.subscribe_reason_v5(GrantedQos0, GrantedQos1),
+            GrantedQos0
+        );
+        assert_eq!(
+            both.subscribe_reason_v5(GrantedQos1, NotAuthorized),
+            NotAuthorized
+        );
+        assert_eq!(
+            both.subscribe_reason_v5(NotAuthorized, GrantedQos1),
+            NotAuthorized
+        );
+        assert_eq!(
+            SubscribePolicy::PrimaryWins.subscribe_reason_v5(GrantedQos1, NotAuthorized),
+            GrantedQos1
+        );
+    }
+}
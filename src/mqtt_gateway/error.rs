@@ -1,11 +1,15 @@
 use ntex_mqtt::v5;
 
 #[derive(Debug)]
-pub struct ServerError;
+pub enum ServerError {
+    Internal,
+    /// A publish the topic ACL denies, with the policy set to disconnect the client.
+    NotAuthorized,
+}
 
 impl From<()> for ServerError {
     fn from(_: ()) -> Self {
-        ServerError
+        ServerError::Internal
     }
 }
 
@@ -15,4 +19,4 @@ impl TryFrom<ServerError> for v5::PublishAck {
     fn try_from(err: ServerError) -> Result<Self, Self::Error> {
         Err(err)
     }
-}
\ No newline at end of file
+}
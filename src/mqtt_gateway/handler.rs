@@ -1,86 +1,217 @@
 use super::session::AnySink;
 
+use super::auth::Identity;
+use super::bridge::{self, ProtocolVersion};
+use super::dedup::{DualDedup, DualSide, PublishDedup};
 use super::dual::DualSink;
+use super::inflight::InflightWindow;
+use super::lifecycle::ConnectionPair;
+use super::registry::SubscriptionRegistry;
+use super::tenant::backend_pool;
 
-use super::UPSTREAM;
+use super::bridge::Bridge;
 use super::error::ServerError;
 use super::session::SessionState;
-use log::{debug, error, info};
+use super::store::StoredSession;
+use super::{CONFIG, DUAL, DUAL_PRIMARY, DUAL_SECONDARY, SESSION_STORE};
+use log::{debug, error, info, warn};
 use ntex::fn_service;
-use ntex::time::Seconds;
-use ntex_mqtt::v3::codec::SubscribeReturnCode;
-use ntex_mqtt::{QoS, v3};
+use ntex::time::{Seconds, sleep};
+use ntex::util::{ByteString, Bytes};
+use ntex_mqtt::error::ClientError;
+use ntex_mqtt::{QoS, v3, v5};
+use pingora_load_balancing::selection::Consistent;
+use pingora_load_balancing::{Backend, LoadBalancer};
 use std::cell::RefCell;
-use std::env;
+use std::rc::Rc;
+
+const FAILOVER_ATTEMPTS: usize = 5;
+const FAILOVER_BACKOFF: Seconds = Seconds(1);
+/// Bounds the walk over the hash ring, which wraps around forever.
+const FAILOVER_SELECT_ITERATIONS: usize = 256;
 
 pub(crate) async fn handle_connect(
     mut handshake: v3::Handshake,
+    identity: Identity,
 ) -> Result<v3::HandshakeAck<SessionState<v3::MqttSink>>, ServerError> {
-
-    if env::var("RUN_DUAL").is_ok() {
-        return handle_dual_connect(handshake).await;
+    if DUAL.applies(&handshake.packet().client_id, &identity) {
+        return handle_dual_connect(handshake, identity).await;
+    }
+    if let Some(ProtocolVersion::V5) = bridge::upstream_protocol() {
+        return handle_bridge_connect(handshake, identity).await;
     }
     // TODO: verify the connect packet.
     let client_id = handshake.packet_mut().client_id.to_string();
 
-    // TODO: get client certificate
-    // handshake.io().query::<PeerCert>().as_ref();
-
-    let backend = UPSTREAM.select(client_id.as_bytes(), 1).ok_or_else(|| {
-        error!("No backend found for client ID: {}", client_id);
-        ServerError
-    })?;
+    let backend = backend_pool(identity.tenant.as_deref())
+        .select(client_id.as_bytes(), 1)
+        .ok_or_else(|| {
+            error!("No backend found for client ID: {}", client_id);
+            ServerError::Internal
+        })?;
 
-    // TODO: clone the received connect packet.
-    let client = v3::client::MqttConnector::new(backend.addr.to_string())
-        .client_id(client_id.clone())
-        .keep_alive(Seconds::new(60))
+    let client = match v3::client::MqttConnector::new(backend.addr.to_string())
+        .packet(|pkt| {
+            *pkt = handshake.packet().clone();
+            pkt.keep_alive = upstream_keep_alive(pkt.keep_alive);
+        })
         .connect()
         .await
-        .map_err(|e| {
+    {
+        Ok(client) => client,
+        Err(ClientError::Ack(ack)) => {
+            error!(
+                "Backend {} refused connection: client_id={}, reason={:?}",
+                backend.addr, client_id, ack.return_code
+            );
+            return Ok(bridge::reject_v3(handshake, ack.return_code));
+        }
+        Err(e) => {
             error!("TCP connection to backend {} failed: {}", backend.addr, e);
-            ServerError
-        })?;
+            return Err(ServerError::Internal);
+        }
+    };
+    let session_present = client.session_present();
 
-    // TODO: close connection when source is disconnected.
     let upstream_sink = client.sink();
 
-    // TODO: load session from database.
     let sink = handshake.sink();
+    let stored = load_session(
+        identity.tenant_name(),
+        &client_id,
+        handshake.packet().clean_session,
+    );
     let session_state = SessionState {
         client_id: client_id.clone(),
-        subscriptions: RefCell::new(Vec::new()),
+        username: identity.username,
+        certificate: identity.certificate.map(Rc::new),
+        tenant: identity.tenant,
+        subscriptions: SubscriptionRegistry::default(),
         source: sink,
         // TODO: create multiple sinks if they need to connect to multiple upstream.
-        sink: AnySink::MqttSink(upstream_sink),
+        sink: Rc::new(RefCell::new(AnySink::MqttSink(upstream_sink))),
+        upstream_inflight: InflightWindow::default(),
+        downstream_inflight: InflightWindow::default(),
+        upstream_dedup: PublishDedup::default(),
+        persistent: !handshake.packet().clean_session && !client_id.is_empty(),
+        offline: Rc::default(),
+        pair: ConnectionPair::default(),
     };
+    session_state.pair.register(
+        session_state.tenant_name(),
+        &client_id,
+        session_state.certificate.clone(),
+        session_state.clone(),
+    );
 
-    let handle_upstream =
-        |packet: v3::client::Control<ServerError>,
-         session: SessionState<v3::MqttSink>| async {
-            match packet {
-                v3::client::Control::Publish(publish) => {
-                    handle_upstream_pub(publish, session).await
-                }
-                _ => handle_upstream_control(packet, session).await,
-            }
-        };
+    start_upstream(
+        client,
+        session_state.clone(),
+        Rc::new(handshake.packet().clone()),
+        backend.addr.to_string(),
+    );
 
-    let session_clone = session_state.clone();
-    ntex::rt::spawn_fn(move || {
-        client.start(fn_service(
-            move |packet: v3::client::Control<ServerError>| {
-                handle_upstream(packet, session_clone.clone())
-            },
-        ))
-    });
+    let resumed = resume_session(&session_state, stored, session_present).await?;
 
     info!(
-        "New MQTT v3 TCP connection established: client_id={}",
-        client_id
+        "New MQTT v3 TCP connection established: client_id={}, certificate={:?}",
+        client_id,
+        session_state.certificate.as_ref().map(|cert| &cert.subject)
     );
     debug!("Connection details: handshake received");
-    Ok(handshake.ack(session_state, false))
+    Ok(handshake.ack(session_state, session_present || resumed))
+}
+
+/// Relays the packets of a backend connection to the session, and fails over to another backend
+/// once the connection is gone.
+fn start_upstream(
+    client: v3::client::Client,
+    session: SessionState<v3::MqttSink>,
+    connect: Rc<v3::codec::Connect>,
+    backend: String,
+) {
+    ntex::rt::spawn(async move {
+        let session_clone = session.clone();
+        let _ = client
+            .start(fn_service(
+                move |packet: v3::client::Control<ServerError>| {
+                    handle_upstream_control(packet, session_clone.clone())
+                },
+            ))
+            .await;
+        failover(session, connect, backend).await;
+    });
+}
+
+/// Moves a session whose backend connection is gone to another healthy backend, so the client
+/// stays connected. The client is disconnected when no backend accepts the session.
+async fn failover(
+    session: SessionState<v3::MqttSink>,
+    connect: Rc<v3::codec::Connect>,
+    failed: String,
+) {
+    for attempt in 0..FAILOVER_ATTEMPTS {
+        // Nothing to fail over when the client is the one who left.
+        if !session.source.is_open() {
+            return;
+        }
+        if attempt > 0 {
+            sleep(FAILOVER_BACKOFF).await;
+        }
+
+        let Some(backend) = select_failover_backend(&session, &failed) else {
+            continue;
+        };
+        let client = match v3::client::MqttConnector::new(backend.addr.to_string())
+            .packet(|pkt| {
+                *pkt = (*connect).clone();
+                pkt.keep_alive = upstream_keep_alive(pkt.keep_alive);
+            })
+            .connect()
+            .await
+        {
+            Ok(client) => client,
+            Err(e) => {
+                warn!(
+                    "Failover of client {} to backend {} failed: {}",
+                    session.client_id, backend.addr, e
+                );
+                continue;
+            }
+        };
+        if !session.source.is_open() {
+            client.sink().close();
+            return;
+        }
+
+        let session_present = client.session_present();
+        *session.sink.borrow_mut() = AnySink::MqttSink(client.sink());
+        // Packet ids are scoped to a connection, the new one starts with none seen.
+        let upstream = SessionState {
+            upstream_dedup: PublishDedup::default(),
+            ..session.clone()
+        };
+        start_upstream(client, upstream, connect, backend.addr.to_string());
+        info!(
+            "Client {} failed over from backend {} to {}",
+            session.client_id, failed, backend.addr
+        );
+
+        if !session_present && session.sink().resubscribe(&session).await.is_err() {
+            // The new connection is gone too and fails over on its own.
+            warn!(
+                "Failed to replay subscriptions of client {}",
+                session.client_id
+            );
+        }
+        return;
+    }
+
+    error!(
+        "No backend accepted client {} after backend {} failed",
+        session.client_id, failed
+    );
+    session.pair.upstream_closed();
 }
 
 pub(crate) async fn handle_downstream_pub(
@@ -94,27 +225,51 @@ pub(crate) async fn handle_downstream_pub(
         publish.topic(),
     );
 
+    if !session.authorize_publish(publish.topic().get_ref())? {
+        return Ok(());
+    }
+
+    if let AnySink::Bridged(sink) = &session.sink() {
+        return handle_bridge_downstream_pub(publish, sink, &session.upstream_inflight).await;
+    }
+
     // Forward duplicate downstream packets to the backend.
-    let new_packet_builder = session
-        .sink
-        .publish(publish.topic().get_ref().clone(), publish.take_payload());
+    let topic = publish.topic().get_ref().clone();
+    let payload = publish.take_payload();
+
+    if let AnySink::DualSink(sink) = &session.sink() {
+        return sink
+            .publish(
+                topic,
+                payload,
+                publish.packet().qos,
+                &session.upstream_inflight,
+            )
+            .await;
+    }
 
     if let QoS::AtMostOnce = publish.packet().qos {
-        new_packet_builder
+        session
+            .sink()
+            .publish(topic, payload)
             .send_at_most_once()
-            .map_err(|_| ServerError)
+            .map_err(|_| ServerError::Internal)
     } else {
-        // TODO: spawn a task to schedule retry.
         // Wait for PUBACK
-        new_packet_builder
-            .send_at_least_once()
+        session
+            .upstream_inflight
+            .deliver(&topic, &payload, |packet_id, dup| {
+                session
+                    .sink()
+                    .publish(topic.clone(), payload.clone())
+                    .packet_id(packet_id)
+                    .dup(dup)
+                    .send_at_least_once()
+            })
             .await
-            .map_err(|_| ServerError)
     }
 }
 
-
-
 async fn handle_upstream_pub(
     publish: v3::client::control::Publish,
     session: SessionState<v3::MqttSink>,
@@ -126,30 +281,56 @@ async fn handle_upstream_pub(
         session.client_id
     );
 
-    // TODO: Return a specific error for duplicate publish attempts, preventing the release of inflight counter.
-    if publish.packet().dup {
-        return Err(ServerError);
+    let packet_id = publish.packet().packet_id;
+    if !session
+        .upstream_dedup
+        .receive(packet_id, publish.packet().dup)
+        .await
+    {
+        debug!(
+            "Acking redelivered publish without forwarding it: packet_id={:?}, client_id={}",
+            packet_id, session.client_id
+        );
+        return Ok(publish.ack());
     }
 
-    let new_packet_builder = session.source.publish(
-        publish.packet().topic.clone(),
-        publish.packet().payload.clone(),
-    );
+    let topic = &publish.packet().topic;
+    let payload = &publish.packet().payload;
 
-    if let QoS::AtMostOnce = publish.packet().qos {
-        new_packet_builder
+    // Publishes still arrive until the backend side of a closed client is torn down, keep them.
+    let queued = !session.source.is_open()
+        && session.queue_offline(
+            &publish.packet().topic,
+            &publish.packet().payload,
+            publish.packet().qos,
+        );
+
+    let result = if queued {
+        Ok(publish.ack())
+    } else if let QoS::AtMostOnce = publish.packet().qos {
+        session
+            .source
+            .publish(topic.clone(), payload.clone())
             .send_at_most_once()
             .map(|_| publish.ack())
-            .map_err(|_| ServerError)
+            .map_err(|_| ServerError::Internal)
     } else {
-        // TODO: spawn a task to schedule retry.
         // Wait for PUBACK
-        new_packet_builder
-            .send_at_least_once()
+        session
+            .downstream_inflight
+            .deliver(topic, payload, |packet_id, dup| {
+                session
+                    .source
+                    .publish(topic.clone(), payload.clone())
+                    .packet_id(packet_id)
+                    .dup(dup)
+                    .send_at_least_once()
+            })
             .await
             .map(|_| publish.ack())
-            .map_err(|_| ServerError)
-    }
+    };
+    session.upstream_dedup.complete(packet_id, result.is_ok());
+    result
 }
 
 pub(crate) async fn handle_downstream_control(
@@ -157,11 +338,15 @@ pub(crate) async fn handle_downstream_control(
     session: SessionState<v3::MqttSink>,
 ) -> Result<v3::ControlAck, ServerError> {
     match control {
-        v3::Control::Subscribe(mut s) => {
-            session.sink.handle_subscribe(&session, s).await
+        v3::Control::Subscribe(s) => {
+            let ack = session.sink().handle_subscribe(&session, s).await;
+            session.persist();
+            ack
         }
         v3::Control::Unsubscribe(s) => {
-            session.sink.handle_unsubscribe(&session, s).await
+            let ack = session.sink().handle_unsubscribe(&session, s).await;
+            session.persist();
+            ack
         }
         v3::Control::Error(e) => Ok(e.ack()),
         v3::Control::ProtocolError(e) => Ok(e.ack()),
@@ -172,10 +357,17 @@ pub(crate) async fn handle_downstream_control(
                 session.client_id
             );
             debug!("Disconnect details: {:?}", d);
-            session.sink.close();
+            session.sink().close();
             Ok(d.ack())
         }
-        v3::Control::Closed(c) => Ok(c.ack()),
+        v3::Control::Closed(c) => {
+            session.persist();
+            if let AnySink::DualSink(sink) = session.sink() {
+                sink.dedup.flush();
+            }
+            session.pair.downstream_closed();
+            Ok(c.ack())
+        }
         v3::Control::PeerGone(c) => Ok(c.ack()),
         // TODO: Back pressure
         v3::Control::WrBackpressure(w) => Ok(w.ack()),
@@ -187,13 +379,10 @@ pub(crate) async fn handle_upstream_control(
     session: SessionState<v3::MqttSink>,
 ) -> Result<v3::ControlAck, ServerError> {
     match control {
-        v3::client::Control::Closed(c) => {
-            session.source.close();
-            Ok(c.ack())
-        }
+        // The session fails over to another backend once the connection is gone.
+        v3::client::Control::Closed(c) => Ok(c.ack()),
         v3::client::Control::Error(error) => {
-            session.source.close();
-            println!(
+            error!(
                 "Server error: clientId: {}, {:?}",
                 session.client_id,
                 error.get_ref()
@@ -201,8 +390,7 @@ pub(crate) async fn handle_upstream_control(
             Ok(error.ack())
         }
         v3::client::Control::ProtocolError(error) => {
-            session.source.close();
-            println!(
+            error!(
                 "Protocol error: clientId: {}, {}",
                 session.client_id,
                 error.get_ref()
@@ -210,104 +398,1230 @@ pub(crate) async fn handle_upstream_control(
             Ok(error.ack())
         }
         v3::client::Control::PeerGone(p) => {
-            session.source.close();
-            println!(
+            error!(
                 "Peer gone error: clientId: {}, {:?}",
                 session.client_id,
                 p.err()
             );
             Ok(p.ack())
         }
-        _ => unimplemented!(),
+        v3::client::Control::Publish(publish) => handle_upstream_pub(publish, session).await,
     }
 }
 
 pub(crate) async fn handle_dual_connect(
     handshake: v3::Handshake,
+    identity: Identity,
 ) -> Result<v3::HandshakeAck<SessionState<v3::MqttSink>>, ServerError> {
-    // TODO initialize dual sink
     let client_id = handshake.packet().client_id.to_string();
-    let primary_sink_address = "127.0.0.1:1883".to_string();
-    let secondary_sink_address = "127.0.0.1:2883".to_string();
-    
-    
-    // TODO: clone the received connect packet.
-    let primary_client = v3::client::MqttConnector::new(primary_sink_address.clone())
-        .client_id(client_id.clone())
-        .keep_alive(Seconds::new(60))
+    let select = |pool: &LoadBalancer<Consistent>| {
+        pool.select(client_id.as_bytes(), 1)
+            .map(|backend| backend.addr.to_string())
+            .ok_or_else(|| {
+                error!("No dual backend found for client ID: {}", client_id);
+                ServerError::Internal
+            })
+    };
+    let primary_sink_address = select(&DUAL_PRIMARY)?;
+    let secondary_sink_address = select(&DUAL_SECONDARY)?;
+
+    let primary_client = match v3::client::MqttConnector::new(primary_sink_address.clone())
+        .packet(|pkt| {
+            *pkt = handshake.packet().clone();
+            pkt.keep_alive = upstream_keep_alive(pkt.keep_alive);
+        })
         .connect()
         .await
-        .map_err(|e| {
-            error!("TCP connection to backend {} failed: {}", primary_sink_address, e);
-            ServerError
-        })?;
+    {
+        Ok(client) => client,
+        Err(ClientError::Ack(ack)) => {
+            error!(
+                "Backend {} refused connection: client_id={}, reason={:?}",
+                primary_sink_address, client_id, ack.return_code
+            );
+            return Ok(bridge::reject_v3(handshake, ack.return_code));
+        }
+        Err(e) => {
+            error!(
+                "TCP connection to backend {} failed: {}",
+                primary_sink_address, e
+            );
+            return Err(ServerError::Internal);
+        }
+    };
+    // The client only sees the primary backend, so its session state is the one reported back.
+    let session_present = primary_client.session_present();
 
     let secondary_client = v3::client::MqttConnector::new(secondary_sink_address.clone())
-        .client_id(client_id.clone())
-        .keep_alive(Seconds::new(60))
+        .packet(|pkt| {
+            *pkt = handshake.packet().clone();
+            pkt.keep_alive = upstream_keep_alive(pkt.keep_alive);
+        })
         .connect()
         .await
         .map_err(|e| {
-            error!("TCP connection to backend {} failed: {}", secondary_sink_address, e);
-            ServerError
+            error!(
+                "TCP connection to backend {} failed: {}",
+                secondary_sink_address, e
+            );
+            // Nothing reaches the primary backend anymore, tell it so instead of timing out.
+            primary_client.sink().close();
+            ServerError::Internal
         })?;
 
-    let dual_sink = DualSink::new(client_id.clone(), primary_client.sink(), secondary_client.sink());
+    let dual_sink = DualSink::new(
+        client_id.clone(),
+        primary_client.sink(),
+        secondary_client.sink(),
+    );
     let source_sink = handshake.sink();
 
+    let stored = load_session(
+        identity.tenant_name(),
+        &client_id,
+        handshake.packet().clean_session,
+    );
     let session_state = SessionState {
         client_id: client_id.clone(),
-        subscriptions: RefCell::new(Vec::new()),
+        username: identity.username,
+        certificate: identity.certificate.map(Rc::new),
+        tenant: identity.tenant,
+        subscriptions: SubscriptionRegistry::default(),
         source: source_sink,
-        sink: AnySink::DualSink(dual_sink),
+        sink: Rc::new(RefCell::new(AnySink::DualSink(dual_sink))),
+        upstream_inflight: InflightWindow::default(),
+        downstream_inflight: InflightWindow::default(),
+        upstream_dedup: PublishDedup::default(),
+        persistent: !handshake.packet().clean_session && !client_id.is_empty(),
+        offline: Rc::default(),
+        pair: ConnectionPair::default(),
     };
+    session_state.pair.register(
+        session_state.tenant_name(),
+        &client_id,
+        session_state.certificate.clone(),
+        session_state.clone(),
+    );
 
+    let handle_upstream = |side: DualSide,
+                           packet: v3::client::Control<ServerError>,
+                           session: SessionState<v3::MqttSink>| async move {
+        match packet {
+            v3::client::Control::Publish(publish) => {
+                handle_dual_upstream_pub(publish, session, side).await
+            }
+            _ => handle_upstream_control(packet, session).await,
+        }
+    };
 
+    let session_clone1 = session_state.clone();
+    // Packet ids are per connection, so each backend needs its own dedup state.
+    let session_clone2 = SessionState {
+        upstream_dedup: PublishDedup::default(),
+        ..session_state.clone()
+    };
+    ntex::rt::spawn(async move {
+        let pair = session_clone1.pair.clone();
+        let _ = primary_client
+            .start(fn_service(
+                move |packet: v3::client::Control<ServerError>| {
+                    handle_upstream(DualSide::Primary, packet, session_clone1.clone())
+                },
+            ))
+            .await;
+        // Dual sessions do not fail over, losing either backend ends the session.
+        pair.upstream_closed();
+    });
 
-   
-    let handle_upstream =
-        |packet: v3::client::Control<ServerError>,
-         session: SessionState<v3::MqttSink>| async {
-            match packet {
-                v3::client::Control::Publish(publish) => {
-                    handle_dual_upstream_pub(publish, session).await
-                }
-                _ => handle_upstream_control(packet, session).await,
+    ntex::rt::spawn(async move {
+        let pair = session_clone2.pair.clone();
+        let _ = secondary_client
+            .start(fn_service(
+                move |packet: v3::client::Control<ServerError>| {
+                    handle_upstream(DualSide::Secondary, packet, session_clone2.clone())
+                },
+            ))
+            .await;
+        pair.upstream_closed();
+    });
+
+    let resumed = resume_session(&session_state, stored, session_present).await?;
+
+    info!(
+        "New MQTT v3 TCP connection established: client_id={}, certificate={:?}",
+        client_id,
+        session_state.certificate.as_ref().map(|cert| &cert.subject)
+    );
+    debug!("Connection details: handshake received");
+    Ok(handshake.ack(session_state, session_present || resumed))
+}
+
+async fn handle_dual_upstream_pub(
+    publish: v3::client::control::Publish,
+    session: SessionState<v3::MqttSink>,
+    side: DualSide,
+) -> Result<v3::ControlAck, ServerError> {
+    debug!(
+        "Incoming MQTT v3 publish over TCP from backend: packet_id={:?}, topic={} -> client_id={}",
+        publish.packet().packet_id,
+        publish.packet().topic,
+        session.client_id
+    );
+
+    let packet_id = publish.packet().packet_id;
+    if !session
+        .upstream_dedup
+        .receive(packet_id, publish.packet().dup)
+        .await
+    {
+        debug!(
+            "Acking redelivered publish without forwarding it: packet_id={:?}, client_id={}",
+            packet_id, session.client_id
+        );
+        return Ok(publish.ack());
+    }
+
+    // MQTT 3.1.1 publishes carry no correlation data, only the content tells messages apart.
+    let key = DualDedup::key(&publish.packet().topic, &publish.packet().payload, None);
+    if let AnySink::DualSink(sink) = session.sink()
+        && !sink.dedup.admit(side, key, &publish.packet().topic).await
+    {
+        debug!(
+            "Acking publish the other backend delivered without forwarding it: side={:?}, \
+             topic={}, client_id={}",
+            side,
+            publish.packet().topic,
+            session.client_id
+        );
+        session.upstream_dedup.complete(packet_id, true);
+        return Ok(publish.ack());
+    }
+
+    let topic = &publish.packet().topic;
+    let payload = &publish.packet().payload;
+
+    // Publishes still arrive until the backend side of a closed client is torn down, keep them.
+    let queued = !session.source.is_open()
+        && session.queue_offline(
+            &publish.packet().topic,
+            &publish.packet().payload,
+            publish.packet().qos,
+        );
+
+    let result = if queued {
+        Ok(publish.ack())
+    } else if let QoS::AtMostOnce = publish.packet().qos {
+        session
+            .source
+            .publish(topic.clone(), payload.clone())
+            .send_at_most_once()
+            .map(|_| publish.ack())
+            .map_err(|_| ServerError::Internal)
+    } else {
+        // Wait for PUBACK
+        session
+            .downstream_inflight
+            .deliver(topic, payload, |packet_id, dup| {
+                session
+                    .source
+                    .publish(topic.clone(), payload.clone())
+                    .packet_id(packet_id)
+                    .dup(dup)
+                    .send_at_least_once()
+            })
+            .await
+            .map(|_| publish.ack())
+    };
+    session.upstream_dedup.complete(packet_id, result.is_ok());
+    result
+}
+
+pub(crate) async fn handle_connect_v5(
+    handshake: v5::Handshake,
+    identity: Identity,
+) -> Result<v5::HandshakeAck<SessionState<v5::MqttSink>>, ServerError> {
+    if DUAL.applies(&handshake.packet().client_id, &identity) {
+        return handle_dual_connect_v5(handshake, identity).await;
+    }
+    if let Some(ProtocolVersion::V3) = bridge::upstream_protocol() {
+        return handle_bridge_connect_v5(handshake, identity).await;
+    }
+    // TODO: verify the connect packet.
+    let client_id = handshake.packet().client_id.to_string();
+
+    let backend = backend_pool(identity.tenant.as_deref())
+        .select(client_id.as_bytes(), 1)
+        .ok_or_else(|| {
+            error!("No backend found for client ID: {}", client_id);
+            ServerError::Internal
+        })?;
+
+    let mut connect = handshake.packet().clone();
+    // Topic aliases and enhanced auth are per connection, the gateway does not relay them.
+    connect.topic_alias_max = 0;
+    connect.auth_method = None;
+    connect.auth_data = None;
+    let connect = Rc::new(connect);
+
+    let client = match v5::client::MqttConnector::new(backend.addr.to_string())
+        .packet(|pkt| {
+            *pkt = (*connect).clone();
+            pkt.keep_alive = upstream_keep_alive(pkt.keep_alive);
+        })
+        .connect()
+        .await
+    {
+        Ok(client) => client,
+        Err(ClientError::Ack(ack)) => {
+            error!(
+                "Backend {} refused connection: client_id={}, reason={:?}",
+                backend.addr, client_id, ack.reason_code
+            );
+            return Ok(handshake.fail_with(*ack));
+        }
+        Err(e) => {
+            error!("TCP connection to backend {} failed: {}", backend.addr, e);
+            return Err(ServerError::Internal);
+        }
+    };
+
+    let upstream_sink = client.sink();
+    let upstream_ack = client.packet().clone();
+
+    let stored = load_session(
+        identity.tenant_name(),
+        &client_id,
+        handshake.packet().clean_start,
+    );
+    let session_state = SessionState {
+        client_id: client_id.clone(),
+        username: identity.username,
+        certificate: identity.certificate.map(Rc::new),
+        tenant: identity.tenant,
+        subscriptions: SubscriptionRegistry::default(),
+        source: handshake.sink(),
+        sink: Rc::new(RefCell::new(AnySink::MqttSink(upstream_sink))),
+        upstream_inflight: InflightWindow::default(),
+        downstream_inflight: InflightWindow::default(),
+        upstream_dedup: PublishDedup::default(),
+        persistent: !handshake.packet().clean_start && !client_id.is_empty(),
+        offline: Rc::default(),
+        pair: ConnectionPair::default(),
+    };
+    session_state.pair.register(
+        session_state.tenant_name(),
+        &client_id,
+        session_state.certificate.clone(),
+        session_state.clone(),
+    );
+
+    start_upstream_v5(
+        client,
+        session_state.clone(),
+        connect,
+        backend.addr.to_string(),
+    );
+
+    let resumed = resume_session(&session_state, stored, upstream_ack.session_present).await?;
+
+    info!(
+        "New MQTT v5 TCP connection established: client_id={}, certificate={:?}",
+        client_id,
+        session_state.certificate.as_ref().map(|cert| &cert.subject)
+    );
+    debug!("Connection details: handshake received");
+    Ok(handshake.ack(session_state).with(|ack| {
+        ack.session_present = upstream_ack.session_present || resumed;
+        ack.assigned_client_id = upstream_ack.assigned_client_id;
+        ack.session_expiry_interval_secs = upstream_ack.session_expiry_interval_secs;
+        ack.receive_max = upstream_ack.receive_max;
+        ack.max_packet_size = upstream_ack.max_packet_size;
+    }))
+}
+
+/// Relays the packets of a backend connection to the session, and fails over to another backend
+/// once the connection is gone.
+fn start_upstream_v5(
+    client: v5::client::Client,
+    session: SessionState<v5::MqttSink>,
+    connect: Rc<v5::codec::Connect>,
+    backend: String,
+) {
+    ntex::rt::spawn(async move {
+        let session_clone = session.clone();
+        let _ = client
+            .start(fn_service(
+                move |packet: v5::client::Control<ServerError>| {
+                    handle_upstream_control_v5(packet, session_clone.clone())
+                },
+            ))
+            .await;
+        failover_v5(session, connect, backend).await;
+    });
+}
+
+/// Moves a session whose backend connection is gone to another healthy backend, so the client
+/// stays connected. The client is disconnected when no backend accepts the session.
+async fn failover_v5(
+    session: SessionState<v5::MqttSink>,
+    connect: Rc<v5::codec::Connect>,
+    failed: String,
+) {
+    for attempt in 0..FAILOVER_ATTEMPTS {
+        // Nothing to fail over when the client is the one who left.
+        if !session.source.is_open() {
+            return;
+        }
+        if attempt > 0 {
+            sleep(FAILOVER_BACKOFF).await;
+        }
+
+        let Some(backend) = select_failover_backend(&session, &failed) else {
+            continue;
+        };
+        let client = match v5::client::MqttConnector::new(backend.addr.to_string())
+            .packet(|pkt| {
+                *pkt = (*connect).clone();
+                pkt.keep_alive = upstream_keep_alive(pkt.keep_alive);
+            })
+            .connect()
+            .await
+        {
+            Ok(client) => client,
+            Err(e) => {
+                warn!(
+                    "Failover of client {} to backend {} failed: {}",
+                    session.client_id, backend.addr, e
+                );
+                continue;
             }
         };
+        if !session.source.is_open() {
+            client.sink().close();
+            return;
+        }
+
+        let session_present = client.packet().session_present;
+        *session.sink.borrow_mut() = AnySink::MqttSink(client.sink());
+        // Packet ids are scoped to a connection, the new one starts with none seen.
+        let upstream = SessionState {
+            upstream_dedup: PublishDedup::default(),
+            ..session.clone()
+        };
+        start_upstream_v5(client, upstream, connect, backend.addr.to_string());
+        info!(
+            "Client {} failed over from backend {} to {}",
+            session.client_id, failed, backend.addr
+        );
+
+        if !session_present && session.sink().resubscribe(&session).await.is_err() {
+            // The new connection is gone too and fails over on its own.
+            warn!(
+                "Failed to replay subscriptions of client {}",
+                session.client_id
+            );
+        }
+        return;
+    }
+
+    error!(
+        "No backend accepted client {} after backend {} failed",
+        session.client_id, failed
+    );
+    session.source.close_with_reason(v5::codec::Disconnect::new(
+        v5::codec::DisconnectReasonCode::ServerBusy,
+    ));
+    session.pair.upstream_closed();
+}
+
+pub(crate) async fn handle_downstream_pub_v5(
+    mut publish: v5::Publish,
+    session: SessionState<v5::MqttSink>,
+) -> Result<v5::PublishAck, ServerError> {
+    debug!(
+        "Incoming MQTT v5 publish over TCP from client {}: packet_id={:?}, topic={:?}",
+        session.client_id,
+        publish.id(),
+        publish.topic(),
+    );
+
+    if !session.authorize_publish(publish.topic().get_ref())? {
+        return Ok(v5::PublishAck::new(
+            v5::codec::PublishAckReason::NotAuthorized,
+        ));
+    }
+
+    if let AnySink::Bridged(sink) = &session.sink() {
+        return handle_bridge_downstream_pub_v5(publish, sink, &session.upstream_inflight).await;
+    }
+
+    let topic = publish.topic().get_ref().clone();
+    let payload = publish.take_payload();
+
+    if let AnySink::DualSink(sink) = &session.sink() {
+        let mut packet = v5::codec::Publish {
+            dup: false,
+            retain: publish.retain(),
+            qos: publish.qos(),
+            packet_id: None,
+            topic,
+            payload,
+            properties: v5::codec::PublishProperties::default(),
+        };
+        forward_publish_properties(&publish.packet().properties, &mut packet.properties);
+        return sink
+            .publish(packet, &session.upstream_inflight)
+            .await
+            .map(v5::PublishAck::new);
+    }
+
+    let new_packet_builder = || {
+        session
+            .sink()
+            .publish(topic.clone(), payload.clone())
+            .retain(publish.retain())
+            .properties(|props| forward_publish_properties(&publish.packet().properties, props))
+    };
+
+    if let QoS::AtMostOnce = publish.qos() {
+        new_packet_builder()
+            .send_at_most_once()
+            .map(|_| publish.ack())
+            .map_err(|_| ServerError::Internal)
+    } else {
+        // Wait for PUBACK and pass the backend reason code to the client.
+        session
+            .upstream_inflight
+            .deliver(&topic, &payload, |packet_id, dup| {
+                new_packet_builder()
+                    .packet_id(packet_id)
+                    .dup(dup)
+                    .send_at_least_once()
+            })
+            .await
+            .map(|ack| v5::PublishAck::new(ack.reason_code))
+    }
+}
+
+async fn handle_upstream_pub_v5(
+    publish: v5::client::control::Publish,
+    session: SessionState<v5::MqttSink>,
+) -> Result<v5::ControlAck, ServerError> {
+    debug!(
+        "Incoming MQTT v5 publish over TCP from backend: packet_id={:?}, topic={} -> client_id={}",
+        publish.packet().packet_id,
+        publish.packet().topic,
+        session.client_id
+    );
+
+    let packet_id = publish.packet().packet_id;
+    if !session
+        .upstream_dedup
+        .receive(packet_id, publish.packet().dup)
+        .await
+    {
+        debug!(
+            "Acking redelivered publish without forwarding it: packet_id={:?}, client_id={}",
+            packet_id, session.client_id
+        );
+        return Ok(publish.ack(v5::codec::PublishAckReason::Success));
+    }
+
+    forward_upstream_pub_v5(publish, session).await
+}
+
+/// Forwards a backend publish registered with the session's `upstream_dedup` to the client, or
+/// queues it while the client is offline.
+async fn forward_upstream_pub_v5(
+    publish: v5::client::control::Publish,
+    session: SessionState<v5::MqttSink>,
+) -> Result<v5::ControlAck, ServerError> {
+    let packet_id = publish.packet().packet_id;
+    let subscription_ids = session.subscription_ids(&publish.packet().topic);
+    let new_packet_builder = || {
+        session
+            .source
+            .publish(
+                publish.packet().topic.clone(),
+                publish.packet().payload.clone(),
+            )
+            .retain(publish.packet().retain)
+            .properties(|props| {
+                forward_publish_properties(&publish.packet().properties, props);
+                props.subscription_ids = subscription_ids.clone();
+            })
+    };
+
+    // Publishes still arrive until the backend side of a closed client is torn down, keep them.
+    let queued = !session.source.is_open()
+        && session.queue_offline(
+            &publish.packet().topic,
+            &publish.packet().payload,
+            publish.packet().qos,
+        );
+
+    let result = if queued {
+        Ok(publish.ack(v5::codec::PublishAckReason::Success))
+    } else if let QoS::AtMostOnce = publish.packet().qos {
+        new_packet_builder()
+            .send_at_most_once()
+            .map(|_| publish.ack_qos0())
+            .map_err(|_| ServerError::Internal)
+    } else {
+        // Wait for PUBACK and pass the client reason code to the backend.
+        let ack = session
+            .downstream_inflight
+            .deliver(
+                &publish.packet().topic,
+                &publish.packet().payload,
+                |packet_id, dup| {
+                    new_packet_builder()
+                        .packet_id(packet_id)
+                        .dup(dup)
+                        .send_at_least_once()
+                },
+            )
+            .await?;
+        Ok(publish.ack(ack.reason_code))
+    };
+    session.upstream_dedup.complete(packet_id, result.is_ok());
+    result
+}
+
+pub(crate) async fn handle_downstream_control_v5(
+    control: v5::Control<ServerError>,
+    session: SessionState<v5::MqttSink>,
+) -> Result<v5::ControlAck, ServerError> {
+    match control {
+        v5::Control::Subscribe(s) => {
+            let ack = session.sink().handle_subscribe(&session, s).await;
+            session.persist();
+            ack
+        }
+        v5::Control::Unsubscribe(s) => {
+            let ack = session.sink().handle_unsubscribe(&session, s).await;
+            session.persist();
+            ack
+        }
+        // TODO: forward enhanced authentication to the backend.
+        v5::Control::Auth(a) => Ok(a.ack(v5::codec::Auth::default())),
+        v5::Control::Error(e) => {
+            let reason = match e.get_ref() {
+                ServerError::NotAuthorized => v5::codec::DisconnectReasonCode::NotAuthorized,
+                _ => v5::codec::DisconnectReasonCode::UnspecifiedError,
+            };
+            Ok(e.ack(reason))
+        }
+        v5::Control::ProtocolError(e) => Ok(e.ack()),
+        v5::Control::Ping(p) => Ok(p.ack()),
+        v5::Control::Disconnect(d) => {
+            info!(
+                "Received TCP disconnect from client: client_id={}",
+                session.client_id
+            );
+            debug!("Disconnect details: {:?}", d);
+            session.sink().close_with_reason(d.packet().clone());
+            Ok(d.ack())
+        }
+        v5::Control::Closed(c) => {
+            session.persist();
+            if let AnySink::DualSink(sink) = session.sink() {
+                sink.dedup.flush();
+            }
+            session.pair.downstream_closed();
+            Ok(c.ack())
+        }
+        v5::Control::PeerGone(c) => Ok(c.ack()),
+        // TODO: Back pressure
+        v5::Control::WrBackpressure(w) => Ok(w.ack()),
+    }
+}
+
+pub(crate) async fn handle_upstream_control_v5(
+    control: v5::client::Control<ServerError>,
+    session: SessionState<v5::MqttSink>,
+) -> Result<v5::ControlAck, ServerError> {
+    match control {
+        v5::client::Control::Disconnect(d) => {
+            info!(
+                "Received TCP disconnect from backend: client_id={}, reason={:?}",
+                session.client_id,
+                d.packet().reason_code
+            );
+            // A backend going away is failed over, anything else is the client's business.
+            if !is_failover_reason(d.packet().reason_code) {
+                session.source.close_with_reason(d.packet().clone());
+            }
+            Ok(d.ack())
+        }
+        // The session fails over to another backend once the connection is gone.
+        v5::client::Control::Closed(c) => Ok(c.ack()),
+        v5::client::Control::Error(error) => {
+            error!(
+                "Server error: clientId: {}, {:?}",
+                session.client_id,
+                error.get_ref()
+            );
+            Ok(error.ack(v5::codec::DisconnectReasonCode::UnspecifiedError))
+        }
+        v5::client::Control::ProtocolError(error) => {
+            error!(
+                "Protocol error: clientId: {}, {}",
+                session.client_id,
+                error.get_ref()
+            );
+            Ok(error.ack())
+        }
+        v5::client::Control::PeerGone(p) => {
+            error!(
+                "Peer gone error: clientId: {}, {:?}",
+                session.client_id,
+                p.error()
+            );
+            Ok(p.ack())
+        }
+        v5::client::Control::Publish(publish) => handle_upstream_pub_v5(publish, session).await,
+    }
+}
+
+fn is_failover_reason(code: v5::codec::DisconnectReasonCode) -> bool {
+    matches!(
+        code,
+        v5::codec::DisconnectReasonCode::ServerShuttingDown
+            | v5::codec::DisconnectReasonCode::ServerBusy
+            | v5::codec::DisconnectReasonCode::UseAnotherServer
+            | v5::codec::DisconnectReasonCode::ServerMoved
+    )
+}
+
+pub(crate) async fn handle_dual_connect_v5(
+    handshake: v5::Handshake,
+    identity: Identity,
+) -> Result<v5::HandshakeAck<SessionState<v5::MqttSink>>, ServerError> {
+    let client_id = handshake.packet().client_id.to_string();
+    let select = |pool: &LoadBalancer<Consistent>| {
+        pool.select(client_id.as_bytes(), 1)
+            .map(|backend| backend.addr.to_string())
+            .ok_or_else(|| {
+                error!("No dual backend found for client ID: {}", client_id);
+                ServerError::Internal
+            })
+    };
+    let primary_sink_address = select(&DUAL_PRIMARY)?;
+    let secondary_sink_address = select(&DUAL_SECONDARY)?;
+
+    let mut connect = handshake.packet().clone();
+    // Topic aliases and enhanced auth are per connection, the gateway does not relay them.
+    connect.topic_alias_max = 0;
+    connect.auth_method = None;
+    connect.auth_data = None;
+    let connector = |address: &str| {
+        v5::client::MqttConnector::new(address.to_string()).packet(|pkt| {
+            *pkt = connect.clone();
+            pkt.keep_alive = upstream_keep_alive(pkt.keep_alive);
+        })
+    };
+
+    let primary_client = match connector(&primary_sink_address).connect().await {
+        Ok(client) => client,
+        Err(ClientError::Ack(ack)) => {
+            error!(
+                "Backend {} refused connection: client_id={}, reason={:?}",
+                primary_sink_address, client_id, ack.reason_code
+            );
+            return Ok(handshake.fail_with(*ack));
+        }
+        Err(e) => {
+            error!(
+                "TCP connection to backend {} failed: {}",
+                primary_sink_address, e
+            );
+            return Err(ServerError::Internal);
+        }
+    };
+    // The client only sees the primary backend, so its connack is the one reported back.
+    let upstream_ack = primary_client.packet().clone();
+
+    let secondary_client = connector(&secondary_sink_address)
+        .connect()
+        .await
+        .map_err(|e| {
+            error!(
+                "TCP connection to backend {} failed: {}",
+                secondary_sink_address, e
+            );
+            // Nothing reaches the primary backend anymore, tell it so instead of timing out.
+            primary_client.sink().close();
+            ServerError::Internal
+        })?;
+
+    let dual_sink = DualSink::new(
+        client_id.clone(),
+        primary_client.sink(),
+        secondary_client.sink(),
+    );
+
+    let stored = load_session(
+        identity.tenant_name(),
+        &client_id,
+        handshake.packet().clean_start,
+    );
+    let session_state = SessionState {
+        client_id: client_id.clone(),
+        username: identity.username,
+        certificate: identity.certificate.map(Rc::new),
+        tenant: identity.tenant,
+        subscriptions: SubscriptionRegistry::default(),
+        source: handshake.sink(),
+        sink: Rc::new(RefCell::new(AnySink::DualSink(dual_sink))),
+        upstream_inflight: InflightWindow::default(),
+        downstream_inflight: InflightWindow::default(),
+        upstream_dedup: PublishDedup::default(),
+        persistent: !handshake.packet().clean_start && !client_id.is_empty(),
+        offline: Rc::default(),
+        pair: ConnectionPair::default(),
+    };
+    session_state.pair.register(
+        session_state.tenant_name(),
+        &client_id,
+        session_state.certificate.clone(),
+        session_state.clone(),
+    );
+
+    let handle_upstream = |side: DualSide,
+                           packet: v5::client::Control<ServerError>,
+                           session: SessionState<v5::MqttSink>| async move {
+        match packet {
+            v5::client::Control::Publish(publish) => {
+                handle_dual_upstream_pub_v5(publish, session, side).await
+            }
+            _ => handle_upstream_control_v5(packet, session).await,
+        }
+    };
 
     let session_clone1 = session_state.clone();
-    let session_clone2 = session_state.clone();
-    ntex::rt::spawn_fn(move || {
-        primary_client.start(fn_service(
-            move |packet: v3::client::Control<ServerError>| {
-                handle_upstream(packet, session_clone1.clone())
-            },
-        ))
+    // Packet ids are per connection, so each backend needs its own dedup state.
+    let session_clone2 = SessionState {
+        upstream_dedup: PublishDedup::default(),
+        ..session_state.clone()
+    };
+    ntex::rt::spawn(async move {
+        let pair = session_clone1.pair.clone();
+        let _ = primary_client
+            .start(fn_service(
+                move |packet: v5::client::Control<ServerError>| {
+                    handle_upstream(DualSide::Primary, packet, session_clone1.clone())
+                },
+            ))
+            .await;
+        // Dual sessions do not fail over, losing either backend ends the session.
+        pair.upstream_closed();
     });
 
-    ntex::rt::spawn_fn(move || {
-        secondary_client.start(fn_service(
-            move |packet: v3::client::Control<ServerError>| {
-                handle_upstream(packet, session_clone2.clone())
-            },
-        ))
+    ntex::rt::spawn(async move {
+        let pair = session_clone2.pair.clone();
+        let _ = secondary_client
+            .start(fn_service(
+                move |packet: v5::client::Control<ServerError>| {
+                    handle_upstream(DualSide::Secondary, packet, session_clone2.clone())
+                },
+            ))
+            .await;
+        pair.upstream_closed();
     });
 
+    let resumed = resume_session(&session_state, stored, upstream_ack.session_present).await?;
+
     info!(
-        "New MQTT v3 TCP connection established: client_id={}",
-        client_id
+        "New MQTT v5 TCP connection established: client_id={}, certificate={:?}",
+        client_id,
+        session_state.certificate.as_ref().map(|cert| &cert.subject)
     );
     debug!("Connection details: handshake received");
-    Ok(handshake.ack(session_state, false))
+    Ok(handshake.ack(session_state).with(|ack| {
+        ack.session_present = upstream_ack.session_present || resumed;
+        ack.assigned_client_id = upstream_ack.assigned_client_id;
+        ack.session_expiry_interval_secs = upstream_ack.session_expiry_interval_secs;
+        ack.receive_max = upstream_ack.receive_max;
+        ack.max_packet_size = upstream_ack.max_packet_size;
+    }))
 }
 
+async fn handle_dual_upstream_pub_v5(
+    publish: v5::client::control::Publish,
+    session: SessionState<v5::MqttSink>,
+    side: DualSide,
+) -> Result<v5::ControlAck, ServerError> {
+    debug!(
+        "Incoming MQTT v5 publish over TCP from backend: packet_id={:?}, topic={} -> client_id={}",
+        publish.packet().packet_id,
+        publish.packet().topic,
+        session.client_id
+    );
 
+    let packet_id = publish.packet().packet_id;
+    if !session
+        .upstream_dedup
+        .receive(packet_id, publish.packet().dup)
+        .await
+    {
+        debug!(
+            "Acking redelivered publish without forwarding it: packet_id={:?}, client_id={}",
+            packet_id, session.client_id
+        );
+        return Ok(publish.ack(v5::codec::PublishAckReason::Success));
+    }
 
+    let key = DualDedup::key(
+        &publish.packet().topic,
+        &publish.packet().payload,
+        publish.packet().properties.correlation_data.as_deref(),
+    );
+    if let AnySink::DualSink(sink) = session.sink()
+        && !sink.dedup.admit(side, key, &publish.packet().topic).await
+    {
+        debug!(
+            "Acking publish the other backend delivered without forwarding it: side={:?}, \
+             topic={}, client_id={}",
+            side,
+            publish.packet().topic,
+            session.client_id
+        );
+        session.upstream_dedup.complete(packet_id, true);
+        return Ok(publish.ack(v5::codec::PublishAckReason::Success));
+    }
 
-async fn handle_dual_upstream_pub(
+    forward_upstream_pub_v5(publish, session).await
+}
+
+/// Connects a MQTT v3 client to a MQTT v5 backend.
+pub(crate) async fn handle_bridge_connect(
+    handshake: v3::Handshake,
+    identity: Identity,
+) -> Result<v3::HandshakeAck<SessionState<v3::MqttSink>>, ServerError> {
+    let client_id = handshake.packet().client_id.to_string();
+
+    let backend = backend_pool(identity.tenant.as_deref())
+        .select(client_id.as_bytes(), 1)
+        .ok_or_else(|| {
+            error!("No backend found for client ID: {}", client_id);
+            ServerError::Internal
+        })?;
+
+    let client = match v5::client::MqttConnector::new(backend.addr.to_string())
+        .packet(|pkt| {
+            *pkt = bridge::connect_to_v5(handshake.packet());
+            pkt.keep_alive = upstream_keep_alive(pkt.keep_alive);
+        })
+        .connect()
+        .await
+    {
+        Ok(client) => client,
+        Err(ClientError::Ack(ack)) => {
+            error!(
+                "Backend {} refused bridged connection: client_id={}, reason={:?}",
+                backend.addr, client_id, ack.reason_code
+            );
+            return Ok(bridge::reject_v3(
+                handshake,
+                bridge::connect_code_to_v3(ack.reason_code),
+            ));
+        }
+        Err(e) => {
+            error!("TCP connection to backend {} failed: {}", backend.addr, e);
+            return Err(ServerError::Internal);
+        }
+    };
+    let session_present = client.packet().session_present;
+
+    let stored = load_session(
+        identity.tenant_name(),
+        &client_id,
+        handshake.packet().clean_session,
+    );
+    let session_state = SessionState {
+        client_id: client_id.clone(),
+        username: identity.username,
+        certificate: identity.certificate.map(Rc::new),
+        tenant: identity.tenant,
+        subscriptions: SubscriptionRegistry::default(),
+        source: handshake.sink(),
+        sink: Rc::new(RefCell::new(AnySink::Bridged(client.sink()))),
+        upstream_inflight: InflightWindow::default(),
+        downstream_inflight: InflightWindow::default(),
+        upstream_dedup: PublishDedup::default(),
+        persistent: !handshake.packet().clean_session && !client_id.is_empty(),
+        offline: Rc::default(),
+        pair: ConnectionPair::default(),
+    };
+    session_state.pair.register(
+        session_state.tenant_name(),
+        &client_id,
+        session_state.certificate.clone(),
+        session_state.clone(),
+    );
+
+    let session_clone = session_state.clone();
+    ntex::rt::spawn(async move {
+        let pair = session_clone.pair.clone();
+        let _ = client
+            .start(fn_service(
+                move |packet: v5::client::Control<ServerError>| {
+                    handle_bridge_upstream_control(packet, session_clone.clone())
+                },
+            ))
+            .await;
+        pair.upstream_closed();
+    });
+
+    let resumed = resume_session(&session_state, stored, session_present).await?;
+
+    info!(
+        "New MQTT v3 TCP connection bridged to MQTT v5 backend: client_id={}, certificate={:?}",
+        client_id,
+        session_state.certificate.as_ref().map(|cert| &cert.subject)
+    );
+    Ok(handshake.ack(session_state, session_present || resumed))
+}
+
+/// Connects a MQTT v5 client to a MQTT v3 backend.
+pub(crate) async fn handle_bridge_connect_v5(
+    handshake: v5::Handshake,
+    identity: Identity,
+) -> Result<v5::HandshakeAck<SessionState<v5::MqttSink>>, ServerError> {
+    let client_id = handshake.packet().client_id.to_string();
+
+    let backend = backend_pool(identity.tenant.as_deref())
+        .select(client_id.as_bytes(), 1)
+        .ok_or_else(|| {
+            error!("No backend found for client ID: {}", client_id);
+            ServerError::Internal
+        })?;
+
+    let client = match v3::client::MqttConnector::new(backend.addr.to_string())
+        .packet(|pkt| {
+            *pkt = bridge::connect_to_v3(handshake.packet());
+            pkt.keep_alive = upstream_keep_alive(pkt.keep_alive);
+        })
+        .connect()
+        .await
+    {
+        Ok(client) => client,
+        Err(ClientError::Ack(ack)) => {
+            error!(
+                "Backend {} refused bridged connection: client_id={}, reason={:?}",
+                backend.addr, client_id, ack.return_code
+            );
+            return Ok(handshake.failed(bridge::connect_code_to_v5(ack.return_code)));
+        }
+        Err(e) => {
+            error!("TCP connection to backend {} failed: {}", backend.addr, e);
+            return Err(ServerError::Internal);
+        }
+    };
+    let session_present = client.session_present();
+
+    let stored = load_session(
+        identity.tenant_name(),
+        &client_id,
+        handshake.packet().clean_start,
+    );
+    let session_state = SessionState {
+        client_id: client_id.clone(),
+        username: identity.username,
+        certificate: identity.certificate.map(Rc::new),
+        tenant: identity.tenant,
+        subscriptions: SubscriptionRegistry::default(),
+        source: handshake.sink(),
+        sink: Rc::new(RefCell::new(AnySink::Bridged(client.sink()))),
+        upstream_inflight: InflightWindow::default(),
+        downstream_inflight: InflightWindow::default(),
+        upstream_dedup: PublishDedup::default(),
+        persistent: !handshake.packet().clean_start && !client_id.is_empty(),
+        offline: Rc::default(),
+        pair: ConnectionPair::default(),
+    };
+    session_state.pair.register(
+        session_state.tenant_name(),
+        &client_id,
+        session_state.certificate.clone(),
+        session_state.clone(),
+    );
+
+    let session_clone = session_state.clone();
+    ntex::rt::spawn(async move {
+        let pair = session_clone.pair.clone();
+        let _ = client
+            .start(fn_service(
+                move |packet: v3::client::Control<ServerError>| {
+                    handle_bridge_upstream_control_v5(packet, session_clone.clone())
+                },
+            ))
+            .await;
+        pair.upstream_closed();
+    });
+
+    let resumed = resume_session(&session_state, stored, session_present).await?;
+
+    info!(
+        "New MQTT v5 TCP connection bridged to MQTT v3 backend: client_id={}, certificate={:?}",
+        client_id,
+        session_state.certificate.as_ref().map(|cert| &cert.subject)
+    );
+    Ok(handshake
+        .ack(session_state)
+        .with(|ack| ack.session_present = session_present || resumed))
+}
+
+async fn handle_bridge_downstream_pub(
+    mut publish: v3::Publish,
+    sink: &v5::MqttSink,
+    inflight: &InflightWindow,
+) -> Result<(), ServerError> {
+    let topic = publish.topic().get_ref().clone();
+    let payload = publish.take_payload();
+    let new_packet_builder = || {
+        sink.publish(topic.clone(), payload.clone())
+            .retain(publish.retain())
+    };
+
+    if let QoS::AtMostOnce = publish.qos() {
+        new_packet_builder()
+            .send_at_most_once()
+            .map_err(|_| ServerError::Internal)
+    } else {
+        let ack = inflight
+            .deliver(&topic, &payload, |packet_id, dup| {
+                new_packet_builder()
+                    .packet_id(packet_id)
+                    .dup(dup)
+                    .send_at_least_once()
+            })
+            .await?;
+
+        // MQTT 3.1.1 PUBACK has no reason code, the only way to report a refused publish is to
+        // drop the connection.
+        if bridge::is_success(ack.reason_code) {
+            Ok(())
+        } else {
+            error!(
+                "Backend refused bridged publish: topic={:?}, reason={:?}",
+                publish.topic(),
+                ack.reason_code
+            );
+            Err(ServerError::Internal)
+        }
+    }
+}
+
+async fn handle_bridge_downstream_pub_v5(
+    mut publish: v5::Publish,
+    sink: &v3::MqttSink,
+    inflight: &InflightWindow,
+) -> Result<v5::PublishAck, ServerError> {
+    let topic = publish.topic().get_ref().clone();
+    let payload = publish.take_payload();
+    let new_packet_builder = || {
+        let builder = sink.publish(topic.clone(), payload.clone());
+        if publish.retain() {
+            builder.retain()
+        } else {
+            builder
+        }
+    };
+
+    if let QoS::AtMostOnce = publish.qos() {
+        new_packet_builder()
+            .send_at_most_once()
+            .map(|_| publish.ack())
+            .map_err(|_| ServerError::Internal)
+    } else {
+        inflight
+            .deliver(&topic, &payload, |packet_id, dup| {
+                new_packet_builder()
+                    .packet_id(packet_id)
+                    .dup(dup)
+                    .send_at_least_once()
+            })
+            .await
+            .map(|_| publish.ack())
+    }
+}
+
+async fn handle_bridge_upstream_pub(
+    publish: v5::client::control::Publish,
+    session: SessionState<v3::MqttSink>,
+) -> Result<v5::ControlAck, ServerError> {
+    debug!(
+        "Incoming MQTT v5 publish over TCP from backend: packet_id={:?}, topic={} -> client_id={}",
+        publish.packet().packet_id,
+        publish.packet().topic,
+        session.client_id
+    );
+
+    let packet_id = publish.packet().packet_id;
+    if !session
+        .upstream_dedup
+        .receive(packet_id, publish.packet().dup)
+        .await
+    {
+        debug!(
+            "Acking redelivered publish without forwarding it: packet_id={:?}, client_id={}",
+            packet_id, session.client_id
+        );
+        return Ok(publish.ack(v5::codec::PublishAckReason::Success));
+    }
+
+    let new_packet_builder = || {
+        let builder = session.source.publish(
+            publish.packet().topic.clone(),
+            publish.packet().payload.clone(),
+        );
+        if publish.packet().retain {
+            builder.retain()
+        } else {
+            builder
+        }
+    };
+
+    // Publishes still arrive until the backend side of a closed client is torn down, keep them.
+    let queued = !session.source.is_open()
+        && session.queue_offline(
+            &publish.packet().topic,
+            &publish.packet().payload,
+            publish.packet().qos,
+        );
+
+    let result = if queued {
+        Ok(publish.ack(v5::codec::PublishAckReason::Success))
+    } else if let QoS::AtMostOnce = publish.packet().qos {
+        new_packet_builder()
+            .send_at_most_once()
+            .map(|_| publish.ack_qos0())
+            .map_err(|_| ServerError::Internal)
+    } else {
+        session
+            .downstream_inflight
+            .deliver(
+                &publish.packet().topic,
+                &publish.packet().payload,
+                |packet_id, dup| {
+                    new_packet_builder()
+                        .packet_id(packet_id)
+                        .dup(dup)
+                        .send_at_least_once()
+                },
+            )
+            .await
+            .map(|_| publish.ack(v5::codec::PublishAckReason::Success))
+    };
+    session.upstream_dedup.complete(packet_id, result.is_ok());
+    result
+}
+
+async fn handle_bridge_upstream_pub_v5(
     publish: v3::client::control::Publish,
-    session: SessionState<v3::MqttSink >,
+    session: SessionState<v5::MqttSink>,
 ) -> Result<v3::ControlAck, ServerError> {
     debug!(
         "Incoming MQTT v3 publish over TCP from backend: packet_id={:?}, topic={} -> client_id={}",
@@ -316,28 +1630,277 @@ async fn handle_dual_upstream_pub(
         session.client_id
     );
 
-    // TODO: Return a specific error for duplicate publish attempts, preventing the release of inflight counter.
-    if publish.packet().dup {
-        return Err(ServerError);
+    let packet_id = publish.packet().packet_id;
+    if !session
+        .upstream_dedup
+        .receive(packet_id, publish.packet().dup)
+        .await
+    {
+        debug!(
+            "Acking redelivered publish without forwarding it: packet_id={:?}, client_id={}",
+            packet_id, session.client_id
+        );
+        return Ok(publish.ack());
     }
 
-    let new_packet_builder = session.source.publish(
-        publish.packet().topic.clone(),
-        publish.packet().payload.clone(),
-    );
+    let subscription_ids = session.subscription_ids(&publish.packet().topic);
+    let new_packet_builder = || {
+        session
+            .source
+            .publish(
+                publish.packet().topic.clone(),
+                publish.packet().payload.clone(),
+            )
+            .retain(publish.packet().retain)
+            .properties(|props| props.subscription_ids = subscription_ids.clone())
+    };
 
-    if let QoS::AtMostOnce = publish.packet().qos {
-        new_packet_builder
+    // Publishes still arrive until the backend side of a closed client is torn down, keep them.
+    let queued = !session.source.is_open()
+        && session.queue_offline(
+            &publish.packet().topic,
+            &publish.packet().payload,
+            publish.packet().qos,
+        );
+
+    let result = if queued {
+        Ok(publish.ack())
+    } else if let QoS::AtMostOnce = publish.packet().qos {
+        new_packet_builder()
             .send_at_most_once()
             .map(|_| publish.ack())
-            .map_err(|_| ServerError)
+            .map_err(|_| ServerError::Internal)
     } else {
-        // TODO: spawn a task to schedule retry.
-        // Wait for PUBACK
-        new_packet_builder
-            .send_at_least_once()
+        // A refused publish cannot be reported to a MQTT 3.1.1 backend, so it is acked anyway.
+        session
+            .downstream_inflight
+            .deliver(
+                &publish.packet().topic,
+                &publish.packet().payload,
+                |packet_id, dup| {
+                    new_packet_builder()
+                        .packet_id(packet_id)
+                        .dup(dup)
+                        .send_at_least_once()
+                },
+            )
             .await
-            .map(|_| publish.ack())
-            .map_err(|_| ServerError)
+            .map(|ack| {
+                if !bridge::is_success(ack.reason_code) {
+                    error!(
+                        "Client {} refused bridged publish: topic={}, reason={:?}",
+                        session.client_id,
+                        publish.packet().topic,
+                        ack.reason_code
+                    );
+                }
+                publish.ack()
+            })
+    };
+    session.upstream_dedup.complete(packet_id, result.is_ok());
+    result
+}
+
+pub(crate) async fn handle_bridge_upstream_control(
+    control: v5::client::Control<ServerError>,
+    session: SessionState<v3::MqttSink>,
+) -> Result<v5::ControlAck, ServerError> {
+    match control {
+        v5::client::Control::Publish(publish) => handle_bridge_upstream_pub(publish, session).await,
+        v5::client::Control::Disconnect(d) => {
+            // MQTT 3.1.1 servers cannot send DISCONNECT, just close the client connection.
+            info!(
+                "Received TCP disconnect from backend: client_id={}, reason={:?}",
+                session.client_id,
+                d.packet().reason_code
+            );
+            session.source.close();
+            Ok(d.ack())
+        }
+        v5::client::Control::Closed(c) => {
+            session.source.close();
+            Ok(c.ack())
+        }
+        v5::client::Control::Error(error) => {
+            session.source.close();
+            error!(
+                "Server error: clientId: {}, {:?}",
+                session.client_id,
+                error.get_ref()
+            );
+            Ok(error.ack(v5::codec::DisconnectReasonCode::UnspecifiedError))
+        }
+        v5::client::Control::ProtocolError(error) => {
+            session.source.close();
+            error!(
+                "Protocol error: clientId: {}, {}",
+                session.client_id,
+                error.get_ref()
+            );
+            Ok(error.ack())
+        }
+        v5::client::Control::PeerGone(p) => {
+            session.source.close();
+            error!(
+                "Peer gone error: clientId: {}, {:?}",
+                session.client_id,
+                p.error()
+            );
+            Ok(p.ack())
+        }
+    }
+}
+
+pub(crate) async fn handle_bridge_upstream_control_v5(
+    control: v3::client::Control<ServerError>,
+    session: SessionState<v5::MqttSink>,
+) -> Result<v3::ControlAck, ServerError> {
+    // Tell the MQTT v5 client why the connection is going away.
+    let disconnect = |reason_code| {
+        session
+            .source
+            .close_with_reason(v5::codec::Disconnect::new(reason_code))
+    };
+
+    match control {
+        v3::client::Control::Publish(publish) => {
+            handle_bridge_upstream_pub_v5(publish, session).await
+        }
+        v3::client::Control::Closed(c) => {
+            disconnect(v5::codec::DisconnectReasonCode::ServerShuttingDown);
+            Ok(c.ack())
+        }
+        v3::client::Control::Error(error) => {
+            disconnect(v5::codec::DisconnectReasonCode::UnspecifiedError);
+            error!(
+                "Server error: clientId: {}, {:?}",
+                session.client_id,
+                error.get_ref()
+            );
+            Ok(error.ack())
+        }
+        v3::client::Control::ProtocolError(error) => {
+            disconnect(v5::codec::DisconnectReasonCode::ProtocolError);
+            error!(
+                "Protocol error: clientId: {}, {}",
+                session.client_id,
+                error.get_ref()
+            );
+            Ok(error.ack())
+        }
+        v3::client::Control::PeerGone(p) => {
+            disconnect(v5::codec::DisconnectReasonCode::ServerShuttingDown);
+            error!(
+                "Peer gone error: clientId: {}, {:?}",
+                session.client_id,
+                p.err()
+            );
+            Ok(p.ack())
+        }
     }
 }
+
+/// Picks a healthy backend for a session whose backend failed, preferring any other backend.
+/// Health checks lag behind, so the failed backend is only used when it is the last one left.
+fn select_failover_backend<Source: Bridge>(
+    session: &SessionState<Source>,
+    failed: &str,
+) -> Option<Backend> {
+    let client_id = session.client_id.as_bytes();
+    let upstream = backend_pool(session.tenant.as_deref());
+    upstream
+        .select_with(client_id, FAILOVER_SELECT_ITERATIONS, |backend, healthy| {
+            healthy && backend.addr.to_string() != failed
+        })
+        .or_else(|| upstream.select(client_id, FAILOVER_SELECT_ITERATIONS))
+}
+
+/// Keep-alive of a backend connection, the client's own unless one is configured.
+fn upstream_keep_alive(client: u16) -> u16 {
+    CONFIG.upstream.keep_alive.unwrap_or(client)
+}
+
+/// Loads the session a client asks to resume, connecting with a clean session discards it.
+/// Clients without a client id have no session to resume.
+fn load_session(
+    tenant: Option<&str>,
+    client_id: &str,
+    clean_session: bool,
+) -> Option<StoredSession> {
+    if client_id.is_empty() {
+        return None;
+    }
+    let loaded = if clean_session {
+        SESSION_STORE.remove(tenant, client_id).map(|_| None)
+    } else {
+        SESSION_STORE.load(tenant, client_id)
+    };
+    loaded.unwrap_or_else(|e| {
+        error!("Failed to load session of client {}: {}", client_id, e);
+        None
+    })
+}
+
+/// Resumes a stored session. The backend is subscribed again unless it kept the session itself,
+/// and the publishes the client missed are redelivered once it is connected.
+async fn resume_session<Source: Bridge + Clone + 'static>(
+    session: &SessionState<Source>,
+    stored: Option<StoredSession>,
+    backend_session_present: bool,
+) -> Result<bool, ServerError> {
+    let Some(stored) = stored else {
+        return Ok(false);
+    };
+
+    let missed = session.restore(stored);
+    session.offline.borrow_mut().extend(missed);
+    if !backend_session_present && let Err(e) = Source::resubscribe(session).await {
+        // The handshake fails, so the client connection never gets to close the pair.
+        session.pair.downstream_closed();
+        return Err(e);
+    }
+
+    let session = session.clone();
+    ntex::rt::spawn(async move {
+        loop {
+            let Some(message) = session.offline.borrow().first().cloned() else {
+                break;
+            };
+            let topic = ByteString::from(message.topic);
+            let payload = Bytes::from(message.payload);
+            let delivered = session
+                .downstream_inflight
+                .deliver(&topic, &payload, |packet_id, dup| {
+                    session.source.send_at_least_once(
+                        topic.clone(),
+                        payload.clone(),
+                        packet_id,
+                        dup,
+                    )
+                })
+                .await;
+            // Anything left stays queued for the next connection.
+            if delivered.is_err() {
+                break;
+            }
+            session.offline.borrow_mut().remove(0);
+            session.persist();
+        }
+    });
+    Ok(true)
+}
+
+/// Copies the application level PUBLISH properties to the packet sent on the other side of the
+/// gateway. Topic aliases and subscription identifiers are scoped to a single connection and
+/// are not forwarded, the client's identifiers come from its session instead.
+fn forward_publish_properties(
+    src: &v5::codec::PublishProperties,
+    dst: &mut v5::codec::PublishProperties,
+) {
+    dst.user_properties = src.user_properties.clone();
+    dst.content_type = src.content_type.clone();
+    dst.response_topic = src.response_topic.clone();
+    dst.correlation_data = src.correlation_data.clone();
+    dst.is_utf8_payload = src.is_utf8_payload;
+    dst.message_expiry_interval = src.message_expiry_interval;
+}
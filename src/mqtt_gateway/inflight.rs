@@ -0,0 +1,369 @@
+use super::CONFIG;
+use super::config::Inflight as Limits;
+use super::error::ServerError;
+use futures::StreamExt;
+use futures::stream::FuturesUnordered;
+use log::warn;
+use ntex::channel::condition::Condition;
+use ntex::time::{Seconds, sleep, timeout};
+use ntex::util::{ByteString, Bytes};
+use ntex_mqtt::error::SendPacketError;
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::future::Future;
+use std::rc::Rc;
+
+#[derive(Debug)]
+struct Inflight {
+    topic: ByteString,
+    payload: Bytes,
+    /// Packet id every attempt is sent with, so a resend is a DUP of the same message.
+    packet_id: u16,
+    attempts: u16,
+}
+
+#[derive(Debug, Default)]
+struct Inner {
+    next_id: u64,
+    next_packet_id: u16,
+    messages: BTreeMap<u64, Inflight>,
+}
+
+impl Inner {
+    /// A packet id no other publish of the window uses.
+    fn allocate_packet_id(&mut self) -> u16 {
+        loop {
+            self.next_packet_id = self.next_packet_id.checked_add(1).unwrap_or(1);
+            let id = self.next_packet_id;
+            if !self
+                .messages
+                .values()
+                .any(|message| message.packet_id == id)
+            {
+                return id;
+            }
+        }
+    }
+}
+
+/// Bounded set of QoS 1 publishes that have been forwarded but not acknowledged yet. Clones share
+/// the same window.
+#[derive(Debug, Clone)]
+pub struct InflightWindow {
+    inner: Rc<RefCell<Inner>>,
+    released: Condition,
+    max: usize,
+    retry_interval: Seconds,
+    max_retries: u16,
+}
+
+impl Default for InflightWindow {
+    fn default() -> Self {
+        Self::new(&CONFIG.inflight)
+    }
+}
+
+/// Frees the slot even if the delivery future is dropped, e.g. when the client disconnects.
+struct Reservation<'a> {
+    window: &'a InflightWindow,
+    id: u64,
+}
+
+impl Drop for Reservation<'_> {
+    fn drop(&mut self) {
+        self.window.inner.borrow_mut().messages.remove(&self.id);
+        self.window.released.notify();
+    }
+}
+
+impl InflightWindow {
+    pub fn new(limits: &Limits) -> Self {
+        Self {
+            inner: Rc::default(),
+            released: Condition::default(),
+            max: limits.max,
+            retry_interval: limits.retry_interval(),
+            max_retries: limits.max_retries,
+        }
+    }
+
+    async fn reserve(&self, topic: &ByteString, payload: &Bytes) -> Reservation<'_> {
+        loop {
+            {
+                let mut inner = self.inner.borrow_mut();
+                if inner.messages.len() < self.max {
+                    let id = inner.next_id;
+                    inner.next_id += 1;
+                    let packet_id = inner.allocate_packet_id();
+                    inner.messages.insert(
+                        id,
+                        Inflight {
+                            topic: topic.clone(),
+                            payload: payload.clone(),
+                            packet_id,
+                            attempts: 0,
+                        },
+                    );
+                    return Reservation { window: self, id };
+                }
+            }
+            self.released.wait().await;
+        }
+    }
+
+    /// Sends a publish until it is acknowledged. `send` gets the packet id and the DUP flag, and
+    /// is called again with the same id whenever no ack arrives within the retry interval, up to
+    /// the configured number of retries. Earlier attempts are still waited for: while the
+    /// connection that carried them is up, the sink refuses the id and nothing is resent, so
+    /// only a replacement connection sees the DUP.
+    pub async fn deliver<F, Fut, T>(
+        &self,
+        topic: &ByteString,
+        payload: &Bytes,
+        mut send: F,
+    ) -> Result<T, ServerError>
+    where
+        F: FnMut(u16, bool) -> Fut,
+        Fut: Future<Output = Result<T, SendPacketError>>,
+    {
+        let reservation = self.reserve(topic, payload).await;
+        let retry_interval = self.retry_interval;
+        let mut attempts = FuturesUnordered::new();
+        attempts.push(send(self.packet_id(&reservation), false));
+        self.attempted(&reservation);
+
+        loop {
+            match timeout(retry_interval, attempts.next()).await {
+                Ok(Some(Ok(ack))) => return Ok(ack),
+                // An earlier attempt is still waiting for its ack on the same connection.
+                Ok(Some(Err(SendPacketError::PacketIdInUse(_)))) if !attempts.is_empty() => {
+                    continue;
+                }
+                Ok(Some(Err(SendPacketError::PacketIdInUse(_)))) => {
+                    // Taken by a request the gateway did not send through this window.
+                    let packet_id = self.reallocate(&reservation);
+                    attempts.push(send(packet_id, self.attempts(&reservation) > 1));
+                    continue;
+                }
+                Ok(Some(Err(e))) => {
+                    warn!(
+                        "Failed to deliver publish: topic={}, attempt={}, error={:?}",
+                        topic,
+                        self.attempts(&reservation),
+                        e
+                    );
+                    if !attempts.is_empty() {
+                        continue;
+                    }
+                    // Resending right away is pointless when the connection is gone.
+                    sleep(retry_interval).await;
+                }
+                Ok(None) => {}
+                Err(()) => warn!(
+                    "Publish not acknowledged in {:?}: topic={}, attempt={}",
+                    retry_interval,
+                    topic,
+                    self.attempts(&reservation)
+                ),
+            }
+
+            if self.attempts(&reservation) > self.max_retries {
+                return Err(ServerError::Internal);
+            }
+            attempts.push(send(self.packet_id(&reservation), true));
+            self.attempted(&reservation);
+        }
+    }
+
+    fn packet_id(&self, reservation: &Reservation<'_>) -> u16 {
+        self.inner.borrow().messages[&reservation.id].packet_id
+    }
+
+    fn attempts(&self, reservation: &Reservation<'_>) -> u16 {
+        self.inner.borrow().messages[&reservation.id].attempts
+    }
+
+    fn attempted(&self, reservation: &Reservation<'_>) {
+        let mut inner = self.inner.borrow_mut();
+        let message = inner
+            .messages
+            .get_mut(&reservation.id)
+            .expect("reserved message is tracked until delivered");
+        message.attempts += 1;
+    }
+
+    fn reallocate(&self, reservation: &Reservation<'_>) -> u16 {
+        let mut inner = self.inner.borrow_mut();
+        let packet_id = inner.allocate_packet_id();
+        inner
+            .messages
+            .get_mut(&reservation.id)
+            .expect("reserved message is tracked until delivered")
+            .packet_id = packet_id;
+        packet_id
+    }
+
+    /// Topic and payload of the publishes that are not acknowledged yet, oldest first.
+    pub(crate) fn pending(&self) -> Vec<(ByteString, Bytes)> {
+        self.inner
+            .borrow()
+            .messages
+            .values()
+            .map(|message| (message.topic.clone(), message.payload.clone()))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ntex::channel::oneshot;
+    use ntex::time::Millis;
+    use std::num::NonZeroU16;
+
+    fn window(max: usize, max_retries: u16) -> InflightWindow {
+        InflightWindow::new(&Limits {
+            max,
+            retry_secs: 1,
+            max_retries,
+        })
+    }
+
+    #[test]
+    fn packet_ids_skip_ids_in_use_and_wrap() {
+        let mut inner = Inner {
+            next_packet_id: u16::MAX - 1,
+            ..Default::default()
+        };
+        // The ids right after the last one allocated are taken, the next free one wraps around.
+        for (id, packet_id) in [(0, u16::MAX), (1, 1)] {
+            inner.messages.insert(
+                id,
+                Inflight {
+                    topic: ByteString::new(),
+                    payload: Bytes::new(),
+                    packet_id,
+                    attempts: 1,
+                },
+            );
+        }
+        assert_eq!(inner.allocate_packet_id(), 2);
+    }
+
+    #[ntex::test]
+    async fn acked_publish_leaves_the_window() {
+        let window = window(4, 3);
+        let sent = RefCell::new(Vec::new());
+        let result = window
+            .deliver(&"a/b".into(), &Bytes::from_static(b"x"), |id, dup| {
+                sent.borrow_mut().push((id, dup));
+                async { Ok(()) }
+            })
+            .await;
+        assert!(result.is_ok());
+        assert_eq!(*sent.borrow(), [(1, false)]);
+        assert!(window.pending().is_empty());
+    }
+
+    #[ntex::test]
+    async fn unacked_publish_is_resent_as_dup_with_the_same_id() {
+        let window = window(4, 3);
+        let sent = RefCell::new(Vec::new());
+        let result = window
+            .deliver(&"a/b".into(), &Bytes::new(), |id, dup| {
+                sent.borrow_mut().push((id, dup));
+                let acked = dup;
+                async move {
+                    if acked {
+                        Ok(())
+                    } else {
+                        // The first attempt never gets its ack.
+                        std::future::pending().await
+                    }
+                }
+            })
+            .await;
+        assert!(result.is_ok());
+        assert_eq!(*sent.borrow(), [(1, false), (1, true)]);
+    }
+
+    #[ntex::test]
+    async fn id_taken_outside_the_window_is_replaced() {
+        let window = window(4, 3);
+        let sent = RefCell::new(Vec::new());
+        let result = window
+            .deliver(&"a/b".into(), &Bytes::new(), |id, dup| {
+                sent.borrow_mut().push((id, dup));
+                async move {
+                    match id {
+                        1 => Err(SendPacketError::PacketIdInUse(NonZeroU16::MIN)),
+                        _ => Ok(()),
+                    }
+                }
+            })
+            .await;
+        assert!(result.is_ok());
+        assert_eq!(*sent.borrow(), [(1, false), (2, false)]);
+    }
+
+    #[ntex::test]
+    async fn delivery_gives_up_after_max_retries() {
+        let window = window(4, 1);
+        let attempts = RefCell::new(0);
+        let result: Result<(), _> = window
+            .deliver(&"a/b".into(), &Bytes::new(), |_, _| {
+                *attempts.borrow_mut() += 1;
+                async { Err(SendPacketError::Disconnected) }
+            })
+            .await;
+        assert!(result.is_err());
+        assert_eq!(*attempts.borrow(), 2);
+        assert!(window.pending().is_empty());
+    }
+
+    #[ntex::test]
+    async fn full_window_holds_publishes_back() {
+        let window = window(1, 3);
+        let (ack, acked) = oneshot::channel::<()>();
+        let mut acked = Some(acked);
+        let first = ntex::rt::spawn({
+            let window = window.clone();
+            async move {
+                window
+                    .deliver(&"first".into(), &Bytes::new(), |_, _| {
+                        let acked = acked.take();
+                        async move {
+                            match acked {
+                                Some(acked) => {
+                                    acked.await.map_err(|_| SendPacketError::Disconnected)
+                                }
+                                None => std::future::pending().await,
+                            }
+                        }
+                    })
+                    .await
+            }
+        });
+        sleep(Millis(10)).await;
+        let second = ntex::rt::spawn({
+            let window = window.clone();
+            async move {
+                window
+                    .deliver(&"second".into(), &Bytes::new(), |_, _| async { Ok(()) })
+                    .await
+            }
+        });
+        sleep(Millis(10)).await;
+        let pending: Vec<_> = window
+            .pending()
+            .into_iter()
+            .map(|(topic, _)| topic)
+            .collect();
+        assert_eq!(pending, ["first"]);
+
+        ack.send(()).unwrap();
+        assert!(first.await.unwrap().is_ok());
+        assert!(second.await.unwrap().is_ok());
+        assert!(window.pending().is_empty());
+    }
+}
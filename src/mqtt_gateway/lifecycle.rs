@@ -0,0 +1,282 @@
+use super::cert::ClientCertificate;
+use super::crl;
+use futures::channel::oneshot;
+use log::{debug, info, warn};
+use ntex::rt::Arbiter;
+use ntex::time::{Seconds, timeout};
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::rc::Rc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{LazyLock, Mutex};
+
+/// How long a new connection waits for the session it takes over to be closed and persisted.
+const TAKEOVER_TIMEOUT: Seconds = Seconds(5);
+
+/// Closes either side of a connection pair.
+pub trait Teardown {
+    fn close_downstream(&self);
+    fn close_upstream(&self);
+
+    /// Closes the client connection because another connection took the session over.
+    fn take_over(&self) {
+        self.close_downstream();
+    }
+}
+
+/// Tenant and client id a session is known by.
+type ClientKey = (Option<String>, String);
+
+struct Pair {
+    key: ClientKey,
+    certificate: Option<Rc<ClientCertificate>>,
+    teardown: Rc<dyn Teardown>,
+    /// Dropped with the pair, which tells a connection taking the session over that it is gone.
+    _closed: oneshot::Sender<()>,
+}
+
+/// Worker running the live session of a client.
+struct Owner {
+    pair_id: u64,
+    arbiter: Arbiter,
+    closed: oneshot::Receiver<()>,
+}
+
+thread_local! {
+    // Sessions never leave the worker thread that accepted the client.
+    static PAIRS: RefCell<HashMap<u64, Pair>> = RefCell::new(HashMap::new());
+}
+
+// Shared by all workers, so a client id is live on a single worker at a time.
+static OWNERS: LazyLock<Mutex<HashMap<ClientKey, Owner>>> = LazyLock::new(Mutex::default);
+static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+
+/// A client connection and the backend connections serving it. Whichever side terminates first
+/// closes the other one and drops the pair from the worker's registry, so neither side outlives
+/// the other. Clones refer to the same pair.
+#[derive(Debug, Clone)]
+pub struct ConnectionPair {
+    id: u64,
+}
+
+impl Default for ConnectionPair {
+    fn default() -> Self {
+        Self {
+            id: NEXT_ID.fetch_add(1, Ordering::Relaxed),
+        }
+    }
+}
+
+impl ConnectionPair {
+    pub fn register(
+        &self,
+        tenant: Option<&str>,
+        client_id: &str,
+        certificate: Option<Rc<ClientCertificate>>,
+        teardown: impl Teardown + 'static,
+    ) {
+        if certificate.is_some() {
+            crl::watch_sessions();
+        }
+
+        let key = (tenant.map(str::to_owned), client_id.to_owned());
+        let (closed_tx, closed_rx) = oneshot::channel();
+        // Clients without an id have nothing to take over, like in `take_over`.
+        if !client_id.is_empty() {
+            let owner = Owner {
+                pair_id: self.id,
+                arbiter: Arbiter::current(),
+                closed: closed_rx,
+            };
+            // Only a connection racing past `take_over` finds an owner left.
+            if let Some(previous) = OWNERS.lock().unwrap().insert(key.clone(), owner) {
+                previous.close();
+            }
+        }
+
+        let live = PAIRS.with_borrow_mut(|pairs| {
+            pairs.insert(
+                self.id,
+                Pair {
+                    key,
+                    certificate,
+                    teardown: Rc::new(teardown),
+                    _closed: closed_tx,
+                },
+            );
+            pairs.len()
+        });
+        debug!(
+            "Connection pair registered: client_id={}, live={}",
+            client_id, live
+        );
+    }
+
+    /// The client connection is gone, closes the backend side.
+    pub fn downstream_closed(&self) {
+        if let Some(pair) = self.take() {
+            pair.teardown.close_upstream();
+        }
+    }
+
+    /// The backend side is gone for good, closes the client connection.
+    pub fn upstream_closed(&self) {
+        if let Some(pair) = self.take() {
+            pair.teardown.close_downstream();
+        }
+    }
+
+    /// Closes both sides of every pair on this worker whose tenant and client certificate
+    /// match, returns how many were closed.
+    pub fn close_where(matches: impl Fn(Option<&str>, &ClientCertificate) -> bool) -> usize {
+        let closed: Vec<(u64, Pair)> = PAIRS.with_borrow_mut(|pairs| {
+            pairs
+                .extract_if(|_, pair| {
+                    pair.certificate
+                        .as_deref()
+                        .is_some_and(|cert| matches(pair.key.0.as_deref(), cert))
+                })
+                .collect()
+        });
+        for (id, pair) in &closed {
+            release(*id, &pair.key);
+            warn!(
+                "Closing connection pair: client_id={}, certificate={:?}",
+                pair.key.1,
+                pair.certificate.as_ref().map(|cert| &cert.subject)
+            );
+            pair.teardown.close_downstream();
+            pair.teardown.close_upstream();
+        }
+        closed.len()
+    }
+
+    fn take(&self) -> Option<Pair> {
+        // Removed before tearing down, so the other side's termination finds nothing left to do.
+        let (pair, live) = PAIRS.with_borrow_mut(|pairs| (pairs.remove(&self.id), pairs.len()));
+        if let Some(pair) = &pair {
+            release(self.id, &pair.key);
+            debug!(
+                "Connection pair closed: client_id={}, live={}",
+                pair.key.1, live
+            );
+        }
+        pair
+    }
+}
+
+impl Owner {
+    /// Asks the owning worker to close the client connection, the pair then tears down as for
+    /// any client that leaves.
+    fn close(&self) {
+        let id = self.pair_id;
+        self.arbiter.exec_fn(move || {
+            let teardown =
+                PAIRS.with_borrow(|pairs| pairs.get(&id).map(|pair| pair.teardown.clone()));
+            if let Some(teardown) = teardown {
+                teardown.take_over();
+            }
+        });
+    }
+}
+
+/// Drops the owner entry of a pair, unless another connection has taken the client id since.
+fn release(id: u64, key: &ClientKey) {
+    let mut owners = OWNERS.lock().unwrap();
+    if owners.get(key).is_some_and(|owner| owner.pair_id == id) {
+        owners.remove(key);
+    }
+}
+
+/// Closes the live session of a client, on whichever worker it runs, and waits until it is
+/// gone. A client id has a single connection at a time, and the old session has to be persisted
+/// before the new connection loads it.
+pub async fn take_over(tenant: Option<&str>, client_id: &str) {
+    if client_id.is_empty() {
+        return;
+    }
+    let key = (tenant.map(str::to_owned), client_id.to_owned());
+    let Some(owner) = OWNERS.lock().unwrap().remove(&key) else {
+        return;
+    };
+
+    info!("Taking over session: client_id={}", client_id);
+    owner.close();
+    // The sender is dropped rather than sent on, either outcome means the pair is gone.
+    if timeout(TAKEOVER_TIMEOUT, owner.closed).await.is_err() {
+        warn!(
+            "Session taken over did not close in time: client_id={}",
+            client_id
+        );
+    }
+}
+
+#[cfg(test)]
+pub(super) mod tests {
+    use super::*;
+    use ntex::time::{Millis, sleep};
+    use std::cell::Cell;
+
+    /// Records which sides of a pair were closed.
+    #[derive(Clone, Default)]
+    pub(in super::super) struct Closed {
+        pub downstream: Rc<Cell<bool>>,
+        pub upstream: Rc<Cell<bool>>,
+    }
+
+    impl Teardown for Closed {
+        fn close_downstream(&self) {
+            self.downstream.set(true);
+        }
+
+        fn close_upstream(&self) {
+            self.upstream.set(true);
+        }
+    }
+
+    fn is_live(pair: &ConnectionPair) -> bool {
+        PAIRS.with_borrow(|pairs| pairs.contains_key(&pair.id))
+    }
+
+    #[ntex::test]
+    async fn anonymous_clients_do_not_take_each_other_over() {
+        let (first, second) = (ConnectionPair::default(), ConnectionPair::default());
+        let (first_closed, second_closed) = (Closed::default(), Closed::default());
+        first.register(Some("anonymous"), "", None, first_closed.clone());
+        second.register(Some("anonymous"), "", None, second_closed.clone());
+        sleep(Millis(10)).await;
+
+        assert!(is_live(&first) && is_live(&second));
+        assert!(!first_closed.downstream.get() && !second_closed.downstream.get());
+        assert!(
+            !OWNERS
+                .lock()
+                .unwrap()
+                .contains_key(&(Some("anonymous".into()), String::new()))
+        );
+
+        first.downstream_closed();
+        assert!(first_closed.upstream.get());
+        assert!(is_live(&second));
+        second.downstream_closed();
+    }
+
+    #[ntex::test]
+    async fn same_client_id_takes_the_session_over() {
+        let (first, second) = (ConnectionPair::default(), ConnectionPair::default());
+        let first_closed = Closed::default();
+        first.register(Some("takeover"), "c1", None, first_closed.clone());
+        second.register(Some("takeover"), "c1", None, Closed::default());
+        sleep(Millis(10)).await;
+
+        assert!(first_closed.downstream.get());
+        second.downstream_closed();
+        first.downstream_closed();
+        assert!(
+            !OWNERS
+                .lock()
+                .unwrap()
+                .contains_key(&(Some("takeover".into()), "c1".into()))
+        );
+    }
+}
@@ -0,0 +1,24 @@
+use super::compare;
+use log::{error, info};
+use ntex::web::{self, App, HttpResponse};
+use std::net::SocketAddr;
+
+/// Serves the gateway metrics in the Prometheus text format on `GET /metrics`.
+pub(crate) async fn listen_metrics(addr: SocketAddr) -> std::io::Result<()> {
+    info!("Starting metrics server on {}", addr);
+    web::server(|| App::new().route("/metrics", web::get().to(metrics)))
+        .bind(addr)
+        .inspect_err(|e| error!("Failed to start metrics server on {}: {}", addr, e))?
+        .workers(1)
+        .run()
+        .await
+}
+
+async fn metrics() -> HttpResponse {
+    let mut body = String::new();
+    // Writing to a string does not fail.
+    let _ = compare::write_metrics(&mut body);
+    HttpResponse::Ok()
+        .content_type("text/plain; version=0.0.4")
+        .body(body)
+}
@@ -1,120 +1,132 @@
+// The unit tests do not run `main`, which everything else is reachable from.
+#![cfg_attr(test, allow(dead_code))]
+
+use self::acl::{TopicAcl, create_acl};
+use self::auth::{Authenticator, create_authenticator};
+use self::cert::{CertMapping, create_cert_mapping};
+use self::config::{Config, load_config};
 use self::dispatcher::{
     connect_v3, connect_v5, control_factory_v3, control_factory_v5, publish_factory_v3,
     publish_factory_v5,
 };
+use self::dual::{DualPolicy, create_dual_policy, create_dual_primary, create_dual_secondary};
 use self::error::ServerError;
 use self::middleware::RequestLogger;
-use ntex::tls::rustls::{PeerCert, TlsAcceptor, TlsServerFilter};
-use ntex::util::Ready;
-use ntex::{chain_factory, fn_service};
-use ntex_io::{Io, Layer};
-use ntex_mqtt::{v3, v5, MqttError, MqttServer};
-use pingora_load_balancing::selection::Consistent;
-use pingora_load_balancing::LoadBalancer;
-use rustls::server::WebPkiClientVerifier;
-use rustls::{RootCertStore, ServerConfig};
-use std::fs::File;
-use std::io::BufReader;
-use std::sync::{Arc, LazyLock};
-use x509_parser::certificate::X509Certificate;
-use x509_parser::prelude::FromDer;
+use self::store::{SessionStore, create_store};
+use self::tenant::{Tenants, create_tenants};
+use self::tls::{ReloadableConfig, ReloadingAcceptor, TlsSettings};
 use self::upstream::create_lb;
-use log::{info, error, debug};
 use env_logger;
+use log::{debug, error, info};
+use ntex::chain_factory;
+use ntex_mqtt::{MqttError, MqttServer, QoS, v3, v5};
+use pingora_load_balancing::LoadBalancer;
+use pingora_load_balancing::selection::Consistent;
+use std::sync::{Arc, LazyLock};
 
+mod acl;
+mod auth;
+mod bridge;
+mod cert;
+mod compare;
+mod config;
+mod crl;
+mod dedup;
 mod dispatcher;
+mod dual;
 mod error;
 mod handler;
+mod inflight;
+mod lifecycle;
+mod metrics;
 mod middleware;
+mod registry;
 mod session;
+mod store;
+mod tenant;
+mod tls;
 mod upstream;
-mod dual;
 
+static CONFIG: LazyLock<Config> = LazyLock::new(load_config);
 static UPSTREAM: LazyLock<Arc<LoadBalancer<Consistent>>> = LazyLock::new(create_lb);
+static SESSION_STORE: LazyLock<Arc<dyn SessionStore>> = LazyLock::new(create_store);
+static AUTHENTICATOR: LazyLock<Arc<dyn Authenticator>> = LazyLock::new(create_authenticator);
+static ACL: LazyLock<Arc<TopicAcl>> = LazyLock::new(create_acl);
+static CERT_MAPPING: LazyLock<CertMapping> = LazyLock::new(create_cert_mapping);
+static TENANTS: LazyLock<Tenants> = LazyLock::new(create_tenants);
+static DUAL: LazyLock<DualPolicy> = LazyLock::new(create_dual_policy);
+static DUAL_PRIMARY: LazyLock<Arc<LoadBalancer<Consistent>>> = LazyLock::new(create_dual_primary);
+static DUAL_SECONDARY: LazyLock<Arc<LoadBalancer<Consistent>>> =
+    LazyLock::new(create_dual_secondary);
+
+/// ntex-mqtt does not implement the PUBREC/PUBREL/PUBCOMP exchange on either the server or the
+/// client side, so the gateway cannot relay QoS 2 and advertises QoS 1 as its maximum instead.
+/// Its dispatchers answer a QoS 2 PUBLISH with a PUBACK and drop PUBREL packets, and its sinks
+/// have no way to send one. End-to-end exactly once delivery is blocked until the library, or a
+/// fork of it, handles the QoS 2 packets.
+const MAX_QOS: QoS = QoS::AtLeastOnce;
 
 async fn listen_tcp() -> std::io::Result<()> {
-    info!("Starting MQTT TCP server on 0.0.0.0:1884");
+    info!("Starting MQTT TCP server on {}", CONFIG.listeners.tcp);
     ntex::server::Server::build()
-        .bind("mqtt-gateway", "0.0.0.0:1884", move |_| {
+        .bind("mqtt-gateway", CONFIG.listeners.tcp, move |_| {
             debug!("Initializing MQTT v3 server");
             let mqtt_v3_server = v3::MqttServer::new(connect_v3)
                 .control(control_factory_v3())
                 .publish(publish_factory_v3())
+                .max_qos(MAX_QOS)
                 .middleware(RequestLogger)
                 // .middleware(fn_pub_ack_factory_v3())
                 // .middleware(fn_handle_packet_id())
-                // .middleware(fn_auth)
                 .finish();
 
             debug!("Initializing MQTT v5 server");
             let mqtt_v5_server = v5::MqttServer::new(connect_v5)
                 .control(control_factory_v5())
                 .publish(publish_factory_v5())
+                .max_qos(MAX_QOS)
                 .finish();
 
             MqttServer::new().v3(mqtt_v3_server).v5(mqtt_v5_server)
         })?
-        .workers(1)
+        .workers(CONFIG.workers)
         .run()
         .await
 }
 
 async fn listen_tls() -> std::io::Result<()> {
-    info!("Starting MQTT TLS server on 0.0.0.0:1885");
-    let cert_file = &mut BufReader::new(File::open("resources/server.chain.crt")?);
-    let key_file = &mut BufReader::new(File::open("resources/server.pkcs8.key")?);
-    let keys = rustls_pemfile::private_key(key_file)?.unwrap();
-
-    debug!("Loading TLS certificates");
-    let cert_chain = rustls_pemfile::certs(cert_file).collect::<Result<Vec<_>, _>>()?;
-    let mut root_store = RootCertStore::empty();
-    root_store.add_parsable_certificates(cert_chain.clone());
-    let client_verifier = WebPkiClientVerifier::builder(root_store.into())
-        .build()
-        .unwrap();
-
-    let tls_config = Arc::new(
-        ServerConfig::builder()
-            .with_client_cert_verifier(client_verifier)
-            .with_single_cert(cert_chain, keys)
-            .unwrap(),
-    );
+    info!("Starting MQTT TLS server on {}", CONFIG.listeners.tls);
+    let tls_config = ReloadableConfig::new(None, TlsSettings::new(&CONFIG.tls))
+        .inspect_err(|e| error!("Invalid TLS configuration: {}", e))?;
     debug!("TLS configuration created successfully");
+    tls_config.watch();
+    for tenant in TENANTS.iter() {
+        tenant.tls.watch();
+    }
 
     ntex::server::Server::build()
-        .bind("mqtt-gateway", "0.0.0.0:1885", move |_| {
-            chain_factory(TlsAcceptor::new(tls_config.clone()))
+        .bind("mqtt-gateway", CONFIG.listeners.tls, move |_| {
+            chain_factory(ReloadingAcceptor::new(tls_config.clone()))
                 .map_err(|err| {
                     error!("TLS handshake failed: {}", err);
-                    MqttError::Service(ServerError)
+                    MqttError::Service(ServerError::Internal)
                 })
-                .and_then(fn_service(|io: Io<Layer<TlsServerFilter>>| {
-                    // Handle peer certificate
-                    let cert_info = io.query::<PeerCert>().as_ref().map(|cert: &PeerCert| {
-                        X509Certificate::from_der(&cert.0)
-                            .unwrap()
-                            .1
-                            .subject()
-                            .to_string()
-                    });
-                    info!("Incoming TLS connection: peer certificate: {:?}", cert_info);
-                    Ready::Ok(io)
-                }))
                 .and_then({
                     debug!("Initializing MQTT v3 server over TLS");
                     let mqtt_v3_server = v3::MqttServer::new(connect_v3)
                         .control(control_factory_v3())
                         .publish(publish_factory_v3())
+                        .max_qos(MAX_QOS)
                         .middleware(RequestLogger)
                         // .middleware(fn_pub_ack_factory_v3())
                         // .middleware(fn_handle_packet_id())
-                        // .middleware(fn_auth)
                         .finish();
 
                     debug!("Initializing MQTT v5 server over TLS");
                     let mqtt_v5_server = v5::MqttServer::new(connect_v5)
                         .control(control_factory_v5())
                         .publish(publish_factory_v5())
+                        .max_qos(MAX_QOS)
                         .finish();
 
                     MqttServer::new().v3(mqtt_v3_server).v5(mqtt_v5_server)
@@ -122,7 +134,7 @@ async fn listen_tls() -> std::io::Result<()> {
             // TODO: add error handler
             // .then(service_error_handler)
         })?
-        .workers(1)
+        .workers(CONFIG.workers)
         .run()
         .await
 }
@@ -131,15 +143,42 @@ async fn listen_tls() -> std::io::Result<()> {
 async fn main() {
     // Initialize the logger
     env_logger::init_from_env(env_logger::Env::default().default_filter_or("info"));
+    // Exits with the reason when the configuration is invalid.
+    let config = &*CONFIG;
     info!("MQTT Gateway starting up");
-    
+    debug!("Configuration: {:?}", config);
+
     // Initialize upstream
     debug!("Initializing upstream connections");
     let _ = UPSTREAM.clone();
-    
+    if DUAL.is_active() {
+        let _ = DUAL_PRIMARY.clone();
+        let _ = DUAL_SECONDARY.clone();
+        DUAL.watch_mode_file();
+        if let Some(interval) = config.dual.report_interval() {
+            compare::report_every(interval);
+        }
+    }
+    // Fail on a broken authentication, ACL, certificate mapping or tenant setup before accepting
+    // clients.
+    let _ = AUTHENTICATOR.clone();
+    let _ = ACL.clone();
+    let _ = &*CERT_MAPPING;
+    let _ = &*TENANTS;
+
+    if let Some(addr) = config.metrics.addr {
+        ntex::rt::spawn(metrics::listen_metrics(addr));
+    }
+
     info!("Starting MQTT servers");
     let tcp_handle = ntex::rt::spawn(listen_tcp());
     let tls_handle = ntex::rt::spawn(listen_tls());
     info!("All servers started, waiting for completion");
     let _ = tokio::join!(tcp_handle, tls_handle);
+
+    // The servers have stopped, sessions saved while closing their connections may still be
+    // waiting to be written.
+    if let Err(e) = SESSION_STORE.flush() {
+        error!("Failed to write the remaining sessions: {}", e);
+    }
 }
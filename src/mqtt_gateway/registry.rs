@@ -0,0 +1,194 @@
+use ntex::util::ByteString;
+use ntex_mqtt::{QoS, v5::codec::SubscriptionOptions};
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::num::NonZeroU32;
+use std::rc::Rc;
+
+/// A client subscription. MQTT 3.1.1 subscriptions only carry a QoS, the other options keep their
+/// defaults.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Subscription {
+    pub options: SubscriptionOptions,
+    /// MQTT v5 subscription identifier.
+    pub id: Option<NonZeroU32>,
+}
+
+impl From<QoS> for Subscription {
+    fn from(qos: QoS) -> Self {
+        Self {
+            options: SubscriptionOptions {
+                qos,
+                ..Default::default()
+            },
+            id: None,
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct Node {
+    children: HashMap<String, Node>,
+    subscription: Option<(ByteString, Subscription)>,
+}
+
+/// Subscriptions of a session in a trie keyed by topic level, so the filters matching a topic are
+/// found without scanning all of them. Clones share the same registry.
+#[derive(Debug, Clone, Default)]
+pub struct SubscriptionRegistry {
+    root: Rc<RefCell<Node>>,
+}
+
+impl SubscriptionRegistry {
+    /// Adds a subscription, replacing the one with the same filter.
+    pub fn subscribe(&self, filter: ByteString, subscription: Subscription) {
+        let mut root = self.root.borrow_mut();
+        let node = filter.split('/').fold(&mut *root, |node, level| {
+            node.children.entry(level.to_owned()).or_default()
+        });
+        node.subscription = Some((filter, subscription));
+    }
+
+    /// Removes the subscription with exactly this filter. Returns whether there was one.
+    pub fn unsubscribe(&self, filter: &str) -> bool {
+        let levels: Vec<&str> = filter.split('/').collect();
+        Self::remove(&mut self.root.borrow_mut(), &levels)
+    }
+
+    fn remove(node: &mut Node, levels: &[&str]) -> bool {
+        let Some((level, rest)) = levels.split_first() else {
+            return node.subscription.take().is_some();
+        };
+        let Some(child) = node.children.get_mut(*level) else {
+            return false;
+        };
+
+        let removed = Self::remove(child, rest);
+        if child.subscription.is_none() && child.children.is_empty() {
+            node.children.remove(*level);
+        }
+        removed
+    }
+
+    /// Returns every subscription whose filter matches a topic name, overlapping filters included.
+    pub fn matches(&self, topic: &str) -> Vec<(ByteString, Subscription)> {
+        let levels: Vec<&str> = topic.split('/').collect();
+        let mut matched = Vec::new();
+        // Wildcards at the first level do not match topics starting with `$` [MQTT-4.7.2-1].
+        Self::collect(
+            &self.root.borrow(),
+            &levels,
+            !topic.starts_with('$'),
+            &mut matched,
+        );
+        matched
+    }
+
+    fn collect(
+        node: &Node,
+        levels: &[&str],
+        wildcards: bool,
+        matched: &mut Vec<(ByteString, Subscription)>,
+    ) {
+        // `#` also matches the parent level, `a/#` matches `a`.
+        if wildcards && let Some(child) = node.children.get("#") {
+            matched.extend(child.subscription.clone());
+        }
+
+        let Some((level, rest)) = levels.split_first() else {
+            matched.extend(node.subscription.clone());
+            return;
+        };
+        if let Some(child) = node.children.get(*level) {
+            Self::collect(child, rest, true, matched);
+        }
+        if wildcards && let Some(child) = node.children.get("+") {
+            Self::collect(child, rest, true, matched);
+        }
+    }
+
+    /// Returns all subscriptions, in no particular order.
+    pub fn entries(&self) -> Vec<(ByteString, Subscription)> {
+        fn walk(node: &Node, entries: &mut Vec<(ByteString, Subscription)>) {
+            entries.extend(node.subscription.clone());
+            node.children
+                .values()
+                .for_each(|child| walk(child, entries));
+        }
+
+        let mut entries = Vec::new();
+        walk(&self.root.borrow(), &mut entries);
+        entries
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn registry(filters: &[&str]) -> SubscriptionRegistry {
+        let registry = SubscriptionRegistry::default();
+        for filter in filters {
+            registry.subscribe(ByteString::from(*filter), QoS::AtLeastOnce.into());
+        }
+        registry
+    }
+
+    fn matched(registry: &SubscriptionRegistry, topic: &str) -> Vec<String> {
+        let mut filters: Vec<String> = registry
+            .matches(topic)
+            .into_iter()
+            .map(|(filter, _)| filter.to_string())
+            .collect();
+        filters.sort();
+        filters
+    }
+
+    #[test]
+    fn matches_wildcards() {
+        let registry = registry(&["a/b", "a/+", "a/#", "+/+/c", "#", "b/+"]);
+        assert_eq!(matched(&registry, "a/b"), ["#", "a/#", "a/+", "a/b"]);
+        assert_eq!(matched(&registry, "a/b/c"), ["#", "+/+/c", "a/#"]);
+        assert_eq!(matched(&registry, "b"), ["#"]);
+        assert_eq!(matched(&registry, "b/"), ["#", "b/+"]);
+    }
+
+    #[test]
+    fn multi_level_wildcard_matches_parent() {
+        let registry = registry(&["a/#"]);
+        assert_eq!(matched(&registry, "a"), ["a/#"]);
+        assert!(matched(&registry, "ab").is_empty());
+    }
+
+    #[test]
+    fn wildcards_skip_dollar_topics() {
+        let registry = registry(&["#", "+/info", "$SYS/#", "$SYS/+"]);
+        assert_eq!(matched(&registry, "$SYS/info"), ["$SYS/#", "$SYS/+"]);
+        assert_eq!(matched(&registry, "sys/info"), ["#", "+/info"]);
+    }
+
+    #[test]
+    fn subscribe_replaces_same_filter() {
+        let registry = registry(&["a/+"]);
+        registry.subscribe("a/+".into(), QoS::AtMostOnce.into());
+        let matches = registry.matches("a/b");
+        assert_eq!(matches.len(), 1);
+        assert_eq!(matches[0].1.options.qos, QoS::AtMostOnce);
+    }
+
+    #[test]
+    fn unsubscribe_removes_exact_filter() {
+        let registry = registry(&["a/b/c", "a/+"]);
+        assert!(!registry.unsubscribe("a/b"));
+        assert!(registry.unsubscribe("a/b/c"));
+        assert!(!registry.unsubscribe("a/b/c"));
+        assert_eq!(matched(&registry, "a/b/c"), Vec::<String>::new());
+        assert_eq!(matched(&registry, "a/b"), ["a/+"]);
+        assert_eq!(registry.entries().len(), 1);
+        assert!(
+            !registry.root.borrow().children["a"]
+                .children
+                .contains_key("b")
+        );
+    }
+}
@@ -1,34 +1,219 @@
+use futures::future::join;
+use log::{error, warn};
 use ntex::util::{ByteString, Bytes};
+use ntex_mqtt::error::SendPacketError;
 use ntex_mqtt::{
+    QoS,
     v3::{
-        self, PublishBuilder, SubscribeBuilder, UnsubscribeBuilder,
+        self, PublishBuilder,
         codec::SubscribeReturnCode,
         control::{Subscribe, Unsubscribe},
     },
     v5,
 };
-use std::{cell::RefCell, fmt};
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::num::NonZeroU32;
+use std::rc::Rc;
+use std::sync::Arc;
 
+use super::acl::DeniedPublish;
+use super::bridge::{self, Bridge};
+use super::cert::ClientCertificate;
+use super::compare;
 use super::error::ServerError;
+use super::{ACL, DUAL, MAX_QOS, SESSION_STORE};
 
+use super::dedup::PublishDedup;
 use super::dual::DualSink;
+use super::inflight::InflightWindow;
+use super::lifecycle::{ConnectionPair, Teardown};
+use super::registry::{Subscription, SubscriptionRegistry};
+use super::store::{StoredMessage, StoredSession, StoredSubscription};
+use super::tenant::Tenant;
 
 #[derive(Debug, Clone)]
-pub struct SessionState<Source> {
+pub struct SessionState<Source: Bridge> {
     pub client_id: String,
-    pub subscriptions: RefCell<Vec<ByteString>>,
+    /// Username the client authenticated with.
+    pub username: Option<String>,
+    /// Certificate the client presented on a TLS connection.
+    pub certificate: Option<Rc<ClientCertificate>>,
+    /// Tenant the client connected to, its backend pool serves the session.
+    pub tenant: Option<Arc<Tenant>>,
+    pub subscriptions: SubscriptionRegistry,
     pub source: Source,
-    pub sink: AnySink<Source>,
+    /// Upstream connection, replaced when the session fails over to another backend.
+    pub sink: Rc<RefCell<AnySink<Source>>>,
+    /// Publishes forwarded to the backend and waiting for its ack.
+    pub upstream_inflight: InflightWindow,
+    /// Publishes forwarded to the client and waiting for its ack.
+    pub downstream_inflight: InflightWindow,
+    /// Publishes received from the backend, to drop redeliveries.
+    pub upstream_dedup: PublishDedup,
+    /// The client connected with clean-session unset and a client id, so the session outlives
+    /// the connection.
+    pub persistent: bool,
+    /// Publishes received from the backend while the client was offline.
+    pub offline: Rc<RefCell<Vec<StoredMessage>>>,
+    pub pair: ConnectionPair,
 }
 
-// TODO: implement separate logic for MQTT3 and MQTT5.
-impl SessionState<v3::MqttSink> {}
-impl SessionState<v5::MqttSink> {}
+impl<Source: Bridge + Clone> SessionState<Source> {
+    pub fn tenant_name(&self) -> Option<&str> {
+        self.tenant.as_ref().map(|tenant| tenant.name.as_str())
+    }
+
+    pub fn sink(&self) -> AnySink<Source> {
+        self.sink.borrow().clone()
+    }
+
+    /// Takes over the subscriptions of a stored session and returns the publishes the client
+    /// missed, oldest first.
+    pub fn restore(&self, stored: StoredSession) -> Vec<StoredMessage> {
+        for subscription in stored.subscriptions {
+            self.subscriptions.subscribe(
+                ByteString::from(subscription.filter),
+                Subscription {
+                    options: v5::codec::SubscriptionOptions {
+                        qos: subscription.qos,
+                        no_local: subscription.no_local,
+                        retain_as_published: subscription.retain_as_published,
+                        retain_handling: subscription
+                            .retain_handling
+                            .try_into()
+                            .unwrap_or(v5::codec::RetainHandling::AtSubscribe),
+                    },
+                    id: subscription.id.and_then(NonZeroU32::new),
+                },
+            );
+        }
+        stored.inflight.into_iter().chain(stored.offline).collect()
+    }
+
+    /// Checks a client publish against the topic ACL. `Ok(false)` means the publish is dropped,
+    /// an error that the client is disconnected.
+    pub fn authorize_publish(&self, topic: &str) -> Result<bool, ServerError> {
+        if ACL.can_publish(&self.client_id, self.username.as_deref(), topic) {
+            return Ok(true);
+        }
+
+        warn!(
+            "Publish denied by ACL: client_id={}, topic={}",
+            self.client_id, topic
+        );
+        match ACL.denied_publish {
+            DeniedPublish::Drop => Ok(false),
+            DeniedPublish::Disconnect => Err(ServerError::NotAuthorized),
+        }
+    }
+
+    pub fn can_subscribe(&self, filter: &str) -> bool {
+        ACL.can_subscribe(&self.client_id, self.username.as_deref(), filter)
+    }
+
+    /// Identifiers of the client subscriptions matching a topic, for the publishes sent to it.
+    pub fn subscription_ids(&self, topic: &str) -> Vec<NonZeroU32> {
+        let mut ids: Vec<_> = self
+            .subscriptions
+            .matches(topic)
+            .into_iter()
+            .filter_map(|(_, subscription)| subscription.id)
+            .collect();
+        ids.sort_unstable();
+        ids.dedup();
+        ids
+    }
+
+    /// Saves the session if the client asked for it to outlive the connection.
+    pub fn persist(&self) {
+        if !self.persistent {
+            return;
+        }
+
+        let stored = StoredSession {
+            tenant: self.tenant_name().map(str::to_string),
+            client_id: self.client_id.clone(),
+            subscriptions: self
+                .subscriptions
+                .entries()
+                .into_iter()
+                .map(|(filter, subscription)| StoredSubscription {
+                    filter: filter.to_string(),
+                    qos: subscription.options.qos,
+                    no_local: subscription.options.no_local,
+                    retain_as_published: subscription.options.retain_as_published,
+                    retain_handling: subscription.options.retain_handling.into(),
+                    id: subscription.id.map(NonZeroU32::get),
+                })
+                .collect(),
+            inflight: self
+                .downstream_inflight
+                .pending()
+                .into_iter()
+                .map(|(topic, payload)| StoredMessage {
+                    topic: topic.to_string(),
+                    payload: payload.to_vec(),
+                    qos: QoS::AtLeastOnce,
+                })
+                .collect(),
+            offline: self.offline.borrow().clone(),
+        };
+        if let Err(e) = SESSION_STORE.save(&stored) {
+            error!("Failed to save session of client {}: {}", self.client_id, e);
+        }
+    }
+
+    /// Keeps a publish for an offline client until it reconnects. QoS 0 publishes and publishes
+    /// for clients without a persistent session are not queued.
+    pub fn queue_offline(&self, topic: &ByteString, payload: &Bytes, qos: QoS) -> bool {
+        if !self.persistent || qos == QoS::AtMostOnce {
+            return false;
+        }
+
+        self.offline.borrow_mut().push(StoredMessage {
+            topic: topic.to_string(),
+            payload: payload.to_vec(),
+            qos,
+        });
+        self.persist();
+        true
+    }
+}
+
+impl Teardown for SessionState<v3::MqttSink> {
+    fn close_downstream(&self) {
+        self.source.close();
+    }
+
+    fn close_upstream(&self) {
+        self.sink().close();
+    }
+}
+
+impl Teardown for SessionState<v5::MqttSink> {
+    fn close_downstream(&self) {
+        self.source.close();
+    }
+
+    fn close_upstream(&self) {
+        self.sink()
+            .close_with_reason(v5::codec::Disconnect::default());
+    }
+
+    fn take_over(&self) {
+        self.source.close_with_reason(v5::codec::Disconnect::new(
+            v5::codec::DisconnectReasonCode::SessionTakenOver,
+        ));
+    }
+}
 
 #[derive(Debug, Clone)]
-pub enum AnySink<T> {
+pub enum AnySink<T: Bridge> {
     MqttSink(T),
     DualSink(DualSink<T>),
+    /// Upstream connection speaking the other protocol version.
+    Bridged(T::Peer),
 }
 
 impl AnySink<v3::MqttSink> {
@@ -38,7 +223,8 @@ impl AnySink<v3::MqttSink> {
     {
         match self {
             AnySink::MqttSink(sink) => sink.publish(topic, payload),
-            AnySink::DualSink(sink) => sink.secondary_sink.publish(topic, payload),
+            AnySink::DualSink(_) => unreachable!("dual publishes are written by DualSink::publish"),
+            AnySink::Bridged(_) => unreachable!("bridged publishes are translated by the handler"),
         }
     }
 
@@ -49,6 +235,28 @@ impl AnySink<v3::MqttSink> {
                 sink.primary_sink.close();
                 sink.secondary_sink.close();
             }
+            AnySink::Bridged(sink) => sink.close(),
+        }
+    }
+
+    /// Subscribes the upstream to all the session subscriptions again, e.g. after it lost them.
+    pub async fn resubscribe(
+        &self,
+        session: &SessionState<v3::MqttSink>,
+    ) -> Result<(), ServerError> {
+        let subscriptions = session.subscriptions.entries();
+        match self {
+            AnySink::MqttSink(sink) => subscribe_all_v3(sink, &subscriptions).await,
+            AnySink::DualSink(sink) => {
+                let (primary, secondary) = join(
+                    subscribe_all_v3(&sink.primary_sink, &subscriptions),
+                    subscribe_all_v3(&sink.secondary_sink, &subscriptions),
+                )
+                .await;
+                DUAL.subscribe_policy()
+                    .merge(&sink.client_id, primary, secondary, |_, _| ())
+            }
+            AnySink::Bridged(sink) => subscribe_all_v5(sink, &subscriptions).await,
         }
     }
 
@@ -57,76 +265,79 @@ impl AnySink<v3::MqttSink> {
         session: &SessionState<v3::MqttSink>,
         mut s: Subscribe,
     ) -> Result<v3::ControlAck, ServerError> {
+        let allowed = authorize_subscribe_v3(session, &mut s);
+        if !allowed.contains(&true) {
+            return Ok(s.ack());
+        }
+
         match self {
             AnySink::MqttSink(sink) => {
-                let subscribe_builder = s.iter_mut().fold(sink.subscribe(), |builder, s| {
-                    session.subscriptions.borrow_mut().push(s.topic().clone());
-                    builder.topic_filter(s.topic().clone(), s.qos())
-                });
+                let subscribe_builder = permitted_v3(&mut s, &allowed)
+                    .fold(sink.subscribe(), |builder, s| {
+                        builder.topic_filter(s.topic().clone(), s.qos().min(MAX_QOS))
+                    });
 
                 subscribe_builder
                     .send()
                     .await
-                    .map_err(|_| ServerError)
-                    .map(|result| {
-                        assert_eq!(result.len(), s.iter_mut().count());
-
-                        s.iter_mut().zip(result.into_iter()).for_each(
-                            |(mut sub, upstream_code)| match upstream_code {
-                                SubscribeReturnCode::Success(qos) => sub.confirm(qos),
-                                SubscribeReturnCode::Failure => sub.fail(),
-                            },
-                        );
-
-                        s.ack()
-                    })
+                    .map_err(|_| ServerError::Internal)
+                    .map(|result| confirm_subscriptions_v3(session, s, &allowed, result))
             }
             AnySink::DualSink(sink) => {
-                // TODO: handle subscribe for both primary and secondary sinks in parallel.
-                let primary_subscribe_builder =
-                    s.iter_mut()
-                        .fold(sink.primary_sink.subscribe(), |builder, s| {
-                            session.subscriptions.borrow_mut().push(s.topic().clone());
-                            builder.topic_filter(s.topic().clone(), s.qos())
-                        });
-
-                primary_subscribe_builder
-                    .send()
-                    .await
-                    .map_err(|_| ServerError)
-                    .map(|result| {
-                        assert_eq!(result.len(), s.iter_mut().count());
-
-                        s.iter_mut().zip(result.into_iter()).for_each(
-                            |(mut sub, upstream_code)| match upstream_code {
-                                SubscribeReturnCode::Success(qos) => sub.confirm(qos),
-                                SubscribeReturnCode::Failure => sub.fail(),
-                            },
-                        );
-                    })?;
+                let filters: Vec<_> = permitted_v3(&mut s, &allowed)
+                    .map(|s| (s.topic().clone(), s.qos().min(MAX_QOS)))
+                    .collect();
+                let subscribe = |upstream: &v3::MqttSink| {
+                    filters
+                        .iter()
+                        .fold(upstream.subscribe(), |builder, (filter, qos)| {
+                            builder.topic_filter(filter.clone(), *qos)
+                        })
+                        .send()
+                };
 
-                let secondary_subscribe_builder =
-                    s.iter_mut()
-                        .fold(sink.secondary_sink.subscribe(), |builder, s| {
-                            session.subscriptions.borrow_mut().push(s.topic().clone());
-                            builder.topic_filter(s.topic().clone(), s.qos())
-                        });
+                let (primary, secondary) = join(
+                    subscribe(&sink.primary_sink),
+                    subscribe(&sink.secondary_sink),
+                )
+                .await;
+                compare::compare_acks(
+                    "subscribe",
+                    &sink.client_id,
+                    filters.iter().map(|(filter, _)| filter),
+                    &primary,
+                    &secondary,
+                );
+                let policy = DUAL.subscribe_policy();
+                let result =
+                    policy.merge(&sink.client_id, primary, secondary, |primary, secondary| {
+                        primary
+                            .into_iter()
+                            .zip(secondary)
+                            .map(|(primary, secondary)| {
+                                policy.subscribe_code_v3(primary, secondary)
+                            })
+                            .collect()
+                    })?;
+                Ok(confirm_subscriptions_v3(session, s, &allowed, result))
+            }
+            AnySink::Bridged(sink) => {
+                let subscribe_builder =
+                    permitted_v3(&mut s, &allowed).fold(sink.subscribe(None), |builder, s| {
+                        let options = v5::codec::SubscriptionOptions {
+                            qos: s.qos().min(MAX_QOS),
+                            ..Default::default()
+                        };
+                        builder.topic_filter(s.topic().clone(), options)
+                    });
 
-                secondary_subscribe_builder
+                subscribe_builder
                     .send()
                     .await
-                    .map_err(|_| ServerError)
+                    .map_err(|_| ServerError::Internal)
                     .map(|result| {
-                        assert_eq!(result.len(), s.iter_mut().count());
-
-                        s.iter_mut().zip(result.into_iter()).for_each(
-                            |(mut sub, upstream_code)| match upstream_code {
-                                SubscribeReturnCode::Success(qos) => sub.confirm(qos),
-                                SubscribeReturnCode::Failure => sub.fail(),
-                            },
-                        );
-
-                        s.ack()
+                        let codes = result.status.into_iter().map(bridge::subscribe_code_to_v3);
+                        confirm_subscriptions_v3(session, s, &allowed, codes.collect())
                     })
             }
         }
@@ -137,42 +348,439 @@ impl AnySink<v3::MqttSink> {
         session: &SessionState<v3::MqttSink>,
         s: Unsubscribe,
     ) -> Result<v3::ControlAck, ServerError> {
-        // TODO: handle unsubscribe for both primary and secondary sinks in parallel.
         match self {
             AnySink::MqttSink(sink) => {
                 let unsubscribe_builder = s.iter().fold(sink.unsubscribe(), |builder, topic| {
-                    session.subscriptions.borrow_mut().push(topic.clone());
+                    session.subscriptions.unsubscribe(topic);
                     builder.topic_filter(topic.clone())
                 });
 
                 unsubscribe_builder
                     .send()
                     .await
-                    .map_err(|_| ServerError)
+                    .map_err(|_| ServerError::Internal)
                     .map(|_| s.ack())
             }
             AnySink::DualSink(sink) => {
-                let primary_unsubscribe_builder = s.iter().fold(sink.primary_sink.unsubscribe(), |builder, topic| {
-                    session.subscriptions.borrow_mut().push(topic.clone());
+                let filters: Vec<_> = s
+                    .iter()
+                    .map(|topic| {
+                        session.subscriptions.unsubscribe(topic);
+                        topic.clone()
+                    })
+                    .collect();
+                let unsubscribe = |upstream: &v3::MqttSink| {
+                    filters
+                        .iter()
+                        .fold(upstream.unsubscribe(), |builder, filter| {
+                            builder.topic_filter(filter.clone())
+                        })
+                        .send()
+                };
+
+                let (primary, secondary) = join(
+                    unsubscribe(&sink.primary_sink),
+                    unsubscribe(&sink.secondary_sink),
+                )
+                .await;
+                DUAL.subscribe_policy()
+                    .merge(&sink.client_id, primary, secondary, |_, _| ())
+                    .map(|_| s.ack())
+            }
+            AnySink::Bridged(sink) => {
+                let unsubscribe_builder = s.iter().fold(sink.unsubscribe(), |builder, topic| {
+                    session.subscriptions.unsubscribe(topic);
                     builder.topic_filter(topic.clone())
                 });
 
-                primary_unsubscribe_builder
+                unsubscribe_builder
                     .send()
                     .await
-                    .map_err(|_| ServerError)?;
+                    .map_err(|_| ServerError::Internal)
+                    .map(|_| s.ack())
+            }
+        }
+    }
+}
 
-                let secondary_unsubscribe_builder = s.iter().fold(sink.secondary_sink.unsubscribe(), |builder, topic| {
-                    session.subscriptions.borrow_mut().push(topic.clone());
-                    builder.topic_filter(topic.clone())
-                });
+impl AnySink<v5::MqttSink> {
+    pub fn publish<U>(&self, topic: U, payload: Bytes) -> v5::PublishBuilder
+    where
+        ByteString: From<U>,
+    {
+        match self {
+            AnySink::MqttSink(sink) => sink.publish(topic, payload),
+            AnySink::DualSink(_) => unreachable!("dual publishes are written by DualSink::publish"),
+            AnySink::Bridged(_) => unreachable!("bridged publishes are translated by the handler"),
+        }
+    }
+
+    pub fn close_with_reason(&self, pkt: v5::codec::Disconnect) {
+        match self {
+            AnySink::MqttSink(sink) => sink.close_with_reason(pkt),
+            AnySink::DualSink(sink) => {
+                sink.primary_sink.close_with_reason(pkt.clone());
+                sink.secondary_sink.close_with_reason(pkt);
+            }
+            // MQTT 3.1.1 clients cannot send a reason to the server.
+            AnySink::Bridged(sink) => sink.close(),
+        }
+    }
+
+    /// Subscribes the upstream to all the session subscriptions again, e.g. after it lost them.
+    pub async fn resubscribe(
+        &self,
+        session: &SessionState<v5::MqttSink>,
+    ) -> Result<(), ServerError> {
+        let subscriptions = session.subscriptions.entries();
+        match self {
+            AnySink::MqttSink(sink) => subscribe_all_v5(sink, &subscriptions).await,
+            AnySink::DualSink(sink) => {
+                let (primary, secondary) = join(
+                    subscribe_all_v5(&sink.primary_sink, &subscriptions),
+                    subscribe_all_v5(&sink.secondary_sink, &subscriptions),
+                )
+                .await;
+                DUAL.subscribe_policy()
+                    .merge(&sink.client_id, primary, secondary, |_, _| ())
+            }
+            AnySink::Bridged(sink) => subscribe_all_v3(sink, &subscriptions).await,
+        }
+    }
+
+    pub async fn handle_subscribe(
+        &self,
+        session: &SessionState<v5::MqttSink>,
+        mut s: v5::control::Subscribe,
+    ) -> Result<v5::ControlAck, ServerError> {
+        let allowed = authorize_subscribe_v5(session, &mut s);
+        if !allowed.contains(&true) {
+            return Ok(s.ack());
+        }
+
+        let status = match self {
+            AnySink::MqttSink(sink) => Self::subscribe_upstream(sink, &mut s, &allowed).await?,
+            AnySink::DualSink(sink) => {
+                let subscription_id = s.packet().id;
+                let filters = filters_v5(&mut s, &allowed);
+                let (primary, secondary) = join(
+                    send_subscribe_v5(&sink.primary_sink, subscription_id, &filters),
+                    send_subscribe_v5(&sink.secondary_sink, subscription_id, &filters),
+                )
+                .await;
+                compare::compare_acks(
+                    "subscribe",
+                    &sink.client_id,
+                    filters.iter().map(|(filter, _)| filter),
+                    &primary,
+                    &secondary,
+                );
+                let policy = DUAL.subscribe_policy();
+                policy.merge(&sink.client_id, primary, secondary, |primary, secondary| {
+                    primary
+                        .into_iter()
+                        .zip(secondary)
+                        .map(|(primary, secondary)| policy.subscribe_reason_v5(primary, secondary))
+                        .collect()
+                })?
+            }
+            AnySink::Bridged(sink) => {
+                let subscribe_builder =
+                    permitted_v5(&mut s, &allowed).fold(sink.subscribe(), |builder, s| {
+                        builder.topic_filter(s.topic().clone(), s.options().qos.min(MAX_QOS))
+                    });
 
-                secondary_unsubscribe_builder
+                subscribe_builder
                     .send()
                     .await
-                    .map_err(|_| ServerError)
-                    .map(|_| s.ack())
+                    .map_err(|_| ServerError::Internal)?
+                    .into_iter()
+                    .map(bridge::subscribe_code_to_v5)
+                    .collect()
             }
-        }
+        };
+
+        assert_eq!(status.len(), permitted_v5(&mut s, &allowed).count());
+
+        // Also kept for the publishes of bridged upstreams, MQTT 3.1.1 has no identifiers.
+        let subscription_id = s.packet().id;
+        permitted_v5(&mut s, &allowed)
+            .zip(status)
+            .for_each(|(mut sub, upstream_code)| {
+                let qos = match upstream_code {
+                    v5::codec::SubscribeAckReason::GrantedQos0 => QoS::AtMostOnce,
+                    v5::codec::SubscribeAckReason::GrantedQos1 => QoS::AtLeastOnce,
+                    v5::codec::SubscribeAckReason::GrantedQos2 => QoS::ExactlyOnce,
+                    code => return sub.fail(code),
+                };
+                // Only what the upstream granted is kept in the session.
+                let options = v5::codec::SubscriptionOptions {
+                    qos,
+                    ..*sub.options()
+                };
+                let subscription = Subscription {
+                    options,
+                    id: subscription_id,
+                };
+                session
+                    .subscriptions
+                    .subscribe(sub.topic().clone(), subscription);
+                sub.confirm(qos);
+            });
+
+        Ok(s.ack())
+    }
+
+    async fn subscribe_upstream(
+        upstream: &v5::MqttSink,
+        s: &mut v5::control::Subscribe,
+        allowed: &[bool],
+    ) -> Result<Vec<v5::codec::SubscribeAckReason>, ServerError> {
+        let subscription_id = s.packet().id;
+        let filters = filters_v5(s, allowed);
+        send_subscribe_v5(upstream, subscription_id, &filters)
+            .await
+            .map_err(|_| ServerError::Internal)
+    }
+
+    pub async fn handle_unsubscribe(
+        &self,
+        session: &SessionState<v5::MqttSink>,
+        mut s: v5::control::Unsubscribe,
+    ) -> Result<v5::ControlAck, ServerError> {
+        let filters: Vec<_> = s
+            .iter()
+            .map(|topic| {
+                session.subscriptions.unsubscribe(topic);
+                topic.clone()
+            })
+            .collect();
+
+        let status = match self {
+            AnySink::MqttSink(sink) => send_unsubscribe_v5(sink, &filters)
+                .await
+                .map_err(|_| ServerError::Internal)?,
+            AnySink::DualSink(sink) => {
+                let (primary, secondary) = join(
+                    send_unsubscribe_v5(&sink.primary_sink, &filters),
+                    send_unsubscribe_v5(&sink.secondary_sink, &filters),
+                )
+                .await;
+                compare::compare_acks(
+                    "unsubscribe",
+                    &sink.client_id,
+                    &filters,
+                    &primary,
+                    &secondary,
+                );
+                let policy = DUAL.subscribe_policy();
+                policy.merge(&sink.client_id, primary, secondary, |primary, secondary| {
+                    primary
+                        .into_iter()
+                        .zip(secondary)
+                        .map(|(primary, secondary)| {
+                            policy.unsubscribe_reason_v5(primary, secondary)
+                        })
+                        .collect()
+                })?
+            }
+            AnySink::Bridged(sink) => {
+                // MQTT 3.1.1 UNSUBACK carries no per topic result.
+                return filters
+                    .iter()
+                    .fold(sink.unsubscribe(), |builder, filter| {
+                        builder.topic_filter(filter.clone())
+                    })
+                    .send()
+                    .await
+                    .map_err(|_| ServerError::Internal)
+                    .map(|_| s.ack());
+            }
+        };
+
+        s.iter_mut()
+            .zip(status)
+            .for_each(|(mut item, upstream_code)| match upstream_code {
+                v5::codec::UnsubscribeAckReason::Success => item.success(),
+                code => item.fail(code),
+            });
+
+        Ok(s.ack())
+    }
+}
+
+/// Fails the subscriptions the topic ACL denies, and returns which ones may be forwarded.
+fn authorize_subscribe_v3(session: &SessionState<v3::MqttSink>, s: &mut Subscribe) -> Vec<bool> {
+    s.iter_mut()
+        .map(|mut sub| {
+            let allowed = session.can_subscribe(sub.topic());
+            if !allowed {
+                warn!(
+                    "Subscription denied by ACL: client_id={}, filter={}",
+                    session.client_id,
+                    sub.topic()
+                );
+                sub.fail();
+            }
+            allowed
+        })
+        .collect()
+}
+
+fn authorize_subscribe_v5(
+    session: &SessionState<v5::MqttSink>,
+    s: &mut v5::control::Subscribe,
+) -> Vec<bool> {
+    s.iter_mut()
+        .map(|mut sub| {
+            let allowed = session.can_subscribe(sub.topic());
+            if !allowed {
+                warn!(
+                    "Subscription denied by ACL: client_id={}, filter={}",
+                    session.client_id,
+                    sub.topic()
+                );
+                sub.fail(v5::codec::SubscribeAckReason::NotAuthorized);
+            }
+            allowed
+        })
+        .collect()
+}
+
+/// The subscriptions of a SUBSCRIBE that passed the topic ACL.
+fn permitted_v3<'a>(
+    s: &'a mut Subscribe,
+    allowed: &'a [bool],
+) -> impl Iterator<Item = v3::control::Subscription<'a>> {
+    s.iter_mut()
+        .zip(allowed)
+        .filter_map(|(sub, allowed)| allowed.then_some(sub))
+}
+
+fn permitted_v5<'a>(
+    s: &'a mut v5::control::Subscribe,
+    allowed: &'a [bool],
+) -> impl Iterator<Item = v5::control::Subscription<'a>> {
+    s.iter_mut()
+        .zip(allowed)
+        .filter_map(|(sub, allowed)| allowed.then_some(sub))
+}
+
+/// Acks the permitted subscriptions of a SUBSCRIBE with the upstream return codes, and records
+/// the ones the upstream granted in the session.
+fn confirm_subscriptions_v3(
+    session: &SessionState<v3::MqttSink>,
+    mut s: Subscribe,
+    allowed: &[bool],
+    codes: Vec<SubscribeReturnCode>,
+) -> v3::ControlAck {
+    assert_eq!(codes.len(), permitted_v3(&mut s, allowed).count());
+
+    permitted_v3(&mut s, allowed).zip(codes).for_each(
+        |(mut sub, upstream_code)| match upstream_code {
+            SubscribeReturnCode::Success(qos) => {
+                session
+                    .subscriptions
+                    .subscribe(sub.topic().clone(), qos.into());
+                sub.confirm(qos)
+            }
+            SubscribeReturnCode::Failure => sub.fail(),
+        },
+    );
+
+    s.ack()
+}
+
+/// The permitted subscriptions of a SUBSCRIBE as they are sent upstream.
+fn filters_v5(
+    s: &mut v5::control::Subscribe,
+    allowed: &[bool],
+) -> Vec<(ByteString, v5::codec::SubscriptionOptions)> {
+    permitted_v5(s, allowed)
+        .map(|s| {
+            let options = v5::codec::SubscriptionOptions {
+                qos: s.options().qos.min(MAX_QOS),
+                ..*s.options()
+            };
+            (s.topic().clone(), options)
+        })
+        .collect()
+}
+
+async fn send_subscribe_v5(
+    sink: &v5::MqttSink,
+    id: Option<NonZeroU32>,
+    filters: &[(ByteString, v5::codec::SubscriptionOptions)],
+) -> Result<Vec<v5::codec::SubscribeAckReason>, SendPacketError> {
+    filters
+        .iter()
+        .fold(sink.subscribe(id), |builder, (filter, options)| {
+            builder.topic_filter(filter.clone(), *options)
+        })
+        .send()
+        .await
+        .map(|result| result.status)
+}
+
+async fn send_unsubscribe_v5(
+    sink: &v5::MqttSink,
+    filters: &[ByteString],
+) -> Result<Vec<v5::codec::UnsubscribeAckReason>, SendPacketError> {
+    filters
+        .iter()
+        .fold(sink.unsubscribe(), |builder, filter| {
+            builder.topic_filter(filter.clone())
+        })
+        .send()
+        .await
+        .map(|result| result.status)
+}
+
+async fn subscribe_all_v3(
+    sink: &v3::MqttSink,
+    subscriptions: &[(ByteString, Subscription)],
+) -> Result<(), ServerError> {
+    if subscriptions.is_empty() {
+        return Ok(());
+    }
+
+    subscriptions
+        .iter()
+        .fold(sink.subscribe(), |builder, (filter, subscription)| {
+            builder.topic_filter(filter.clone(), subscription.options.qos.min(MAX_QOS))
+        })
+        .send()
+        .await
+        .map(|_| ())
+        .map_err(|_| ServerError::Internal)
+}
+
+async fn subscribe_all_v5(
+    sink: &v5::MqttSink,
+    subscriptions: &[(ByteString, Subscription)],
+) -> Result<(), ServerError> {
+    // A SUBSCRIBE carries a single subscription identifier.
+    let mut by_id: BTreeMap<Option<NonZeroU32>, Vec<_>> = BTreeMap::new();
+    for (filter, subscription) in subscriptions {
+        by_id
+            .entry(subscription.id)
+            .or_default()
+            .push((filter, subscription.options));
+    }
+
+    for (id, filters) in by_id {
+        filters
+            .into_iter()
+            .fold(sink.subscribe(id), |builder, (filter, options)| {
+                let options = v5::codec::SubscriptionOptions {
+                    qos: options.qos.min(MAX_QOS),
+                    ..options
+                };
+                builder.topic_filter(filter.clone(), options)
+            })
+            .send()
+            .await
+            .map_err(|_| ServerError::Internal)?;
     }
+    Ok(())
 }
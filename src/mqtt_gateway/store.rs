@@ -0,0 +1,396 @@
+use super::CONFIG;
+use super::config::{ConfigError, SessionStorage, or_exit};
+use log::error;
+use ntex_mqtt::QoS;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread::{self, JoinHandle};
+
+/// Publish waiting to be delivered to a client.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct StoredMessage {
+    pub topic: String,
+    pub payload: Vec<u8>,
+    pub qos: QoS,
+}
+
+/// Subscription with its MQTT v5 options, which keep their defaults for MQTT 3.1.1 clients.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct StoredSubscription {
+    pub filter: String,
+    pub qos: QoS,
+    #[serde(default)]
+    pub no_local: bool,
+    #[serde(default)]
+    pub retain_as_published: bool,
+    #[serde(default)]
+    pub retain_handling: u8,
+    #[serde(default)]
+    pub id: Option<u32>,
+}
+
+/// Session state kept across reconnects of a client that connected with clean-session unset.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct StoredSession {
+    /// Tenant the client connected to, client ids are only unique within a tenant.
+    #[serde(default)]
+    pub tenant: Option<String>,
+    pub client_id: String,
+    pub subscriptions: Vec<StoredSubscription>,
+    /// Publishes sent to the client but not acknowledged yet.
+    pub inflight: Vec<StoredMessage>,
+    /// Publishes received from the backend while the client was offline.
+    pub offline: Vec<StoredMessage>,
+}
+
+pub trait SessionStore: Send + Sync {
+    fn load(&self, tenant: Option<&str>, client_id: &str) -> io::Result<Option<StoredSession>>;
+    fn save(&self, session: &StoredSession) -> io::Result<()>;
+    fn remove(&self, tenant: Option<&str>, client_id: &str) -> io::Result<()>;
+
+    /// Waits until the sessions saved or removed so far are written.
+    fn flush(&self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+type SessionKey = (Option<String>, String);
+
+fn session_key(tenant: Option<&str>, client_id: &str) -> SessionKey {
+    (tenant.map(str::to_string), client_id.to_string())
+}
+
+/// Keeps sessions for the lifetime of the process.
+#[derive(Default)]
+pub struct MemorySessionStore {
+    sessions: Mutex<HashMap<SessionKey, StoredSession>>,
+}
+
+impl SessionStore for MemorySessionStore {
+    fn load(&self, tenant: Option<&str>, client_id: &str) -> io::Result<Option<StoredSession>> {
+        let key = session_key(tenant, client_id);
+        Ok(self.sessions.lock().unwrap().get(&key).cloned())
+    }
+
+    fn save(&self, session: &StoredSession) -> io::Result<()> {
+        let key = session_key(session.tenant.as_deref(), &session.client_id);
+        self.sessions.lock().unwrap().insert(key, session.clone());
+        Ok(())
+    }
+
+    fn remove(&self, tenant: Option<&str>, client_id: &str) -> io::Result<()> {
+        self.sessions
+            .lock()
+            .unwrap()
+            .remove(&session_key(tenant, client_id));
+        Ok(())
+    }
+}
+
+/// Keeps one JSON file per session in a directory, so sessions survive a restart and can be
+/// picked up by any gateway process sharing the directory. Sessions of a tenant are kept in a
+/// subdirectory of their own.
+pub struct FileSessionStore {
+    dir: PathBuf,
+}
+
+impl FileSessionStore {
+    pub fn new(dir: impl Into<PathBuf>) -> io::Result<Self> {
+        let dir = dir.into();
+        fs::create_dir_all(&dir)?;
+        Ok(Self { dir })
+    }
+
+    fn path(&self, tenant: Option<&str>, client_id: &str) -> PathBuf {
+        // Client ids may contain any character, hex keeps the file name valid.
+        let hex = |name: &str| -> String { name.bytes().map(|b| format!("{:02x}", b)).collect() };
+        let dir = match tenant {
+            Some(tenant) => self.dir.join(hex(tenant)),
+            None => self.dir.clone(),
+        };
+        dir.join(format!("{}.json", hex(client_id)))
+    }
+}
+
+impl SessionStore for FileSessionStore {
+    fn load(&self, tenant: Option<&str>, client_id: &str) -> io::Result<Option<StoredSession>> {
+        match fs::read(self.path(tenant, client_id)) {
+            Ok(data) => Ok(Some(serde_json::from_slice(&data)?)),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    fn save(&self, session: &StoredSession) -> io::Result<()> {
+        let path = self.path(session.tenant.as_deref(), &session.client_id);
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        let tmp = path.with_extension("json.tmp");
+        fs::write(&tmp, serde_json::to_vec(session)?)?;
+        fs::rename(tmp, path)
+    }
+
+    fn remove(&self, tenant: Option<&str>, client_id: &str) -> io::Result<()> {
+        match fs::remove_file(self.path(tenant, client_id)) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Latest state of a session waiting to be written, `None` to remove it.
+type PendingWrites = HashMap<SessionKey, Option<StoredSession>>;
+
+#[derive(Default)]
+struct Pending {
+    queued: PendingWrites,
+    /// Writes the writer thread is busy with.
+    writing: PendingWrites,
+    /// Set when the store is dropped, the writer thread exits once everything is written.
+    stopped: bool,
+}
+
+/// Writes sessions on a thread of its own, so saving a session does not block the event loop.
+/// A session saved again before its previous state is written is only written once, and loads
+/// see the states still waiting to be written.
+pub struct WriteBehindStore {
+    /// The condition variable is signalled when writes are queued, and when they are written.
+    pending: Arc<(Mutex<Pending>, Condvar)>,
+    inner: Arc<dyn SessionStore>,
+    writer: Option<JoinHandle<()>>,
+}
+
+impl WriteBehindStore {
+    pub fn new(inner: Arc<dyn SessionStore>) -> io::Result<Self> {
+        let pending = Arc::new((Mutex::new(Pending::default()), Condvar::new()));
+        let writer = thread::Builder::new().name("session-store".into()).spawn({
+            let pending = pending.clone();
+            let inner = inner.clone();
+            move || write_sessions(&pending, inner.as_ref())
+        })?;
+        Ok(Self {
+            pending,
+            inner,
+            writer: Some(writer),
+        })
+    }
+
+    fn queue(&self, key: SessionKey, session: Option<StoredSession>) {
+        let (pending, changed) = &*self.pending;
+        pending.lock().unwrap().queued.insert(key, session);
+        changed.notify_all();
+    }
+}
+
+/// Writes what is left before the writer thread exits.
+impl Drop for WriteBehindStore {
+    fn drop(&mut self) {
+        let (pending, changed) = &*self.pending;
+        pending.lock().unwrap().stopped = true;
+        changed.notify_all();
+        if let Some(writer) = self.writer.take() {
+            let _ = writer.join();
+        }
+    }
+}
+
+fn write_sessions(pending: &(Mutex<Pending>, Condvar), store: &dyn SessionStore) {
+    let (pending, changed) = pending;
+    loop {
+        let writes = {
+            let mut pending = changed
+                .wait_while(pending.lock().unwrap(), |pending| {
+                    pending.queued.is_empty() && !pending.stopped
+                })
+                .unwrap();
+            if pending.queued.is_empty() {
+                return;
+            }
+            pending.writing = std::mem::take(&mut pending.queued);
+            pending.writing.clone()
+        };
+        for ((tenant, client_id), session) in writes {
+            let written = match session {
+                Some(session) => store.save(&session),
+                None => store.remove(tenant.as_deref(), &client_id),
+            };
+            if let Err(e) = written {
+                error!("Failed to save session of client {}: {}", client_id, e);
+            }
+        }
+        pending.lock().unwrap().writing.clear();
+        changed.notify_all();
+    }
+}
+
+impl SessionStore for WriteBehindStore {
+    fn load(&self, tenant: Option<&str>, client_id: &str) -> io::Result<Option<StoredSession>> {
+        let key = session_key(tenant, client_id);
+        {
+            let pending = self.pending.0.lock().unwrap();
+            if let Some(session) = pending.queued.get(&key).or(pending.writing.get(&key)) {
+                return Ok(session.clone());
+            }
+        }
+        self.inner.load(tenant, client_id)
+    }
+
+    fn save(&self, session: &StoredSession) -> io::Result<()> {
+        let key = session_key(session.tenant.as_deref(), &session.client_id);
+        self.queue(key, Some(session.clone()));
+        Ok(())
+    }
+
+    fn remove(&self, tenant: Option<&str>, client_id: &str) -> io::Result<()> {
+        self.queue(session_key(tenant, client_id), None);
+        Ok(())
+    }
+
+    fn flush(&self) -> io::Result<()> {
+        let (pending, changed) = &*self.pending;
+        let _written = changed
+            .wait_while(pending.lock().unwrap(), |pending| {
+                !pending.queued.is_empty() || !pending.writing.is_empty()
+            })
+            .unwrap();
+        Ok(())
+    }
+}
+
+pub(crate) fn create_store() -> Arc<dyn SessionStore> {
+    or_exit(open_store(&CONFIG.session_store))
+}
+
+fn open_store(config: &SessionStorage) -> Result<Arc<dyn SessionStore>, ConfigError> {
+    let Some(dir) = &config.dir else {
+        return Ok(Arc::new(MemorySessionStore::default()));
+    };
+    FileSessionStore::new(dir)
+        .and_then(|store| WriteBehindStore::new(Arc::new(store)))
+        .map(|store| Arc::new(store) as Arc<dyn SessionStore>)
+        .map_err(|e| {
+            ConfigError::Invalid(
+                "session_store.dir",
+                format!("cannot open {}: {}", dir.display(), e),
+            )
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A directory, named after the test, in the temp directory.
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir =
+            std::env::temp_dir().join(format!("mqtt_gateway-{}-{}", std::process::id(), name));
+        let _ = fs::remove_dir_all(&dir);
+        dir
+    }
+
+    fn session(tenant: Option<&str>, client_id: &str, filter: &str) -> StoredSession {
+        StoredSession {
+            tenant: tenant.map(str::to_owned),
+            client_id: client_id.into(),
+            subscriptions: vec![StoredSubscription {
+                filter: filter.into(),
+                qos: QoS::AtLeastOnce,
+                no_local: true,
+                retain_as_published: false,
+                retain_handling: 2,
+                id: Some(7),
+            }],
+            inflight: vec![StoredMessage {
+                topic: "a/b".into(),
+                payload: b"x".to_vec(),
+                qos: QoS::AtLeastOnce,
+            }],
+            offline: Vec::new(),
+        }
+    }
+
+    fn filter(store: &dyn SessionStore, tenant: Option<&str>, client_id: &str) -> Option<String> {
+        let session = store.load(tenant, client_id).unwrap()?;
+        Some(session.subscriptions[0].filter.clone())
+    }
+
+    #[test]
+    fn file_store_round_trips_sessions() {
+        let dir = temp_dir("file-store");
+        let store = FileSessionStore::new(&dir).unwrap();
+        // Any character may be part of a client id.
+        let client_id = "../c1/ü";
+        store.save(&session(None, client_id, "a/#")).unwrap();
+        store
+            .save(&session(Some("acme"), client_id, "b/#"))
+            .unwrap();
+
+        let loaded = store.load(None, client_id).unwrap().unwrap();
+        assert_eq!(loaded.client_id, client_id);
+        let subscription = &loaded.subscriptions[0];
+        assert_eq!(subscription.filter, "a/#");
+        assert_eq!(subscription.qos, QoS::AtLeastOnce);
+        assert!(subscription.no_local && !subscription.retain_as_published);
+        assert_eq!(
+            (subscription.retain_handling, subscription.id),
+            (2, Some(7))
+        );
+        assert_eq!(loaded.inflight[0].payload, b"x");
+        assert_eq!(
+            filter(&store, Some("acme"), client_id).as_deref(),
+            Some("b/#")
+        );
+        assert!(store.load(None, "c2").unwrap().is_none());
+
+        store.remove(None, client_id).unwrap();
+        assert!(store.load(None, client_id).unwrap().is_none());
+        assert!(store.load(Some("acme"), client_id).unwrap().is_some());
+        // Removing a session that is not stored is not an error.
+        store.remove(None, client_id).unwrap();
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn write_behind_store_flushes_the_latest_state() {
+        let dir = temp_dir("write-behind-flush");
+        let inner = Arc::new(FileSessionStore::new(&dir).unwrap());
+        let store = WriteBehindStore::new(inner.clone()).unwrap();
+        store.save(&session(None, "c1", "a/1")).unwrap();
+        store.save(&session(None, "c1", "a/2")).unwrap();
+        store.save(&session(None, "c2", "b/1")).unwrap();
+        store.remove(None, "c2").unwrap();
+        // Loads see the queued state before it is written.
+        assert_eq!(filter(&store, None, "c1").as_deref(), Some("a/2"));
+
+        store.flush().unwrap();
+        assert_eq!(filter(inner.as_ref(), None, "c1").as_deref(), Some("a/2"));
+        assert!(inner.load(None, "c2").unwrap().is_none());
+        drop(store);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn write_behind_store_writes_everything_when_dropped() {
+        let dir = temp_dir("write-behind-drop");
+        let inner = Arc::new(FileSessionStore::new(&dir).unwrap());
+        let store = WriteBehindStore::new(inner.clone()).unwrap();
+        for n in 0..50 {
+            store
+                .save(&session(None, &format!("c{}", n % 5), &format!("a/{}", n)))
+                .unwrap();
+        }
+        drop(store);
+
+        for n in 45..50 {
+            let client_id = format!("c{}", n % 5);
+            let expected = format!("a/{}", n);
+            assert_eq!(filter(inner.as_ref(), None, &client_id), Some(expected));
+        }
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
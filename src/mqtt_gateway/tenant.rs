@@ -0,0 +1,146 @@
+use super::config::{ConfigError, TenantSettings, Tls, or_exit};
+use super::tls::{ClientAuth, ReloadableConfig, TlsSettings};
+use super::upstream::{backend_set, create_pool};
+use super::{CONFIG, TENANTS, UPSTREAM};
+use log::info;
+use ntex::io::IoRef;
+use ntex::tls::Servername;
+use pingora_load_balancing::LoadBalancer;
+use pingora_load_balancing::selection::Consistent;
+use std::fmt;
+use std::sync::Arc;
+
+/// A customer hosted behind the shared listener, picked by the TLS server name its clients
+/// connect to.
+pub struct Tenant {
+    pub name: String,
+    /// Server names routed to this tenant, `*.example.com` matches a single label.
+    server_names: Vec<String>,
+    pub upstream: Arc<LoadBalancer<Consistent>>,
+    /// Client CA and server certificate, the listener's own where the tenant has none.
+    pub tls: Arc<ReloadableConfig>,
+}
+
+impl Tenant {
+    fn serves(&self, server_name: &str) -> bool {
+        self.server_names
+            .iter()
+            .any(|pattern| server_name_matches(pattern, server_name))
+    }
+
+    /// Tenant of a TLS connection. Connections without a server name, or with one no tenant
+    /// serves, use the listener's configuration and the default backend pool.
+    pub fn from_io(io: &IoRef) -> Option<Arc<Tenant>> {
+        let server_name = io.query::<Servername>();
+        TENANTS.resolve(&server_name.as_ref()?.0).cloned()
+    }
+}
+
+/// Whether a lowercase server name matches an exact name, or a `*.` pattern matching one label.
+fn server_name_matches(pattern: &str, server_name: &str) -> bool {
+    match pattern.strip_prefix("*.") {
+        Some(suffix) => server_name
+            .split_once('.')
+            .is_some_and(|(label, domain)| !label.is_empty() && domain == suffix),
+        None => pattern == server_name,
+    }
+}
+
+impl fmt::Debug for Tenant {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Tenant")
+            .field("name", &self.name)
+            .field("server_names", &self.server_names)
+            .finish_non_exhaustive()
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct Tenants {
+    tenants: Vec<Arc<Tenant>>,
+}
+
+impl Tenants {
+    pub fn resolve(&self, server_name: &str) -> Option<&Arc<Tenant>> {
+        let server_name = server_name.to_ascii_lowercase();
+        self.tenants
+            .iter()
+            .find(|tenant| tenant.serves(&server_name))
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.tenants.is_empty()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Arc<Tenant>> {
+        self.tenants.iter()
+    }
+}
+
+/// Backend pool of a tenant, the default pool for clients without one.
+pub(crate) fn backend_pool(tenant: Option<&Tenant>) -> &LoadBalancer<Consistent> {
+    tenant.map_or(&**UPSTREAM, |tenant| tenant.upstream.as_ref())
+}
+
+pub(crate) fn create_tenants() -> Tenants {
+    or_exit(load_tenants(&CONFIG.tenants, &CONFIG.tls))
+}
+
+/// The tenant settings are validated when the configuration is loaded, their certificate and
+/// CA files are read here.
+fn load_tenants(settings: &[TenantSettings], tls: &Tls) -> Result<Tenants, ConfigError> {
+    let base = TlsSettings::new(tls);
+
+    let mut tenants = Vec::new();
+    for tenant in settings {
+        let mut settings = base.clone();
+        if let Some(cert_file) = &tenant.cert_file {
+            settings.cert_file = cert_file.clone();
+            settings.key_file = tenant.key_file.clone().unwrap();
+        }
+        if let Some(client_ca_file) = &tenant.client_ca_file {
+            settings.client_ca_file = Some(client_ca_file.clone());
+            if settings.client_auth == ClientAuth::None {
+                settings.client_auth = ClientAuth::Required;
+            }
+        }
+        let tls = ReloadableConfig::new(Some(&tenant.name), settings).map_err(|e| {
+            ConfigError::Invalid("tenants", format!("tenant {}: {}", tenant.name, e))
+        })?;
+
+        let tenant = Tenant {
+            name: tenant.name.clone(),
+            server_names: tenant
+                .server_names
+                .iter()
+                .map(|server_name| server_name.to_ascii_lowercase())
+                .collect(),
+            upstream: create_pool(backend_set(&tenant.backends)),
+            tls,
+        };
+        info!("Tenant configured: {:?}", tenant);
+        tenants.push(Arc::new(tenant));
+    }
+    Ok(Tenants { tenants })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn exact_server_name_matches_only_itself() {
+        assert!(server_name_matches("example.com", "example.com"));
+        assert!(!server_name_matches("example.com", "mqtt.example.com"));
+        assert!(!server_name_matches("mqtt.example.com", "example.com"));
+    }
+
+    #[test]
+    fn wildcard_matches_a_single_label() {
+        assert!(server_name_matches("*.example.com", "acme.example.com"));
+        assert!(!server_name_matches("*.example.com", "example.com"));
+        assert!(!server_name_matches("*.example.com", "a.acme.example.com"));
+        assert!(!server_name_matches("*.example.com", ".example.com"));
+        assert!(!server_name_matches("*.example.com", "acme.example.org"));
+    }
+}
@@ -0,0 +1,343 @@
+use super::TENANTS;
+use super::config::Tls;
+use super::crl::{self, RevokedCertificates};
+use clap::ValueEnum;
+use log::{error, info};
+use ntex::io::{Filter, Io, Layer};
+use ntex::service::{Service, ServiceCtx, ServiceFactory};
+use ntex::time::{Millis, Seconds, sleep, timeout};
+use ntex::tls::rustls::TlsServerFilter;
+use rustls::pki_types::{CertificateDer, CertificateRevocationListDer, PrivateKeyDer};
+use rustls::server::danger::ClientCertVerifier;
+use rustls::server::{Acceptor, WebPkiClientVerifier};
+use rustls::version::{TLS12, TLS13};
+use rustls::{RootCertStore, ServerConfig, SupportedProtocolVersion};
+use serde::Deserialize;
+use std::fmt;
+use std::fs::File;
+use std::io::{self, BufReader};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+use std::time::SystemTime;
+
+/// Whether the TLS listener asks clients for a certificate.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, ValueEnum)]
+#[serde(rename_all = "kebab-case")]
+pub enum ClientAuth {
+    None,
+    /// Clients may present a certificate, which is verified when they do.
+    Optional,
+    Required,
+}
+
+/// Oldest TLS version the listener accepts.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, ValueEnum)]
+pub enum TlsVersion {
+    #[default]
+    #[serde(rename = "1.2")]
+    #[value(name = "1.2")]
+    V1_2,
+    #[serde(rename = "1.3")]
+    #[value(name = "1.3")]
+    V1_3,
+}
+
+#[derive(Debug, Clone)]
+pub struct TlsSettings {
+    pub cert_file: PathBuf,
+    pub key_file: PathBuf,
+    /// CA bundle client certificates are verified against.
+    pub client_ca_file: Option<PathBuf>,
+    pub client_auth: ClientAuth,
+    /// CRLs client certificates are checked against. Only the client certificate itself is
+    /// checked, and certificates of an issuer without a CRL are accepted.
+    pub crl_files: Vec<PathBuf>,
+    /// Whether sessions are closed when a reloaded CRL revokes their client certificate.
+    pub kick_revoked: bool,
+    pub alpn: Vec<Vec<u8>>,
+    pub min_version: &'static SupportedProtocolVersion,
+    /// How often the certificate, key and CA files are checked for changes, `None` to never.
+    pub reload_interval: Option<Seconds>,
+}
+
+impl TlsSettings {
+    /// Settings of the listener. Unless `client_auth` says otherwise, client certificates are
+    /// required once a client CA bundle is configured.
+    pub fn new(tls: &Tls) -> Self {
+        Self {
+            cert_file: tls.cert_file.clone(),
+            key_file: tls.key_file.clone(),
+            client_ca_file: tls.client_ca_file.clone(),
+            client_auth: tls.client_auth(),
+            crl_files: tls.crl_files.clone(),
+            kick_revoked: tls.crl_kick,
+            alpn: tls
+                .alpn
+                .iter()
+                .map(|protocol| protocol.as_bytes().to_vec())
+                .collect(),
+            min_version: match tls.min_version {
+                TlsVersion::V1_2 => &TLS12,
+                TlsVersion::V1_3 => &TLS13,
+            },
+            reload_interval: (tls.reload_interval > 0).then_some(Seconds(tls.reload_interval)),
+        }
+    }
+
+    pub fn server_config(&self) -> io::Result<Arc<ServerConfig>> {
+        let versions: &[&'static SupportedProtocolVersion] = if self.min_version == &TLS13 {
+            &[&TLS13]
+        } else {
+            &[&TLS12, &TLS13]
+        };
+
+        let mut config = ServerConfig::builder_with_protocol_versions(versions)
+            .with_client_cert_verifier(self.client_verifier()?)
+            .with_single_cert(load_certs(&self.cert_file)?, load_key(&self.key_file)?)
+            .map_err(|e| invalid(self.key_file.display(), e))?;
+        config.alpn_protocols = self.alpn.clone();
+        Ok(Arc::new(config))
+    }
+
+    /// Files the server configuration is built from.
+    fn files(&self) -> Vec<&Path> {
+        let mut files = vec![self.cert_file.as_path(), self.key_file.as_path()];
+        if self.client_auth != ClientAuth::None {
+            files.extend(self.client_ca_file.as_deref());
+        }
+        files.extend(self.crl_files.iter().map(PathBuf::as_path));
+        files
+    }
+
+    fn client_verifier(&self) -> io::Result<Arc<dyn ClientCertVerifier>> {
+        let ca_file = match (&self.client_ca_file, self.client_auth) {
+            (Some(ca_file), ClientAuth::Optional | ClientAuth::Required) => ca_file,
+            _ => return Ok(WebPkiClientVerifier::no_client_auth()),
+        };
+
+        let mut roots = RootCertStore::empty();
+        let (added, _) = roots.add_parsable_certificates(load_certs(ca_file)?);
+        if added == 0 {
+            return Err(invalid(ca_file.display(), "no usable CA certificate"));
+        }
+
+        let builder = WebPkiClientVerifier::builder(roots.into());
+        let builder = match self.client_auth {
+            ClientAuth::Optional => builder.allow_unauthenticated(),
+            _ => builder,
+        };
+        let builder = if self.crl_files.is_empty() {
+            builder
+        } else {
+            builder
+                .with_crls(self.load_crls()?)
+                .only_check_end_entity_revocation()
+                .allow_unknown_revocation_status()
+        };
+        builder.build().map_err(|e| invalid(ca_file.display(), e))
+    }
+
+    fn load_crls(&self) -> io::Result<Vec<CertificateRevocationListDer<'static>>> {
+        let mut crls = Vec::new();
+        for path in &self.crl_files {
+            let loaded = rustls_pemfile::crls(&mut open(path)?)
+                .collect::<Result<Vec<_>, _>>()
+                .map_err(|e| invalid(path.display(), e))?;
+            if loaded.is_empty() {
+                return Err(invalid(path.display(), "no CRL found"));
+            }
+            crls.extend(loaded);
+        }
+        Ok(crls)
+    }
+
+    fn revoked_certificates(&self) -> io::Result<RevokedCertificates> {
+        RevokedCertificates::parse(&self.load_crls()?)
+    }
+}
+
+/// Server configuration that is rebuilt when the files it was built from change. A handshake
+/// uses the configuration current when it starts, established connections are not affected.
+pub struct ReloadableConfig {
+    /// Tenant the configuration belongs to, `None` for the listener's own.
+    tenant: Option<String>,
+    settings: TlsSettings,
+    current: RwLock<Arc<ServerConfig>>,
+}
+
+impl ReloadableConfig {
+    pub fn new(tenant: Option<&str>, settings: TlsSettings) -> io::Result<Arc<Self>> {
+        let current = RwLock::new(settings.server_config()?);
+        Ok(Arc::new(Self {
+            tenant: tenant.map(str::to_owned),
+            settings,
+            current,
+        }))
+    }
+
+    pub fn current(&self) -> Arc<ServerConfig> {
+        self.current.read().unwrap().clone()
+    }
+
+    /// Polls the modification times of the files until the process exits. A configuration that
+    /// fails to build is logged and the previous one stays in use, it is retried once the files
+    /// change again.
+    pub(crate) fn watch(self: &Arc<Self>) {
+        let Some(interval) = self.settings.reload_interval else {
+            return;
+        };
+        let this = self.clone();
+        ntex::rt::spawn(async move {
+            let mut modified = this.modified();
+            loop {
+                sleep(interval).await;
+                let now = this.modified();
+                if now == modified {
+                    continue;
+                }
+                modified = now;
+
+                match this.settings.server_config() {
+                    Ok(config) => {
+                        *this.current.write().unwrap() = config;
+                        info!("TLS configuration reloaded");
+                        this.kick_revoked();
+                    }
+                    Err(e) => error!(
+                        "Failed to reload TLS configuration, keeping the old one: {}",
+                        e
+                    ),
+                }
+            }
+        });
+    }
+
+    fn kick_revoked(&self) {
+        if !self.settings.kick_revoked || self.settings.crl_files.is_empty() {
+            return;
+        }
+        match self.settings.revoked_certificates() {
+            Ok(revoked) => crl::kick_revoked(self.tenant.as_deref(), revoked),
+            Err(e) => error!("Failed to read revoked client certificates: {}", e),
+        }
+    }
+
+    fn modified(&self) -> Vec<Option<SystemTime>> {
+        self.settings
+            .files()
+            .into_iter()
+            .map(|path| {
+                std::fs::metadata(path)
+                    .and_then(|meta| meta.modified())
+                    .ok()
+            })
+            .collect()
+    }
+}
+
+/// TLS acceptor handing each handshake the current [`ReloadableConfig`]. With tenants configured,
+/// the server name the client asks for picks the tenant's configuration instead.
+#[derive(Clone)]
+pub struct ReloadingAcceptor {
+    config: Arc<ReloadableConfig>,
+    timeout: Millis,
+}
+
+impl ReloadingAcceptor {
+    pub fn new(config: Arc<ReloadableConfig>) -> Self {
+        Self {
+            config,
+            timeout: Millis(5_000),
+        }
+    }
+}
+
+impl<F: Filter, C> ServiceFactory<Io<F>, C> for ReloadingAcceptor {
+    type Response = Io<Layer<TlsServerFilter, F>>;
+    type Error = io::Error;
+    type Service = ReloadingAcceptor;
+    type InitError = ();
+
+    async fn create(&self, _: C) -> Result<Self::Service, Self::InitError> {
+        Ok(self.clone())
+    }
+}
+
+impl<F: Filter> Service<Io<F>> for ReloadingAcceptor {
+    type Response = Io<Layer<TlsServerFilter, F>>;
+    type Error = io::Error;
+
+    async fn call(
+        &self,
+        io: Io<F>,
+        _: ServiceCtx<'_, Self>,
+    ) -> Result<Self::Response, Self::Error> {
+        let config = if TENANTS.is_empty() {
+            self.config.current()
+        } else {
+            let server_name = timeout(self.timeout, peek_server_name(&io))
+                .await
+                .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "TLS handshake timeout"))??;
+            server_name
+                .and_then(|server_name| TENANTS.resolve(&server_name))
+                .map_or_else(|| self.config.current(), |tenant| tenant.tls.current())
+        };
+        TlsServerFilter::create(io, config, self.timeout).await
+    }
+}
+
+/// Reads the server name from the ClientHello without consuming it, the TLS filter takes over
+/// the buffered bytes once it is added.
+async fn peek_server_name<F>(io: &Io<F>) -> io::Result<Option<String>> {
+    loop {
+        let hello = io.with_read_buf(|buf| {
+            let mut acceptor = Acceptor::default();
+            let mut data = &buf[..];
+            while !data.is_empty() {
+                acceptor.read_tls(&mut data)?;
+            }
+            match acceptor.accept() {
+                Ok(Some(accepted)) => Ok(Some(
+                    accepted.client_hello().server_name().map(str::to_owned),
+                )),
+                Ok(None) => Ok(None),
+                Err((e, _)) => Err(io::Error::new(io::ErrorKind::InvalidData, e)),
+            }
+        })?;
+        if let Some(server_name) = hello {
+            return Ok(server_name);
+        }
+        if io.read_notify().await?.is_none() {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "disconnected"));
+        }
+    }
+}
+
+fn invalid(context: impl fmt::Display, error: impl fmt::Display) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidInput,
+        format!("{}: {}", context, error),
+    )
+}
+
+fn open(path: &Path) -> io::Result<BufReader<File>> {
+    File::open(path)
+        .map(BufReader::new)
+        .map_err(|e| io::Error::new(e.kind(), format!("{}: {}", path.display(), e)))
+}
+
+fn load_certs(path: &Path) -> io::Result<Vec<CertificateDer<'static>>> {
+    let certs = rustls_pemfile::certs(&mut open(path)?)
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|e| invalid(path.display(), e))?;
+    if certs.is_empty() {
+        return Err(invalid(path.display(), "no certificate found"));
+    }
+    Ok(certs)
+}
+
+fn load_key(path: &Path) -> io::Result<PrivateKeyDer<'static>> {
+    rustls_pemfile::private_key(&mut open(path)?)
+        .map_err(|e| invalid(path.display(), e))?
+        .ok_or_else(|| invalid(path.display(), "no private key found"))
+}
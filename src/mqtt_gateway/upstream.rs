@@ -1,3 +1,4 @@
+use super::CONFIG;
 use pingora_load_balancing::discovery::Static;
 use pingora_load_balancing::prelude::TcpHealthCheck;
 use pingora_load_balancing::selection::Consistent;
@@ -8,16 +9,25 @@ use std::time::{Duration, Instant};
 
 pub(crate) fn create_lb() -> Arc<LoadBalancer<Consistent>> {
     // TODO: implement k8s or configuration discovery.
-    let backend_set = match std::env::var("BACKEND") {
-        Ok(val) => val.split(",").map(|s|Backend::new(s.trim()).unwrap()).collect(),
-        Err(_) => BTreeSet::from([Backend::new("127.0.0.1:1883").unwrap()]),
-    };
+    create_pool(backend_set(&CONFIG.upstream.backends))
+}
+
+/// Backends of configured addresses, which are validated when the configuration is loaded.
+pub(crate) fn backend_set(addrs: &[String]) -> BTreeSet<Backend> {
+    addrs
+        .iter()
+        .map(|addr| Backend::new(addr).unwrap())
+        .collect()
+}
+
+/// Load balancer over a set of backends, health checked in the background.
+pub(crate) fn create_pool(backend_set: BTreeSet<Backend>) -> Arc<LoadBalancer<Consistent>> {
     let mut backends = Backends::new(Static::new(backend_set));
     backends.set_health_check(TcpHealthCheck::new());
-    
+
     let mut lb = LoadBalancer::from_backends(backends);
-    lb.update_frequency = Some(Duration::from_secs(60));
-    lb.health_check_frequency = Some(Duration::from_secs(60));
+    lb.update_frequency = Some(Duration::from_secs(CONFIG.upstream.discovery_interval));
+    lb.health_check_frequency = Some(Duration::from_secs(CONFIG.upstream.health_check_interval));
     lb.parallel_health_check = true;
     let lb = Arc::new(lb);
 
@@ -25,10 +35,10 @@ pub(crate) fn create_lb() -> Arc<LoadBalancer<Consistent>> {
     let lb_clone = lb.clone();
     ntex::rt::spawn_fn(|| async move {
         let lb = lb_clone;
-        
+
         const NEVER: Duration = Duration::from_secs(u32::MAX as u64);
         let mut now = Instant::now();
-        
+
         // run update and health check once
         let mut next_update = now;
         let mut next_health_check = now;
@@ -56,6 +66,6 @@ pub(crate) fn create_lb() -> Arc<LoadBalancer<Consistent>> {
             now = Instant::now();
         }
     });
-    
+
     lb
 }